@@ -1,4 +1,6 @@
+pub mod icons;
 pub mod osm;
+pub mod overlays;
 pub mod semantic;
 
 
@@ -0,0 +1,96 @@
+use std::{collections::HashMap, fs, hash::{DefaultHasher, Hash, Hasher}};
+
+use serde::Deserialize;
+
+use crate::data::osm::OsmElementRef;
+use crate::errors::Result;
+use crate::etl::semantic_map::matches_tag;
+
+pub const ICON_MANIFEST_PATH: &str = "resources/icons.json";
+
+/// Describes the icons drawn on the map and the landmark categories using them.
+#[derive(Deserialize)]
+pub struct IconManifest {
+    /// By name. Landmark icons are named after their category.
+    pub icons: HashMap<String, IconSpec>,
+    /// Checked in order, a feature gets the first category it matches.
+    pub landmarks: Vec<LandmarkCategory>,
+    /// Hash of the manifest file, stages whose output depends on it are rebuilt when it
+    /// changes.
+    #[serde(skip)]
+    pub digest: u64,
+}
+
+#[derive(Deserialize)]
+pub struct IconSpec {
+    /// Image in `resources/images`. SVGs are named with their extension, like `tram.svg`,
+    /// and rasterized to fit `width` x `height`, keeping their aspect ratio. PNGs are
+    /// named without it.
+    pub image: String,
+    pub width: f32,
+    pub height: f32,
+    /// Point of the icon placed on the feature, as fractions of the width and height.
+    #[serde(default = "default_anchor")]
+    pub anchor: (f32, f32),
+    /// Shown in the map legend, defaults to the icon's name.
+    #[serde(default)]
+    pub label: Option<String>,
+}
+
+fn default_anchor() -> (f32, f32) {
+    (0.5, 0.5)
+}
+
+#[derive(Deserialize)]
+pub struct LandmarkCategory {
+    pub category: String,
+    /// A feature matches if it has all tags of any of the entries, see `matches_tag`.
+    #[serde(default)]
+    pub tags: Vec<HashMap<String, String>>,
+    /// Elements in this category whatever their tags, like `node/123`. Listed elements
+    /// take precedence over matching tags.
+    #[serde(default)]
+    pub elements: Vec<OsmElementRef>,
+}
+
+impl LandmarkCategory {
+    /// The first entry of `tags` the feature has all tags of.
+    fn matching_tags(&self, tags: &HashMap<Vec<u8>, Vec<u8>>) -> Option<&HashMap<String, String>> {
+        self.tags.iter().find(|required| {
+            required.iter().all(|(key, value)| matches_tag(tags, (key, value)))
+        })
+    }
+}
+
+impl IconManifest {
+    pub fn load(path: &str) -> Result<IconManifest> {
+        let data = fs::read(path)
+            .map_err(|e| format!("Could not open icon manifest {}: {}", path, e))?;
+        let mut manifest: IconManifest = serde_json::from_slice(&data)
+            .map_err(|e| format!("Could not parse icon manifest {}: {}", path, e))?;
+        let mut hasher = DefaultHasher::new();
+        data.hash(&mut hasher);
+        manifest.digest = hasher.finish();
+        Ok(manifest)
+    }
+
+    /// The category of `element`, either the one listing it or the first whose tags it has.
+    pub fn landmark_category(&self, element: OsmElementRef, tags: &HashMap<Vec<u8>, Vec<u8>>) -> Option<&str> {
+        self.listed_landmark(element)
+            .or_else(|| self.landmark_match(tags).map(|(category, _)| category))
+    }
+
+    /// The category listing `element` in its `elements`.
+    pub fn listed_landmark(&self, element: OsmElementRef) -> Option<&str> {
+        self.landmarks.iter()
+            .find(|category| category.elements.contains(&element))
+            .map(|category| category.category.as_str())
+    }
+
+    /// The category of a feature, like `landmark_category`, and the tags it matched on.
+    pub fn landmark_match(&self, tags: &HashMap<Vec<u8>, Vec<u8>>) -> Option<(&str, &HashMap<String, String>)> {
+        self.landmarks.iter().find_map(|category| {
+            Some((category.category.as_str(), category.matching_tags(tags)?))
+        })
+    }
+}
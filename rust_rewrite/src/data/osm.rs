@@ -1,18 +1,100 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use crate::errors::Error;
 
 pub type OsmId = u64;
 
+/// Reference to an element of any type. Ids are only unique per element type.
+#[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[archive(check_bytes)]
+#[serde(try_from = "String")]
+pub enum OsmElementRef {
+    Node(OsmId),
+    Way(OsmId),
+    Relation(OsmId),
+}
+
+/// As in OSM URLs, e.g. `way/123`.
+impl fmt::Display for OsmElementRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OsmElementRef::Node(id) => write!(f, "node/{}", id),
+            OsmElementRef::Way(id) => write!(f, "way/{}", id),
+            OsmElementRef::Relation(id) => write!(f, "relation/{}", id),
+        }
+    }
+}
+
+impl FromStr for OsmElementRef {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (element_type, id) = s.split_once('/')
+            .ok_or_else(|| format!("Expected an element like way/123, got {}", s))?;
+        let id = id.parse()?;
+        match element_type {
+            "node" => Ok(OsmElementRef::Node(id)),
+            "way" => Ok(OsmElementRef::Way(id)),
+            "relation" => Ok(OsmElementRef::Relation(id)),
+            _ => Err(format!("Unknown element type {}, expected node, way or relation", element_type).into()),
+        }
+    }
+}
+
+impl TryFrom<String> for OsmElementRef {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse().map_err(|e: Error| e.message)
+    }
+}
+
 /// Map data as defined in the .osm file. Some elements are discarded but most are
 /// kept without any processing.
 
 #[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize, Debug, Default, Clone)]
+#[archive(check_bytes)]
 pub struct OsmMapData {
     pub nodes: HashMap<OsmId, Node>,
     pub ways: HashMap<OsmId, Way>,
     pub relations: HashMap<OsmId, Relation>,
+    /// Area covered by the extract, from its `<bounds>` or else the extent of its nodes.
+    /// None for an extract without nodes.
+    pub bounds: Option<Bounds>,
+}
+
+/// Area covered by an extract, in degrees.
+#[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize, Debug, Clone, Copy)]
+#[archive(check_bytes)]
+pub struct Bounds {
+    pub min_lon: f64,
+    pub min_lat: f64,
+    pub max_lon: f64,
+    pub max_lat: f64,
+}
+
+impl Bounds {
+    /// Smallest bounds containing all `nodes`.
+    pub fn of_nodes<'a, I: IntoIterator<Item = &'a Node>>(nodes: I) -> Option<Bounds> {
+        nodes.into_iter().fold(None, |bounds, node| {
+            let bounds = bounds.unwrap_or(Bounds {
+                min_lon: node.lon,
+                min_lat: node.lat,
+                max_lon: node.lon,
+                max_lat: node.lat,
+            });
+            Some(Bounds {
+                min_lon: bounds.min_lon.min(node.lon),
+                min_lat: bounds.min_lat.min(node.lat),
+                max_lon: bounds.max_lon.max(node.lon),
+                max_lat: bounds.max_lat.max(node.lat),
+            })
+        })
+    }
 }
 
 #[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize, Debug, Default, Clone)]
+#[archive(check_bytes)]
 pub struct Node {
     pub id: OsmId,
     pub lon: f64,
@@ -21,6 +103,7 @@ pub struct Node {
 }
 
 #[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize, Debug, Default, Clone)]
+#[archive(check_bytes)]
 pub struct Way {
     pub id: OsmId,
     pub nodes: Vec<Node>,
@@ -28,8 +111,14 @@ pub struct Way {
 }
 
 #[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize, Debug, Default, Clone)]
+#[archive(check_bytes)]
 pub struct Relation {
     pub id: OsmId,
     pub ways: Vec<Way>,
+    pub nodes: Vec<Node>,
+    /// Role of each member node, in the same order as `nodes`.
+    pub node_roles: Vec<Vec<u8>>,
+    /// Member relations are kept by id only, as they may be defined after this one.
+    pub relations: Vec<OsmId>,
     pub tags: HashMap<Vec<u8>, Vec<u8>>,
 }
@@ -0,0 +1,311 @@
+use std::{collections::HashMap, fs::File, path::Path, str};
+
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::reader::Reader;
+use serde_json::Value;
+
+use crate::data::semantic::MapCoords;
+use crate::errors::Result;
+
+pub enum OverlayGeometry {
+    Point(MapCoords),
+    Line(Vec<MapCoords>),
+    /// Outer ring first, followed by the holes.
+    Polygon(Vec<Vec<MapCoords>>),
+}
+
+/// A feature of a user supplied overlay file. Multi-part geometries are split into one
+/// feature per part, sharing the properties.
+pub struct OverlayFeature {
+    pub geometry: OverlayGeometry,
+    /// GeoJSON properties, the text of GPX child elements like `name` and `desc`, or KML
+    /// `name`, `description` and `ExtendedData`. Non-string values are kept as JSON.
+    pub properties: HashMap<String, String>,
+}
+
+/// Reads a GeoJSON, GPX or KML file, telling them apart by the extension.
+pub fn load_overlay(path: &Path) -> Result<Vec<OverlayFeature>> {
+    let extension = path.extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("geojson" | "json") => {
+            let file = File::open(path)?;
+            let value: Value = serde_json::from_reader(file)
+                .map_err(|e| format!("invalid JSON: {}", e))?;
+            let mut features = Vec::new();
+            read_geojson(&value, &HashMap::new(), &mut features)?;
+            Ok(features)
+        },
+        Some("gpx") => Ok(read_gpx(&XmlElement::load(path)?)),
+        Some("kml") => read_kml(&XmlElement::load(path)?),
+        _ => Err("unknown format, expected .geojson, .json, .gpx or .kml".into()),
+    }
+}
+
+fn geojson_position(value: &Value) -> Result<MapCoords> {
+    match value.as_array().map(|position| position.as_slice()) {
+        Some([lon, lat, ..]) => Ok(MapCoords {
+            lat: lat.as_f64().ok_or("latitude is not a number")?,
+            lon: lon.as_f64().ok_or("longitude is not a number")?,
+        }),
+        _ => Err(format!("invalid position {}", value).into()),
+    }
+}
+
+fn geojson_positions(value: &Value) -> Result<Vec<MapCoords>> {
+    value.as_array()
+        .ok_or_else(|| format!("invalid coordinates {}", value))?
+        .iter()
+        .map(geojson_position)
+        .collect()
+}
+
+fn geojson_polygon(value: &Value) -> Result<Vec<Vec<MapCoords>>> {
+    value.as_array()
+        .ok_or_else(|| format!("invalid polygon {}", value))?
+        .iter()
+        .map(geojson_positions)
+        .collect()
+}
+
+/// Collects the features of any GeoJSON object, with `properties` of the enclosing feature.
+fn read_geojson(value: &Value, properties: &HashMap<String, String>, features: &mut Vec<OverlayFeature>) -> Result<()> {
+    let kind = value["type"].as_str().ok_or("GeoJSON object without a type")?;
+    let coordinates = &value["coordinates"];
+    let mut push = |geometry| features.push(OverlayFeature { geometry, properties: properties.clone() });
+    match kind {
+        "FeatureCollection" => {
+            for feature in value["features"].as_array().ok_or("FeatureCollection without features")? {
+                read_geojson(feature, properties, features)?;
+            }
+        },
+        "Feature" => {
+            let properties = value["properties"].as_object()
+                .map(|object| object.iter()
+                    .map(|(key, value)| {
+                        let value = value.as_str().map_or_else(|| value.to_string(), str::to_string);
+                        (key.clone(), value)
+                    })
+                    .collect())
+                .unwrap_or_default();
+            // Features may have a null geometry.
+            if !value["geometry"].is_null() {
+                read_geojson(&value["geometry"], &properties, features)?;
+            }
+        },
+        "GeometryCollection" => {
+            for geometry in value["geometries"].as_array().ok_or("GeometryCollection without geometries")? {
+                read_geojson(geometry, properties, features)?;
+            }
+        },
+        "Point" => push(OverlayGeometry::Point(geojson_position(coordinates)?)),
+        "MultiPoint" => {
+            for coords in geojson_positions(coordinates)? {
+                push(OverlayGeometry::Point(coords));
+            }
+        },
+        "LineString" => push(OverlayGeometry::Line(geojson_positions(coordinates)?)),
+        "MultiLineString" => {
+            for line in geojson_polygon(coordinates)? {
+                push(OverlayGeometry::Line(line));
+            }
+        },
+        "Polygon" => push(OverlayGeometry::Polygon(geojson_polygon(coordinates)?)),
+        "MultiPolygon" => {
+            for polygon in coordinates.as_array().ok_or("invalid MultiPolygon")? {
+                push(OverlayGeometry::Polygon(geojson_polygon(polygon)?));
+            }
+        },
+        _ => return Err(format!("unknown GeoJSON type {}", kind).into()),
+    }
+    Ok(())
+}
+
+/// Just enough of an XML document tree to read GPX and KML, with namespace prefixes
+/// dropped from all names.
+struct XmlElement {
+    name: String,
+    attributes: HashMap<String, String>,
+    text: String,
+    children: Vec<XmlElement>,
+}
+
+impl XmlElement {
+    fn new(start: &BytesStart) -> Result<XmlElement> {
+        let mut attributes = HashMap::new();
+        for attribute in start.attributes() {
+            let attribute = attribute?;
+            let key = str::from_utf8(attribute.key.local_name().into_inner())?.to_string();
+            attributes.insert(key, attribute.unescape_value()?.into_owned());
+        }
+        Ok(XmlElement {
+            name: str::from_utf8(start.local_name().into_inner())?.to_string(),
+            attributes,
+            text: String::new(),
+            children: Vec::new(),
+        })
+    }
+
+    /// Reads the root element of the file.
+    fn load(path: &Path) -> Result<XmlElement> {
+        let mut reader = Reader::from_file(path)?;
+        reader.trim_text(true);
+        let mut buf = Vec::new();
+        // Open elements, innermost last.
+        let mut stack: Vec<XmlElement> = Vec::new();
+        loop {
+            match reader.read_event_into(&mut buf)? {
+                Event::Start(start) => stack.push(XmlElement::new(&start)?),
+                Event::Empty(start) => {
+                    let element = XmlElement::new(&start)?;
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(element),
+                        None => return Ok(element),
+                    }
+                },
+                Event::End(_) => {
+                    let element = stack.pop().ok_or("unbalanced end tag")?;
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(element),
+                        None => return Ok(element),
+                    }
+                },
+                Event::Text(text) => {
+                    if let Some(element) = stack.last_mut() {
+                        element.text.push_str(&text.unescape()?);
+                    }
+                },
+                Event::CData(data) => {
+                    if let Some(element) = stack.last_mut() {
+                        element.text.push_str(str::from_utf8(&data.into_inner())?);
+                    }
+                },
+                Event::Eof => return Err("no root element".into()),
+                _ => {},
+            }
+            buf.clear();
+        }
+    }
+
+    fn children<'s>(&'s self, name: &'s str) -> impl Iterator<Item = &'s XmlElement> {
+        self.children.iter().filter(move |child| child.name == name)
+    }
+
+    fn child(&self, name: &str) -> Option<&XmlElement> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    /// All elements below this one, depth first.
+    fn descendants(&self) -> Vec<&XmlElement> {
+        let mut descendants = Vec::new();
+        for child in &self.children {
+            descendants.push(child);
+            descendants.extend(child.descendants());
+        }
+        descendants
+    }
+
+    /// Text of the children without children of their own, by name.
+    fn text_children(&self) -> HashMap<String, String> {
+        self.children.iter()
+            .filter(|child| child.children.is_empty() && !child.text.is_empty())
+            .map(|child| (child.name.clone(), child.text.clone()))
+            .collect()
+    }
+}
+
+fn gpx_point(element: &XmlElement) -> Option<MapCoords> {
+    Some(MapCoords {
+        lat: element.attributes.get("lat")?.trim().parse().ok()?,
+        lon: element.attributes.get("lon")?.trim().parse().ok()?,
+    })
+}
+
+/// Waypoints become points, each track segment and route a line.
+fn read_gpx(root: &XmlElement) -> Vec<OverlayFeature> {
+    let mut features = Vec::new();
+    for waypoint in root.children("wpt") {
+        if let Some(coords) = gpx_point(waypoint) {
+            features.push(OverlayFeature {
+                geometry: OverlayGeometry::Point(coords),
+                properties: waypoint.text_children(),
+            });
+        }
+    }
+    for track in root.children("trk") {
+        for segment in track.children("trkseg") {
+            features.push(OverlayFeature {
+                geometry: OverlayGeometry::Line(segment.children("trkpt").filter_map(gpx_point).collect()),
+                properties: track.text_children(),
+            });
+        }
+    }
+    for route in root.children("rte") {
+        features.push(OverlayFeature {
+            geometry: OverlayGeometry::Line(route.children("rtept").filter_map(gpx_point).collect()),
+            properties: route.text_children(),
+        });
+    }
+    features
+}
+
+/// KML coordinates are `lon,lat[,alt]` tuples separated by whitespace.
+fn kml_coordinates(element: &XmlElement) -> Result<Vec<MapCoords>> {
+    let coordinates = element.child("coordinates").ok_or("geometry without coordinates")?;
+    coordinates.text.split_whitespace()
+        .map(|tuple| {
+            let mut parts = tuple.split(',');
+            let lon = parts.next().unwrap_or_default().parse()?;
+            let lat = parts.next().ok_or_else(|| format!("invalid coordinates {}", tuple))?.parse()?;
+            Ok(MapCoords { lat, lon })
+        })
+        .collect()
+}
+
+fn kml_ring(boundary: &XmlElement) -> Result<Vec<MapCoords>> {
+    kml_coordinates(boundary.child("LinearRing").ok_or("boundary without LinearRing")?)
+}
+
+/// Collects the geometries of a placemark, looking into `MultiGeometry` elements.
+fn kml_geometries(element: &XmlElement, geometries: &mut Vec<OverlayGeometry>) -> Result<()> {
+    for child in &element.children {
+        match child.name.as_str() {
+            "Point" => geometries.extend(kml_coordinates(child)?.into_iter().map(OverlayGeometry::Point)),
+            "LineString" | "LinearRing" => geometries.push(OverlayGeometry::Line(kml_coordinates(child)?)),
+            "Polygon" => {
+                let outer = child.child("outerBoundaryIs").ok_or("Polygon without outerBoundaryIs")?;
+                let mut rings = vec![kml_ring(outer)?];
+                for inner in child.children("innerBoundaryIs") {
+                    rings.push(kml_ring(inner)?);
+                }
+                geometries.push(OverlayGeometry::Polygon(rings));
+            },
+            "MultiGeometry" => kml_geometries(child, geometries)?,
+            _ => {},
+        }
+    }
+    Ok(())
+}
+
+/// Reads all placemarks, however deeply nested in documents and folders.
+fn read_kml(root: &XmlElement) -> Result<Vec<OverlayFeature>> {
+    let mut features = Vec::new();
+    for placemark in root.descendants().into_iter().filter(|element| element.name == "Placemark") {
+        let mut properties = placemark.text_children();
+        if let Some(extended_data) = placemark.child("ExtendedData") {
+            for data in extended_data.children("Data") {
+                if let (Some(name), Some(value)) = (data.attributes.get("name"), data.child("value")) {
+                    properties.insert(name.clone(), value.text.clone());
+                }
+            }
+        }
+        let mut geometries = Vec::new();
+        kml_geometries(placemark, &mut geometries)?;
+        features.extend(geometries.into_iter().map(|geometry| OverlayFeature {
+            geometry,
+            properties: properties.clone(),
+        }));
+    }
+    Ok(features)
+}
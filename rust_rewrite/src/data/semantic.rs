@@ -1,25 +1,71 @@
-use std::{collections::{HashMap, HashSet}, hash::Hash};
-use super::osm::{Node, Way};
+use std::{cmp::Ordering, collections::{BinaryHeap, HashMap, HashSet}, hash::Hash};
+use super::osm::{Node, OsmElementRef, OsmId, Way};
 
 /// Collection of all semantic map elements (roads, tube stations, etc.) we could want to draw.
 
 #[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize, Debug, Default, Clone)]
+#[archive(check_bytes)]
 pub struct SemanticMapElements {
     pub underground_stations: Vec<TransportStation>,
     pub rails: Vec<Path>,
-    pub roads: Vec<Path>,
+    pub roads: Vec<Road>,
     pub areas: Vec<Area>,
+    pub waterways: Vec<Waterway>,
+    pub buildings: Vec<Building>,
     pub landmarks: Vec<Landmark>,
     pub tube_rails: Vec<TubeRail>,
+    pub transit_lines: HashMap<TransitLineId, TransitLine>,
     pub councils: Vec<Council>,
+    pub places: Vec<Place>,
 }
 
 #[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize, Debug, Clone)]
+#[archive(check_bytes)]
 pub struct MapCoords {
     pub lat: f64,
     pub lon: f64,
 }
 
+const METRES_PER_DEG_LAT: f64 = 110_540.0;
+const METRES_PER_DEG_LON_AT_EQUATOR: f64 = 111_320.0;
+
+impl MapCoords {
+    /// Local planar approximation around `origin`, in metres east and north of it. Good
+    /// enough for the distances within a city.
+    fn to_local_m(&self, origin: &MapCoords) -> (f64, f64) {
+        (
+            (self.lon - origin.lon) * METRES_PER_DEG_LON_AT_EQUATOR * origin.lat.to_radians().cos(),
+            (self.lat - origin.lat) * METRES_PER_DEG_LAT,
+        )
+    }
+
+    fn from_local_m(origin: &MapCoords, x: f64, y: f64) -> MapCoords {
+        MapCoords {
+            lat: origin.lat + y / METRES_PER_DEG_LAT,
+            lon: origin.lon + x / (METRES_PER_DEG_LON_AT_EQUATOR * origin.lat.to_radians().cos()),
+        }
+    }
+
+    pub fn distance_m(&self, other: &MapCoords) -> f64 {
+        let (x, y) = other.to_local_m(self);
+        x.hypot(y)
+    }
+
+    /// Distance to the closest point on the segment from `a` to `b`.
+    pub fn distance_to_segment_m(&self, a: &MapCoords, b: &MapCoords) -> f64 {
+        let (ax, ay) = a.to_local_m(self);
+        let (bx, by) = b.to_local_m(self);
+        let (dx, dy) = (bx - ax, by - ay);
+        let len_sq = dx * dx + dy * dy;
+        let t = if len_sq == 0.0 {
+            0.0
+        } else {
+            (-(ax * dx + ay * dy) / len_sq).clamp(0.0, 1.0)
+        };
+        (ax + t * dx).hypot(ay + t * dy)
+    }
+}
+
 impl From<&Node> for MapCoords {
     fn from(value: &Node) -> Self {
         MapCoords {
@@ -38,6 +84,194 @@ impl Hash for MapCoords {
 
 impl Eq for MapCoords { }
 
+/// Square cell of the pole of inaccessibility search, in local metres.
+struct PoleCell {
+    x: f64,
+    y: f64,
+    half_size: f64,
+    /// Signed distance from the center to the polygon outline, negative outside.
+    distance: f64,
+    /// Upper bound for the distance of any point within the cell.
+    max_distance: f64,
+}
+
+impl PoleCell {
+    fn new(x: f64, y: f64, half_size: f64, rings: &[Vec<(f64, f64)>]) -> Self {
+        let distance = signed_distance_to_rings(x, y, rings);
+        PoleCell {
+            x,
+            y,
+            half_size,
+            distance,
+            max_distance: distance + half_size * std::f64::consts::SQRT_2,
+        }
+    }
+}
+
+impl PartialEq for PoleCell {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for PoleCell { }
+
+impl PartialOrd for PoleCell {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PoleCell {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.max_distance.total_cmp(&other.max_distance)
+    }
+}
+
+/// Distance from (x, y) to the closest ring edge, negative if the point is outside the
+/// polygon. Rings are combined with the even-odd rule, so holes count as outside.
+fn signed_distance_to_rings(x: f64, y: f64, rings: &[Vec<(f64, f64)>]) -> f64 {
+    let mut inside = false;
+    let mut min_distance = f64::INFINITY;
+    for ring in rings {
+        for (a, b) in ring.iter().zip(ring.iter().cycle().skip(1)) {
+            if (a.1 > y) != (b.1 > y) && x < (b.0 - a.0) * (y - a.1) / (b.1 - a.1) + a.0 {
+                inside = !inside;
+            }
+            let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+            let len_sq = dx * dx + dy * dy;
+            let t = if len_sq == 0.0 {
+                0.0
+            } else {
+                (((x - a.0) * dx + (y - a.1) * dy) / len_sq).clamp(0.0, 1.0)
+            };
+            min_distance = min_distance.min((x - a.0 - t * dx).hypot(y - a.1 - t * dy));
+        }
+    }
+    if inside { min_distance } else { -min_distance }
+}
+
+/// Whether `coords` lies inside `ring`, by ray casting.
+pub fn ring_contains(ring: &[MapCoords], coords: &MapCoords) -> bool {
+    let mut inside = false;
+    for (a, b) in ring.iter().zip(ring.iter().cycle().skip(1)) {
+        if (a.lat > coords.lat) != (b.lat > coords.lat)
+            && coords.lon < (b.lon - a.lon) * (coords.lat - a.lat) / (b.lat - a.lat) + a.lon {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+/// The point inside the polygon farthest from its outline, to within `precision_m`. Unlike
+/// the centroid it always lies inside, even for concave polygons, so it makes a good label
+/// position. See <https://github.com/mapbox/polylabel>.
+pub fn pole_of_inaccessibility(rings: &[Path], precision_m: f64) -> Option<MapCoords> {
+    let origin = rings.iter().flatten().next()?;
+    let local_rings: Vec<Vec<(f64, f64)>> = rings.iter()
+        .map(|ring| ring.iter().map(|coords| coords.to_local_m(origin)).collect())
+        .collect();
+
+    let points = || local_rings.iter().flatten();
+    let min_x = points().map(|p| p.0).fold(f64::INFINITY, f64::min);
+    let min_y = points().map(|p| p.1).fold(f64::INFINITY, f64::min);
+    let max_x = points().map(|p| p.0).fold(f64::NEG_INFINITY, f64::max);
+    let max_y = points().map(|p| p.1).fold(f64::NEG_INFINITY, f64::max);
+    let cell_size = (max_x - min_x).min(max_y - min_y);
+    if cell_size <= 0.0 {
+        return Some(MapCoords::from_local_m(origin, (min_x + max_x) / 2.0, (min_y + max_y) / 2.0));
+    }
+
+    let mut queue = BinaryHeap::new();
+    let half_size = cell_size / 2.0;
+    let mut x = min_x;
+    while x < max_x {
+        let mut y = min_y;
+        while y < max_y {
+            queue.push(PoleCell::new(x + half_size, y + half_size, half_size, &local_rings));
+            y += cell_size;
+        }
+        x += cell_size;
+    }
+
+    let mut best = PoleCell::new((min_x + max_x) / 2.0, (min_y + max_y) / 2.0, 0.0, &local_rings);
+    while let Some(cell) = queue.pop() {
+        if cell.distance > best.distance {
+            best = PoleCell::new(cell.x, cell.y, 0.0, &local_rings);
+        }
+        if cell.max_distance - best.distance <= precision_m {
+            continue;
+        }
+        let half_size = cell.half_size / 2.0;
+        for (dx, dy) in [(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)] {
+            queue.push(PoleCell::new(cell.x + dx * half_size, cell.y + dy * half_size, half_size, &local_rings));
+        }
+    }
+    Some(MapCoords::from_local_m(origin, best.x, best.y))
+}
+
+/// Segments of a set of paths bucketed in a grid of lat/lon cells, to find the paths
+/// running near a point without measuring the distance to every segment.
+pub struct SegmentGrid<'a> {
+    paths: &'a [&'a [MapCoords]],
+    cell_size_deg: f64,
+    /// Path and index of the first point of each segment crossing a cell.
+    cells: HashMap<(i64, i64), Vec<(usize, usize)>>,
+}
+
+impl<'a> SegmentGrid<'a> {
+    /// Grid over `paths` with cells about `cell_size_m` high. Queries are cheapest for
+    /// distances of about the cell size.
+    pub fn new(paths: &'a [&'a [MapCoords]], cell_size_m: f64) -> Self {
+        let mut grid = SegmentGrid {
+            paths,
+            cell_size_deg: cell_size_m / METRES_PER_DEG_LAT,
+            cells: HashMap::new(),
+        };
+        for (path_idx, path) in paths.iter().enumerate() {
+            for (segment_idx, segment) in path.windows(2).enumerate() {
+                let (min, max) = Self::bounds(&segment[0], &segment[1]);
+                for cell in grid.cell_range(&min, &max) {
+                    grid.cells.entry(cell).or_default().push((path_idx, segment_idx));
+                }
+            }
+        }
+        grid
+    }
+
+    fn bounds(a: &MapCoords, b: &MapCoords) -> (MapCoords, MapCoords) {
+        (
+            MapCoords { lat: a.lat.min(b.lat), lon: a.lon.min(b.lon) },
+            MapCoords { lat: a.lat.max(b.lat), lon: a.lon.max(b.lon) },
+        )
+    }
+
+    fn cell_range(&self, min: &MapCoords, max: &MapCoords) -> impl Iterator<Item = (i64, i64)> {
+        let cell = |degrees: f64| (degrees / self.cell_size_deg).floor() as i64;
+        let (lat0, lat1, lon0, lon1) = (cell(min.lat), cell(max.lat), cell(min.lon), cell(max.lon));
+        (lat0..=lat1).flat_map(move |lat| (lon0..=lon1).map(move |lon| (lat, lon)))
+    }
+
+    /// Indices of the paths with a segment closer than `distance_m` to `coords`, in
+    /// ascending order.
+    pub fn paths_near(&self, coords: &MapCoords, distance_m: f64) -> Vec<usize> {
+        let min = MapCoords::from_local_m(coords, -distance_m, -distance_m);
+        let max = MapCoords::from_local_m(coords, distance_m, distance_m);
+        let mut paths: Vec<usize> = self.cell_range(&min, &max)
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .filter(|(path_idx, segment_idx)| {
+                let path = self.paths[*path_idx];
+                coords.distance_to_segment_m(&path[*segment_idx], &path[*segment_idx + 1]) < distance_m
+            })
+            .map(|(path_idx, _)| *path_idx)
+            .collect();
+        paths.sort_unstable();
+        paths.dedup();
+        paths
+    }
+}
+
 impl PartialEq for MapCoords {
     fn eq(&self, other: &Self) -> bool {
         self.lat == other.lat && self.lon == other.lon
@@ -45,19 +279,41 @@ impl PartialEq for MapCoords {
 }
 
 #[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize, Debug, Clone)]
+#[archive(check_bytes)]
 pub struct TransportStation {
     pub name: String,
     pub station_type: TransportStationType,
+    pub network: Option<String>,
     pub lat: f64,
     pub lon: f64,
+    /// The OSM elements this station was built from. Several elements with the same name
+    /// and network are merged into one station.
+    pub osm_elements: Vec<OsmElementRef>,
+    /// Location of each separate part of the station, like platforms of different lines
+    /// mapped apart, used to draw connected interchange rings. Elements describing the same
+    /// part share a location.
+    pub locations: Vec<MapCoords>,
+    /// Distinct lines serving this station.
+    pub lines: Vec<TransitLineId>,
 }
 
-#[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize, Debug, Clone)]
+impl TransportStation {
+    /// Served by more than one line, or made up of separate parts.
+    pub fn is_interchange(&self) -> bool {
+        self.lines.len() > 1 || self.locations.len() > 1
+    }
+}
+
+#[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize, Debug, Clone, Copy)]
+#[archive(check_bytes)]
 pub enum TransportStationType {
     Underground,
     Overground,
     Dlr,
     ElizabethLine,
+    NationalRail,
+    Tram,
+    CableCar,
 }
 
 impl From<&TransportStation> for MapCoords {
@@ -80,13 +336,19 @@ impl From<&Way> for Path {
 }
 
 #[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize, Debug, Clone)]
+#[archive(check_bytes)]
 pub struct Area {
     pub area_type: AreaType,
     pub area_polygons: Vec<Path>,
 }
 
 impl Area {
-    fn reorder_ways(unordered_ways: &Vec<Path>) -> Vec<Path> {
+    /// Joins the ways of a multipolygon into closed rings, reversing ways where needed so
+    /// that each ring runs in one direction. The first way of a ring keeps its direction
+    /// unless its end doesn't link to the next way, so a ring of ways that already run head
+    /// to tail, like the two halves of a boundary, keeps their orientation. Rings are
+    /// otherwise in no particular winding order, callers that care have to check it.
+    pub fn reorder_ways(unordered_ways: &Vec<Path>) -> Vec<Path> {
         fn get_next(link_node: &MapCoords, way: &Path, by_end: &HashMap<MapCoords, Vec<Path>>) -> Option<(MapCoords, Path)> {
             let next_link = if *link_node == way[0] {
                 &way[way.len() - 1]
@@ -126,8 +388,8 @@ impl Area {
 
         let mut by_end: HashMap<MapCoords, Vec<Path>> = HashMap::new();
         for way in unordered_ways {
-            by_end.entry(way[0].clone()).or_insert(Vec::new()).push(way.clone());
-            by_end.entry(way[way.len() - 1].clone()).or_insert(Vec::new()).push(way.clone());
+            by_end.entry(way[0].clone()).or_default().push(way.clone());
+            by_end.entry(way[way.len() - 1].clone()).or_default().push(way.clone());
         }
 
         let mut ordered: Vec<Vec<Path>> = vec![vec![unordered_ways[0].clone()]];
@@ -153,7 +415,27 @@ impl Area {
             }
         }
 
-        ordered.iter().map(|group| group.concat()).collect()
+        ordered.iter()
+            .map(|group| {
+                let mut ring: Path = Vec::new();
+                for (idx, way) in group.iter().enumerate() {
+                    let reverse = if idx == 0 {
+                        // Orient the first way so that its end links to the second way,
+                        // keeping its direction if either end would do.
+                        let last = &way[way.len() - 1];
+                        group.get(1).is_some_and(|next| *last != next[0] && *last != next[next.len() - 1])
+                    } else {
+                        ring.last() != Some(&way[0])
+                    };
+                    if reverse {
+                        ring.extend(way.iter().rev().cloned());
+                    } else {
+                        ring.extend(way.iter().cloned());
+                    }
+                }
+                ring
+            })
+            .collect()
     }
 
     pub fn new(area_type: AreaType, unordered_ways: &Vec<Path>) -> Self {
@@ -164,18 +446,102 @@ impl Area {
     }
 }
 
-#[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize, Debug, Clone)]
+/// Land use and land cover. Ordered bottom to top: broad land use first, so that the
+/// parks, pitches and water within it are drawn on top.
+#[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[archive(check_bytes)]
+#[serde(rename_all = "snake_case")]
 pub enum AreaType {
+    Residential,
+    Commercial,
+    Retail,
+    Industrial,
+    RailwayLand,
+    Farmland,
+    /// School, college and university grounds.
+    Education,
+    Hospital,
+    Cemetery,
+    Allotments,
+    GolfCourse,
     Park,
     Wood,
+    Wetland,
+    SportsPitch,
+    Beach,
     Water,
 }
 
+impl AreaType {
+    pub const ALL: [AreaType; 17] = [
+        AreaType::Residential,
+        AreaType::Commercial,
+        AreaType::Retail,
+        AreaType::Industrial,
+        AreaType::RailwayLand,
+        AreaType::Farmland,
+        AreaType::Education,
+        AreaType::Hospital,
+        AreaType::Cemetery,
+        AreaType::Allotments,
+        AreaType::GolfCourse,
+        AreaType::Park,
+        AreaType::Wood,
+        AreaType::Wetland,
+        AreaType::SportsPitch,
+        AreaType::Beach,
+        AreaType::Water,
+    ];
+}
+
+/// Any `highway=*` way, from motorways to footpaths.
+#[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize, Debug, Clone)]
+#[archive(check_bytes)]
+pub struct Road {
+    pub name: Option<String>,
+    pub path: Path,
+}
+
+/// River, canal or stream drawn as a line. Wide rivers are usually also mapped as water
+/// areas, which are drawn underneath.
 #[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize, Debug, Clone)]
+#[archive(check_bytes)]
+pub struct Waterway {
+    pub name: Option<String>,
+    pub waterway_type: WaterwayType,
+    pub path: Path,
+}
+
+/// Ordered from most to least important.
+#[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[archive(check_bytes)]
+pub enum WaterwayType {
+    River,
+    Canal,
+    /// Streams, drains and ditches.
+    Stream,
+}
+
+/// Building footprint from a `building=*` way or multipolygon.
+#[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize, Debug, Clone)]
+#[archive(check_bytes)]
+pub struct Building {
+    /// Outer and inner rings, filled with the even-odd rule.
+    pub polygons: Vec<Path>,
+    /// From the `height` tag, in metres.
+    pub height_m: Option<f32>,
+    /// From the `building:levels` tag.
+    pub levels: Option<u32>,
+}
+
+#[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize, Debug, Clone)]
+#[archive(check_bytes)]
 pub struct Landmark {
     pub lon: f64,
     pub lat: f64,
-    pub landmark_type: LandmarkType,
+    /// One of the categories of the icon manifest.
+    pub category: String,
+    pub name: Option<String>,
 }
 
 impl From<&Landmark> for MapCoords {
@@ -187,60 +553,77 @@ impl From<&Landmark> for MapCoords {
     }
 }
 
-#[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize, Debug, Clone)]
-pub enum LandmarkType {
-    Lgbtq,
-    LgbtqMen,
-    CocktailBar,
-    ClimbingBoulder,
-    ClimbingRope,
-    ClimbingOutdoor,
-    Gym,
-    Hospital,
-    MusicVenue,
-    Tree,
-    TubeEmergencyExit,
-    TempleAetheriusSociety,
-    TempleBuddhist,
-    TempleChristian,
-    TempleHindu,
-    TempleHumanist,
-    TempleJain,
-    TempleJewish,
-    TempleMuslim,
-    TempleRastafarian,
-    TempleRosicucian,
-    TempleScientologist,
-    TempleSelfRealizationFellowship,
-    TempleSikh,
-}
+/// Lines are identified by the id of their `route_master` relation, or of their `route`
+/// relation if it isn't part of a route master.
+pub type TransitLineId = OsmId;
 
 #[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize, Debug, Clone)]
+#[archive(check_bytes)]
 pub struct TubeRail {
-    pub line: TubeLine,
+    pub line: TransitLineId,
     pub path: Path,
 }
 
-#[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize, Debug, Clone, PartialEq, PartialOrd, Eq, Ord)]
-pub enum TubeLine {
-    Bakerloo,
-    Central,
-    Circle,
-    District,
-    Dlr,
-    Elizabeth,
-    HammersmithAndCity,
-    Jubilee,
-    Metropolitan,
-    Northern,
-    Overground,  // Not technically a tube line, sue me
-    Piccadilly,
-    Victoria,
-    WaterlooAndCity,
+/// A public transport line, as described by OSM `route` / `route_master` relations.
+#[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize, Debug, Clone)]
+#[archive(check_bytes)]
+pub struct TransitLine {
+    pub id: TransitLineId,
+    pub name: String,
+    /// The `ref` tag, e.g. "U2" or "A".
+    pub reference: Option<String>,
+    /// The `colour` tag as given in OSM, usually a hex code like "#0019A8".
+    pub colour: Option<String>,
+    pub network: Option<String>,
+    pub mode: TransitMode,
+}
+
+#[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+#[archive(check_bytes)]
+#[serde(rename_all = "snake_case")]
+pub enum TransitMode {
+    Subway,
+    LightRail,
+    Train,
+    Tram,
+    Monorail,
 }
 
 #[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize, Debug, Clone, PartialEq)]
+#[archive(check_bytes)]
 pub struct Council {
     pub name: String,
+    /// Where to put the label.
     pub center: MapCoords,
+    /// Outline of the borough, as closed rings.
+    pub boundary: Vec<Path>,
+}
+
+/// Named settlement or part of one, from `place=*` tags.
+#[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize, Debug, Clone)]
+#[archive(check_bytes)]
+pub struct Place {
+    pub name: String,
+    pub place_type: PlaceType,
+    pub population: Option<u64>,
+    pub center: MapCoords,
+}
+
+/// Ordered from most to least important.
+#[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[archive(check_bytes)]
+#[serde(rename_all = "snake_case")]
+pub enum PlaceType {
+    Town,
+    Suburb,
+    Village,
+    Quarter,
+    Neighbourhood,
+}
+
+impl Place {
+    /// Sort key, lower is more important. Places of the same type are ranked by population.
+    pub fn rank(&self) -> (PlaceType, std::cmp::Reverse<u64>) {
+        (self.place_type, std::cmp::Reverse(self.population.unwrap_or(0)))
+    }
 }
@@ -1,11 +1,15 @@
+pub mod artifact;
 pub mod draw_map;
+pub mod export_geojson;
 pub mod parse_osm;
 pub mod semantic_map;
+pub mod stats;
 
 use std::path::Path;
-use log::{info, error};
+use log::{info, error, warn};
 
-use crate::errors::Result;
+use crate::errors::{Error, Result};
+use stats::{EtlStats, PhaseStats};
 
 
 pub trait Etl {
@@ -21,41 +25,75 @@ pub trait Etl {
     fn transform(&mut self, input: Self::Input) -> Result<Self::Output>;
     fn load(&mut self, dir: &Path, output: Self::Output) -> Result<()>;
 
+    /// Statistics of the current run, which the stage adds its counts to.
+    fn stats(&mut self) -> &mut EtlStats;
+
     fn process(&mut self, dir: &Path) -> Result<()> {
         info!(etl_name = self.etl_name(); "Starting ETL process");
         if self.is_cached(dir)? {
             info!(etl_name = self.etl_name(); "Using cached value");
         } else {
             info!(etl_name = self.etl_name(); "Extracting");
-            let input = match self.extract(dir) {
+            let (input, phase) = PhaseStats::measure("extract", || self.extract(dir));
+            self.stats().phases.push(phase);
+            let input = match input {
                 Ok(input) => Ok(input),
                 Err(err) => {
                     error!(etl_name = self.etl_name(), err = err.message; "Extraction failed with error");
+                    self.write_failed_stats(dir, "extract", &err);
                     Err(err)
                 },
             }?;
             
             info!(etl_name = self.etl_name(); "Transforming");
-            let output = match self.transform(input) {
+            let (output, phase) = PhaseStats::measure("transform", || self.transform(input));
+            self.stats().phases.push(phase);
+            let output = match output {
                 Ok(output) => Ok(output),
                 Err(err) => {
                     error!(etl_name = self.etl_name(), err = err.message; "Transformation failed with error");
+                    self.write_failed_stats(dir, "transform", &err);
                     Err(err)
                 },
             }?;
 
             info!(etl_name = self.etl_name(); "Loading");
-            match self.load(dir, output) {
+            let (result, phase) = PhaseStats::measure("load", || self.load(dir, output));
+            self.stats().phases.push(phase);
+            match result {
                 Ok(_) => Ok(()),
                 Err(err) => {
                     error!(etl_name = self.etl_name(), err = err.message; "Loading failed with error");
+                    self.write_failed_stats(dir, "load", &err);
                     Err(err)
                 },
             }?;
+
+            self.write_stats(dir)?;
         }
         info!(etl_name = self.etl_name(); "Process finished");
         Ok(())
     }
+
+    fn write_stats(&mut self, dir: &Path) -> Result<()> {
+        let etl_name = self.etl_name().to_string();
+        let stats = self.stats();
+        stats.etl_name = etl_name;
+        stats.write(dir)?;
+        stats.log();
+        Ok(())
+    }
+
+    /// Writes the statistics gathered until `phase` failed, which tell how far the stage
+    /// got. Problems writing them are only logged, so that they don't hide `err`.
+    fn write_failed_stats(&mut self, dir: &Path, phase: &'static str, err: &Error) {
+        let stats = self.stats();
+        stats.failed_phase = Some(phase);
+        stats.error = Some(err.message.clone());
+        if let Err(write_err) = self.write_stats(dir) {
+            warn!(etl_name = self.etl_name(), err = write_err.message; "Could not write statistics of the failed run");
+        }
+    }
 }
 
 
@@ -0,0 +1,34 @@
+use std::fs::{self, File};
+use std::path::Path;
+
+use rkyv::de::deserializers::SharedDeserializeMap;
+use rkyv::ser::serializers::AllocSerializer;
+use rkyv::validation::validators::DefaultValidator;
+use rkyv::{AlignedVec, Archive, CheckBytes, Deserialize, Serialize};
+
+use crate::errors::Result;
+
+/// Writes the artifact of a stage for the following stages to read.
+pub fn write<T: Serialize<AllocSerializer<256>>>(path: &Path, value: &T) -> Result<()> {
+    let bytes = rkyv::to_bytes::<_, 256>(value)
+        .map_err(|e| format!("Could not serialize {}: {}", path.display(), e))?;
+    fs::write(path, &bytes)?;
+    Ok(())
+}
+
+/// Reads an artifact written by `write`. The archive is validated before it is used, so a
+/// file from a build with a different layout is an error rather than garbage. Layout
+/// changes should still bump the version in the file name, so that such files are rebuilt.
+pub fn read<T>(path: &Path) -> Result<T>
+where
+    T: Archive,
+    for<'a> T::Archived: CheckBytes<DefaultValidator<'a>> + Deserialize<T, SharedDeserializeMap>,
+{
+    let mut file = File::open(path)?;
+    let mut bytes = AlignedVec::new();
+    bytes.extend_from_reader(&mut file)?;
+    let value = rkyv::from_bytes::<T>(&bytes).map_err(|e| {
+        format!("{} doesn't match this build, delete it to rebuild it: {}", path.display(), e)
+    })?;
+    Ok(value)
+}
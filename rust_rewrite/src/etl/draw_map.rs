@@ -1,27 +1,291 @@
-use core::f64;
-use std::{cmp::Ordering, fs::{self, File}, io::Read, path::{Path, PathBuf}};
+use std::{collections::HashMap, fs::{self, File}, path::{Path, PathBuf}, rc::Rc};
 
 use png::{self, BitDepth, ColorType};
-use raqote::{BlendMode, DrawOptions, DrawTarget, Image, LineCap, LineJoin, PathBuilder, Point, SolidSource, Source, StrokeStyle};
+use raqote::{DrawOptions, DrawTarget, ExtendMode, FilterMode, Image, LineCap, LineJoin, PathBuilder, SolidSource, Source, StrokeStyle, Transform, Winding};
+use log::warn;
 use serde::Deserialize;
 
 use crate::{
     data::semantic::{
-        self, Area, Council, Landmark, MapCoords, SemanticMapElements, TransportStation
+        self, Area, AreaType, Council, Landmark, MapCoords, SemanticMapElements, PlaceType, Road, TransitLine, TransitLineId, TransitMode, TransportStation, TubeRail, Waterway, WaterwayType, Building
     },
     errors::Result, UserConfig,
 };
 
-use super::{semantic_map, Etl};
+use super::{artifact, semantic_map, Etl};
+use super::stats::EtlStats;
+
+mod furniture;
+mod gazetteer;
+mod generalize;
+mod graticule;
+mod icons;
+mod labels;
+mod line_bundling;
+mod overlays;
+mod patterns;
+mod spatial_index;
+mod text;
+
+pub use furniture::Furniture;
+pub use gazetteer::GridStyle;
+pub use generalize::Generalization;
+pub use graticule::GraticuleStyle;
+pub use icons::IconRegistry;
+pub use overlays::{OverlayLayer, Overlays};
+pub use patterns::{Pattern, Textures};
+use furniture::FurnitureBlock;
+use gazetteer::{write_index_csv, IndexEntry, INDEX_CSV_FILE_NAME};
+use generalize::{signed_area, simplify_indices, smooth, GeneralizationStyle};
+use icons::Icon;
+use labels::{path_length, place_along_path, wrap_words, GlyphPlacement, LabelPlacer};
+use line_bundling::LineBundles;
+use overlays::MapLayer;
+use patterns::{grid_points, hatch_path, push_dot, push_symbol};
+use spatial_index::{BoundingBox, GridIndex};
+use text::{FontChain, LoadedFont, ShapedGlyph, ShapedText};
 
 mod fk {
     pub use font_kit::font::Font;
-    pub use pathfinder_geometry::vector::vec2f;
+    pub use font_kit::hinting::HintingOptions;
+    pub use font_kit::outline::OutlineSink;
+    pub use pathfinder_geometry::line_segment::LineSegment2F;
+    pub use pathfinder_geometry::vector::Vector2F;
 }
 
 pub const ETL_NAME: &str = "draw_map";
 pub const OUTPUT_FILE_NAME: &str = "output.png";
 
+const CELL_SIZE: usize = 4096 * 2;
+const INDEX_BUCKET_SIZE: f32 = 2048.0;
+
+const STATION_LABEL_OFFSET: f32 = 35.0;
+const STATION_DOT_RADIUS: f32 = 12.0;
+const STATION_DOT_OUTLINE: f32 = 4.0;
+const INTERCHANGE_RING_RADIUS: f32 = 18.0;
+const INTERCHANGE_RING_WIDTH: f32 = 7.0;
+const INTERCHANGE_CONNECTOR_WIDTH: f32 = 14.0;
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum FontWeight {
+    #[default]
+    Regular,
+    Medium,
+    SemiBold,
+    Bold,
+}
+
+impl FontWeight {
+    fn font_path(&self) -> &'static str {
+        match self {
+            FontWeight::Regular => "resources/fonts/Domine-Regular.ttf",
+            FontWeight::Medium => "resources/fonts/Domine-Medium.ttf",
+            FontWeight::SemiBold => "resources/fonts/Domine-SemiBold.ttf",
+            FontWeight::Bold => "resources/fonts/Domine-Bold.ttf",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum TextCase {
+    #[default]
+    Original,
+    Upper,
+    Lower,
+}
+
+/// Outline drawn behind text, so that it stays readable on top of lines.
+#[derive(Deserialize)]
+pub struct Halo<'a> {
+    #[serde(deserialize_with = "deserialize")]
+    pub color: Source<'a>,
+    pub width: f32,
+}
+
+#[derive(Deserialize)]
+pub struct TextStyle<'a> {
+    /// Font file to use instead of the bundled font of `weight`.
+    #[serde(default)]
+    pub font: Option<String>,
+    #[serde(default)]
+    pub weight: FontWeight,
+    /// In px per em.
+    pub size: f32,
+    /// Extra space after each glyph, as a fraction of `size`.
+    #[serde(default)]
+    pub letter_spacing: f32,
+    #[serde(default)]
+    pub case: TextCase,
+    /// Defaults to the theme colour for the kind of label, e.g. `place_name_color`.
+    #[serde(default, deserialize_with = "deserialize_optional")]
+    pub color: Option<Source<'a>>,
+    #[serde(default)]
+    pub halo: Option<Halo<'a>>,
+    /// Longer text is wrapped onto several lines at spaces, in px. Labels along lines are
+    /// never wrapped.
+    #[serde(default)]
+    pub max_width: Option<f32>,
+    /// Caps the number of lines when wrapping, even if lines stay wider than `max_width`.
+    #[serde(default)]
+    pub max_lines: Option<usize>,
+    /// Distance between baselines, as a fraction of `size`.
+    #[serde(default = "default_line_height")]
+    pub line_height: f32,
+}
+
+fn default_line_height() -> f32 {
+    1.2
+}
+
+impl TextStyle<'_> {
+    fn font_path(&self) -> &str {
+        self.font.as_deref().unwrap_or(self.weight.font_path())
+    }
+
+    fn halo_width(&self) -> f32 {
+        self.halo.as_ref().map_or(0.0, |halo| halo.width)
+    }
+}
+
+/// Typography per kind of label.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct LabelStyles<'a> {
+    pub station: TextStyle<'a>,
+    pub council: TextStyle<'a>,
+    pub waterway: TextStyle<'a>,
+    /// Places of types missing here are not labelled.
+    pub places: HashMap<PlaceType, TextStyle<'a>>,
+}
+
+/// Text style without colour, halo or wrapping.
+fn plain_text_style<'a>(weight: FontWeight, size: f32, letter_spacing: f32, case: TextCase) -> TextStyle<'a> {
+    TextStyle {
+        font: None,
+        weight,
+        size,
+        letter_spacing,
+        case,
+        color: None,
+        halo: None,
+        max_width: None,
+        max_lines: None,
+        line_height: default_line_height(),
+    }
+}
+
+impl Default for LabelStyles<'_> {
+    fn default() -> Self {
+        LabelStyles {
+            station: plain_text_style(FontWeight::Bold, 40.0, 0.0, TextCase::Original),
+            council: plain_text_style(FontWeight::Bold, 120.0, 0.7, TextCase::Original),
+            waterway: plain_text_style(FontWeight::Medium, 40.0, 0.1, TextCase::Original),
+            places: HashMap::from([
+                (PlaceType::Town, plain_text_style(FontWeight::Bold, 90.0, 0.25, TextCase::Upper)),
+                (PlaceType::Suburb, plain_text_style(FontWeight::SemiBold, 70.0, 0.2, TextCase::Upper)),
+                (PlaceType::Village, plain_text_style(FontWeight::Medium, 56.0, 0.1, TextCase::Original)),
+                (PlaceType::Quarter, plain_text_style(FontWeight::Medium, 46.0, 0.05, TextCase::Original)),
+                (PlaceType::Neighbourhood, plain_text_style(FontWeight::Regular, 38.0, 0.0, TextCase::Original)),
+            ]),
+        }
+    }
+}
+
+/// Used for buildings without a `height` tag, per level or if the levels are missing too.
+const BUILDING_LEVEL_HEIGHT_M: f32 = 3.0;
+const DEFAULT_BUILDING_HEIGHT_M: f32 = 8.0;
+
+/// Labels of the same waterway are at least this far apart.
+const WATERWAY_LABEL_REPEAT: f32 = 3000.0;
+/// Largest turn between two glyphs of a waterway label, in radians.
+const WATERWAY_LABEL_MAX_BEND: f32 = 0.35;
+
+/// Places glyphs at `offsets` along `points`, with the text centered `fraction` of the way
+/// along. The text runs left to right, so that it stays upright.
+fn place_upright(points: &[(f32, f32)], fraction: f32, offsets: &[f32], width: f32) -> Option<Vec<GlyphPlacement>> {
+    let length = path_length(points);
+    let start = length * fraction - width / 2.0;
+    let glyphs = place_along_path(points, start, offsets, width, WATERWAY_LABEL_MAX_BEND)?;
+    if glyphs.iter().map(|glyph| glyph.angle.cos()).sum::<f32>() >= 0.0 {
+        return Some(glyphs);
+    }
+    let reversed: Vec<(f32, f32)> = points.iter().rev().copied().collect();
+    place_along_path(&reversed, length - start - width, offsets, width, WATERWAY_LABEL_MAX_BEND)
+}
+
+fn waterway_width(waterway_type: WaterwayType) -> f32 {
+    match waterway_type {
+        WaterwayType::River => 14.0,
+        WaterwayType::Canal => 10.0,
+        WaterwayType::Stream => 4.0,
+    }
+}
+
+/// A label whose position was fixed before drawing, in absolute pixel coordinates.
+struct PlacedLabel<'a> {
+    text: String,
+    style: &'a TextStyle<'a>,
+    x: f32,
+    y: f32,
+}
+
+/// A label that follows a line, with each glyph placed and rotated separately.
+struct PathLabel<'a> {
+    shaped: Vec<ShapedGlyph>,
+    glyphs: Vec<GlyphPlacement>,
+    style: &'a TextStyle<'a>,
+    bbox: BoundingBox,
+}
+
+/// Collects glyph outlines into a path. Outlines are in font units with y pointing up, so
+/// they are scaled, flipped and then rotated and moved to the glyph's placement.
+struct GlyphPathSink {
+    pb: PathBuilder,
+    scale: f32,
+    /// Moves the glyphs down, in px.
+    baseline_shift: f32,
+    placement: GlyphPlacement,
+}
+
+impl GlyphPathSink {
+    fn map(&self, v: fk::Vector2F) -> (f32, f32) {
+        let (x, y) = (v.x() * self.scale, self.baseline_shift - v.y() * self.scale);
+        let (sin, cos) = self.placement.angle.sin_cos();
+        (self.placement.x + x * cos - y * sin, self.placement.y + x * sin + y * cos)
+    }
+}
+
+impl fk::OutlineSink for GlyphPathSink {
+    fn move_to(&mut self, to: fk::Vector2F) {
+        let (x, y) = self.map(to);
+        self.pb.move_to(x, y);
+    }
+
+    fn line_to(&mut self, to: fk::Vector2F) {
+        let (x, y) = self.map(to);
+        self.pb.line_to(x, y);
+    }
+
+    fn quadratic_curve_to(&mut self, ctrl: fk::Vector2F, to: fk::Vector2F) {
+        let (cx, cy) = self.map(ctrl);
+        let (x, y) = self.map(to);
+        self.pb.quad_to(cx, cy, x, y);
+    }
+
+    fn cubic_curve_to(&mut self, ctrl: fk::LineSegment2F, to: fk::Vector2F) {
+        let (c1x, c1y) = self.map(ctrl.from());
+        let (c2x, c2y) = self.map(ctrl.to());
+        let (x, y) = self.map(to);
+        self.pb.cubic_to(c1x, c1y, c2x, c2y, x, y);
+    }
+
+    fn close(&mut self) {
+        self.pb.close();
+    }
+}
+
 enum PathStyle {
     Road,
     Rail,
@@ -33,19 +297,13 @@ pub struct OwnedImage {
     pub data: Vec<u32>,
 }
 
-use serialize_color::deserialize;
+use serialize_color::{deserialize, deserialize_optional, parse_osm_colour};
 
 #[derive(Deserialize)]
 pub struct Theme<'a> {
     #[serde(deserialize_with = "deserialize")]
     pub background_color: Source<'a>,
 
-    #[serde(deserialize_with = "deserialize")]
-    pub park_color: Source<'a>,
-
-    #[serde(deserialize_with = "deserialize")]
-    pub wood_color: Source<'a>,
-
     #[serde(deserialize_with = "deserialize")]
     pub road_color: Source<'a>,
 
@@ -59,7 +317,195 @@ pub struct Theme<'a> {
     pub council_name_color: Source<'a>,
 
     #[serde(deserialize_with = "deserialize")]
-    pub water_color: Source<'a>,
+    pub place_name_color: Source<'a>,
+
+    #[serde(deserialize_with = "deserialize")]
+    pub water_name_color: Source<'a>,
+
+    #[serde(default)]
+    pub labels: LabelStyles<'a>,
+
+    /// Font files tried in order for characters missing from the map fonts.
+    #[serde(default)]
+    pub fallback_fonts: Vec<String>,
+
+    /// Styles per area type. Areas of types missing here are not drawn, which is warned
+    /// about when the theme is loaded. Water is required, its colour is used for waterway
+    /// lines too.
+    #[serde(default)]
+    pub areas: HashMap<AreaType, AreaStyle<'a>>,
+
+    /// Recolours icons of the icon manifest, by name. Only the icons' shapes are kept.
+    #[serde(default)]
+    pub icons: HashMap<String, IconStyle<'a>>,
+
+    /// Styles per transit line, keyed by the line's `ref`, `name` or `network` tag (tried
+    /// in that order, ignoring case). Lines listed here are always drawn.
+    #[serde(default)]
+    pub tube_lines: HashMap<String, TubeLineStyle<'a>>,
+
+    /// Lines of these modes are drawn even without an entry in `tube_lines`, using their
+    /// OSM `colour` tag, or `rail_color` if it is missing.
+    #[serde(default = "default_transit_modes")]
+    pub transit_modes: Vec<TransitMode>,
+
+    /// Distance between the centers of lines running along shared track.
+    #[serde(default = "default_tube_line_spacing")]
+    pub tube_line_spacing: f32,
+
+    /// Rings of stations served by several lines, also used in the legend.
+    #[serde(default)]
+    pub interchange: InterchangeStyle<'a>,
+
+    /// Building footprints are only drawn if this is set.
+    #[serde(default)]
+    pub buildings: Option<BuildingStyle<'a>>,
+
+    /// Borough boundaries are only drawn if this is set.
+    #[serde(default)]
+    pub council_boundary: Option<BoundaryStyle<'a>>,
+
+    /// Latitude/longitude or national grid lines.
+    #[serde(default)]
+    pub graticule: Option<GraticuleStyle<'a>>,
+
+    /// A–Z reference grid. The index of its squares is only written if this is set.
+    #[serde(default)]
+    pub grid: Option<GridStyle<'a>>,
+
+    /// Legend, scale bar and the like, drawn on top of the map.
+    #[serde(default)]
+    pub furniture: Furniture<'a>,
+}
+
+#[derive(Deserialize)]
+pub struct AreaStyle<'a> {
+    #[serde(deserialize_with = "deserialize")]
+    pub color: Source<'a>,
+
+    /// Drawn on top of `color`.
+    #[serde(default)]
+    pub pattern: Option<Pattern<'a>>,
+}
+
+#[derive(Deserialize)]
+pub struct InterchangeStyle<'a> {
+    #[serde(deserialize_with = "deserialize")]
+    pub fill: Source<'a>,
+
+    /// Outline of the rings and the bar joining them.
+    #[serde(deserialize_with = "deserialize")]
+    pub stroke: Source<'a>,
+
+    #[serde(default = "default_interchange_width")]
+    pub width: f32,
+}
+
+impl Default for InterchangeStyle<'_> {
+    fn default() -> Self {
+        InterchangeStyle {
+            fill: Source::Solid(SolidSource::from_unpremultiplied_argb(255, 255, 255, 255)),
+            stroke: Source::Solid(SolidSource::from_unpremultiplied_argb(255, 0, 0, 0)),
+            width: default_interchange_width(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct IconStyle<'a> {
+    #[serde(deserialize_with = "deserialize")]
+    pub color: Source<'a>,
+}
+
+#[derive(Deserialize)]
+pub struct BuildingStyle<'a> {
+    #[serde(deserialize_with = "deserialize")]
+    pub color: Source<'a>,
+
+    #[serde(deserialize_with = "deserialize")]
+    pub outline_color: Source<'a>,
+
+    #[serde(default = "default_building_outline_width")]
+    pub outline_width: f32,
+
+    /// Draws a shadow whose length follows the building height, which makes taller
+    /// buildings stand out.
+    #[serde(default)]
+    pub shadow: Option<BuildingShadow<'a>>,
+}
+
+#[derive(Deserialize)]
+pub struct BuildingShadow<'a> {
+    #[serde(deserialize_with = "deserialize")]
+    pub color: Source<'a>,
+
+    /// Direction the shadow falls in, clockwise from east.
+    #[serde(default = "default_shadow_angle_deg")]
+    pub angle_deg: f32,
+
+    /// Shadow length per metre of building height.
+    #[serde(default = "default_shadow_px_per_m")]
+    pub px_per_m: f32,
+}
+
+#[derive(Deserialize)]
+pub struct BoundaryStyle<'a> {
+    #[serde(deserialize_with = "deserialize")]
+    pub color: Source<'a>,
+
+    #[serde(default = "default_boundary_width")]
+    pub width: f32,
+
+    #[serde(default)]
+    pub dash_array: Vec<f32>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct TubeLineStyle<'a> {
+    #[serde(deserialize_with = "deserialize")]
+    pub color: Source<'a>,
+
+    #[serde(default = "default_tube_line_width")]
+    pub width: f32,
+
+    #[serde(default)]
+    pub dash_array: Vec<f32>,
+
+    /// Lines with a higher draw order are drawn on top.
+    #[serde(default)]
+    pub draw_order: i32,
+}
+
+fn default_tube_line_width() -> f32 {
+    5.0
+}
+
+fn default_building_outline_width() -> f32 {
+    1.0
+}
+
+fn default_shadow_angle_deg() -> f32 {
+    45.0
+}
+
+fn default_shadow_px_per_m() -> f32 {
+    0.3
+}
+
+fn default_boundary_width() -> f32 {
+    3.0
+}
+
+fn default_interchange_width() -> f32 {
+    INTERCHANGE_RING_WIDTH
+}
+
+fn default_tube_line_spacing() -> f32 {
+    6.0
+}
+
+fn default_transit_modes() -> Vec<TransitMode> {
+    vec![TransitMode::Subway, TransitMode::LightRail, TransitMode::Monorail]
 }
 
 mod serialize_color {
@@ -89,6 +535,21 @@ mod serialize_color {
         }
     }
 
+    /// Parses colours as used in OSM `colour` tags, i.e. "#RRGGBB" or "#RGB".
+    pub fn parse_osm_colour(string: &str) -> Option<SolidSource> {
+        let hex = string.trim().strip_prefix('#')?;
+        let byte = |idx: usize, len: usize| {
+            let value = u8::from_str_radix(hex.get(idx * len..(idx + 1) * len)?, 16).ok()?;
+            Some(if len == 1 { value * 17 } else { value })
+        };
+        let len = match hex.len() {
+            3 => 1,
+            6 => 2,
+            _ => return None,
+        };
+        Some(SolidSource::from_unpremultiplied_argb(0xff, byte(0, len)?, byte(1, len)?, byte(2, len)?))
+    }
+
     fn parse_hex_byte<E>(visitor: &ColorVisitor, string: &str) -> Result<u8, E> where E: de::Error {
         u8::from_str_radix(string, 16).map_err(|_| {
             de::Error::invalid_value(de::Unexpected::Str(string), visitor)
@@ -101,55 +562,293 @@ mod serialize_color {
         where D: Deserializer<'de>, 'a: 'de {
         Ok(Source::Solid(deserializer.deserialize_str(ColorVisitor)?))
     }
+
+    pub fn deserialize_optional<'de, 'a, D>(
+        deserializer: D,
+    ) -> Result<Option<Source<'a>>, D::Error>
+        where D: Deserializer<'de>, 'a: 'de {
+        deserialize(deserializer).map(Some)
+    }
+}
+
+/// Spatial indices over each drawable collection of the semantic map, keyed by the
+/// position of the feature in its collection.
+struct SpatialIndices {
+    areas: GridIndex,
+    waterways: GridIndex,
+    buildings: GridIndex,
+    roads: GridIndex,
+    rails: GridIndex,
+    tube_rails: GridIndex,
+    councils: GridIndex,
+    council_boundaries: GridIndex,
+    stations: GridIndex,
+    landmarks: GridIndex,
+    place_labels: GridIndex,
+    waterway_labels: GridIndex,
 }
 
 pub struct DrawMapEtl <'a> {
     user_config: &'a UserConfig<'a>,
-    underground_logo: OwnedImage,
-    overground_logo: OwnedImage,
-    dlr_logo: OwnedImage,
-    elizabeth_line_logo: OwnedImage,
-    lgbtq_logo: OwnedImage,
-    lgbtq_men_logo: OwnedImage,
-    cocktail_logo: OwnedImage,
-    climbing_boulder_logo: OwnedImage,
-    climbing_rope_logo: OwnedImage,
-    climbing_outdoor_logo: OwnedImage,
-    gym_logo: OwnedImage,
-    hospital_logo: OwnedImage,
-    music_logo: OwnedImage,
-    temple_aetherius_society_logo: OwnedImage,
-    temple_buddhist_logo: OwnedImage,
-    temple_christian_logo: OwnedImage,
-    temple_hindu_logo: OwnedImage,
-    temple_humanist_logo: OwnedImage,
-    temple_jain_logo: OwnedImage,
-    temple_jewish_logo: OwnedImage,
-    temple_muslim_logo: OwnedImage,
-    temple_rastafarian_logo: OwnedImage,
-    temple_rosicrucian_logo: OwnedImage,
-    temple_scientologist_logo: OwnedImage,
-    temple_self_realization_fellowship_logo: OwnedImage,
-    temple_sikh_logo: OwnedImage,
-    tree_logo: OwnedImage,
+    icons: IconRegistry,
+    overlays: Overlays,
     x_shift: f32,
     y_shift: f32,
-    font: fk::Font,
+    line_styles: HashMap<TransitLineId, TubeLineStyle<'a>>,
+    /// By the path of the first font.
+    fonts: HashMap<String, FontChain>,
+    /// Images used by texture patterns, by name.
+    textures: Textures,
+    /// Index of the grid squares, written next to the map.
+    index: Vec<IndexEntry>,
     theme: &'a Theme<'a>,
+    stats: EtlStats,
 }
 
-impl DrawMapEtl<'_> {
+impl<'a> DrawMapEtl<'a> {
     fn output_path(dir: &Path) -> PathBuf {
         dir.join(OUTPUT_FILE_NAME)
     }
 
-    fn project_mercantor(&self, coords: &MapCoords) -> (f32, f32) {
+    /// Projects into pixel coordinates of the whole map, ignoring the current cell.
+    fn project_absolute(&self, coords: &MapCoords) -> (f32, f32) {
         let rel_lon = coords.lon - self.user_config.top_left_lon;
         let rel_lat = coords.lat - self.user_config.top_left_lat;
 
         let x = rel_lon * self.user_config.px_per_deg_lon;
         let y = - rel_lat * self.user_config.px_per_deg_lat;
-        (x as f32 - self.x_shift, y as f32 - self.y_shift)
+        (x as f32, y as f32)
+    }
+
+    fn project_mercantor(&self, coords: &MapCoords) -> (f32, f32) {
+        let (x, y) = self.project_absolute(coords);
+        (x - self.x_shift, y - self.y_shift)
+    }
+
+    fn path_bbox(&self, path: &[MapCoords], margin: f32) -> Option<BoundingBox> {
+        BoundingBox::from_points(path.iter().map(|coords| self.project_absolute(coords)))
+            .map(|bbox| bbox.grow(margin))
+    }
+
+    /// Simplifies and smooths `path` according to `style`. Returns `None` if the path is too
+    /// small to be visible at the render resolution.
+    fn generalize_path(&self, path: &semantic::Path, style: &GeneralizationStyle) -> Option<semantic::Path> {
+        let points: Vec<(f32, f32)> = path.iter().map(|coords| self.project_absolute(coords)).collect();
+        let bbox = BoundingBox::from_points(points.iter().copied())?;
+        if bbox.width() < style.min_size_px && bbox.height() < style.min_size_px {
+            return None;
+        }
+        if style.min_area_px > 0.0 && signed_area(&points).abs() < style.min_area_px {
+            return None;
+        }
+        let simplified: semantic::Path = simplify_indices(&points, style.tolerance_px)
+            .into_iter()
+            .map(|idx| path[idx].clone())
+            .collect();
+        Some(smooth(&simplified, style.smoothing_iterations))
+    }
+
+    fn generalize(&self, input: &mut SemanticMapElements) {
+        let generalization = &self.user_config.generalization;
+
+        input.roads = input.roads.iter()
+            .filter_map(|road| Some(Road {
+                name: road.name.clone(),
+                path: self.generalize_path(&road.path, &generalization.roads)?,
+            }))
+            .collect();
+        input.rails = input.rails.iter()
+            .filter_map(|rail| self.generalize_path(rail, &generalization.rails))
+            .collect();
+        input.tube_rails = input.tube_rails.iter()
+            .filter_map(|rail| Some(TubeRail {
+                line: rail.line,
+                path: self.generalize_path(&rail.path, &generalization.tube_rails)?,
+            }))
+            .collect();
+        input.areas = input.areas.iter()
+            .map(|area| Area {
+                area_type: area.area_type,
+                area_polygons: area.area_polygons.iter()
+                    .filter_map(|polygon| self.generalize_path(polygon, &generalization.areas))
+                    .collect(),
+            })
+            .filter(|area| !area.area_polygons.is_empty())
+            .collect();
+        input.waterways = input.waterways.iter()
+            .filter_map(|waterway| Some(Waterway {
+                name: waterway.name.clone(),
+                waterway_type: waterway.waterway_type,
+                path: self.generalize_path(&waterway.path, &generalization.waterways)?,
+            }))
+            .collect();
+        input.buildings = input.buildings.iter()
+            .map(|building| Building {
+                polygons: building.polygons.iter()
+                    .filter_map(|polygon| self.generalize_path(polygon, &generalization.buildings))
+                    .collect(),
+                height_m: building.height_m,
+                levels: building.levels,
+            })
+            .filter(|building| !building.polygons.is_empty())
+            .collect();
+        for council in &mut input.councils {
+            council.boundary = council.boundary.iter()
+                .filter_map(|ring| self.generalize_path(ring, &generalization.boundaries))
+                .collect();
+        }
+    }
+
+    /// Places place and waterway labels around the station and council labels, which are
+    /// always drawn. Place labels take precedence over waterway labels.
+    fn place_labels(&self, input: &SemanticMapElements) -> (Vec<PlacedLabel<'a>>, Vec<PathLabel<'a>>) {
+        let mut placer = LabelPlacer::new(INDEX_BUCKET_SIZE);
+        for station in &input.underground_stations {
+            placer.block(self.station_bbox(station));
+        }
+        for council in &input.councils {
+            placer.block(self.council_bbox(council));
+        }
+        let place_labels = self.place_place_labels(&mut placer, input);
+        let waterway_labels = self.place_waterway_labels(&mut placer, input);
+        (place_labels, waterway_labels)
+    }
+
+    /// Places the labels of named places, most important first, skipping any that would
+    /// overlap something placed before. Each label is tried centered on the place, then just
+    /// above and just below it.
+    fn place_place_labels(&self, placer: &mut LabelPlacer, input: &SemanticMapElements) -> Vec<PlacedLabel<'a>> {
+        let mut places: Vec<_> = input.places.iter().collect();
+        places.sort_by_key(|place| place.rank());
+
+        let mut labels = Vec::new();
+        for place in places {
+            let Some(style) = self.theme.labels.places.get(&place.place_type) else {
+                continue;
+            };
+            let (x, y) = self.project_absolute(&place.center);
+            let anchors = [
+                (x, y + style.size * 0.25),
+                (x, y - style.size * 0.75),
+                (x, y + style.size * 1.25),
+            ];
+            let candidates: Vec<BoundingBox> = anchors.iter()
+                .map(|(x, y)| self.text_bbox(*x, *y, &place.name, style))
+                .collect();
+            if let Some(idx) = placer.try_place(&candidates) {
+                let (x, y) = anchors[idx];
+                labels.push(PlacedLabel { text: place.name.clone(), style, x, y });
+            }
+        }
+        labels
+    }
+
+    /// Places names along waterways, bigger and longer waterways first. Each way is tried at
+    /// its middle and at a quarter from either end. Rivers are split into many ways, so
+    /// labels with the same name are kept `WATERWAY_LABEL_REPEAT` apart.
+    fn place_waterway_labels(&self, placer: &mut LabelPlacer, input: &SemanticMapElements) -> Vec<PathLabel<'a>> {
+        let mut waterways: Vec<(&Waterway, Vec<(f32, f32)>)> = input.waterways.iter()
+            .filter(|waterway| waterway.name.is_some())
+            .map(|waterway| {
+                (waterway, waterway.path.iter().map(|coords| self.project_absolute(coords)).collect())
+            })
+            .collect();
+        waterways.sort_by(|(a, a_points), (b, b_points)| {
+            a.waterway_type.cmp(&b.waterway_type)
+                .then_with(|| path_length(b_points).total_cmp(&path_length(a_points)))
+        });
+
+        let style = &self.theme.labels.waterway;
+        let mut placed_by_name: HashMap<&str, Vec<(f32, f32)>> = HashMap::new();
+        let mut labels = Vec::new();
+        for (waterway, points) in waterways {
+            let Some(name) = waterway.name.as_deref() else {
+                continue;
+            };
+            let ShapedText { glyphs: shaped, width, .. } = self.layout_text(name, style);
+            let offsets: Vec<f32> = shaped.iter().map(|glyph| glyph.x).collect();
+            for fraction in [0.5, 0.25, 0.75] {
+                let Some(glyphs) = place_upright(&points, fraction, &offsets, width) else {
+                    continue;
+                };
+                let middle = glyphs[glyphs.len() / 2];
+                let placed = placed_by_name.entry(name).or_default();
+                let too_close = placed.iter().any(|(x, y)| {
+                    (x - middle.x).hypot(y - middle.y) < WATERWAY_LABEL_REPEAT
+                });
+                if too_close {
+                    continue;
+                }
+                let Some(bbox) = BoundingBox::from_points(glyphs.iter().map(|glyph| (glyph.x, glyph.y))) else {
+                    continue;
+                };
+                let bbox = bbox.grow(style.size * 0.75 + style.halo_width());
+                if placer.try_place(&[bbox]).is_some() {
+                    placed.push((middle.x, middle.y));
+                    labels.push(PathLabel { shaped: shaped.clone(), glyphs, style, bbox });
+                }
+            }
+        }
+        labels
+    }
+
+    fn build_spatial_indices(
+        &self,
+        input: &SemanticMapElements,
+        bundles: &LineBundles,
+        place_labels: &[PlacedLabel<'a>],
+        waterway_labels: &[PathLabel<'a>],
+    ) -> SpatialIndices {
+        // Margins cover half the stroke width, plus the parallel offset for tube lines.
+        let max_tube_line_width = self.line_styles.values()
+            .map(|style| style.width)
+            .fold(0.0, f32::max);
+        let tube_rail_margin = (bundles.max_bundle_size() as f32 / 2.0) * self.theme.tube_line_spacing
+            + max_tube_line_width / 2.0;
+        SpatialIndices {
+            areas: GridIndex::build(INDEX_BUCKET_SIZE, &input.areas, |area| {
+                area.area_polygons.iter()
+                    .filter_map(|polygon| self.path_bbox(polygon, 1.0))
+                    .reduce(|a, b| a.union(&b))
+            }),
+            waterways: GridIndex::build(INDEX_BUCKET_SIZE, &input.waterways, |waterway| {
+                self.path_bbox(&waterway.path, waterway_width(waterway.waterway_type) / 2.0)
+            }),
+            buildings: GridIndex::build(INDEX_BUCKET_SIZE, &input.buildings, |building| {
+                let style = self.theme.buildings.as_ref()?;
+                let bbox = building.polygons.iter()
+                    .filter_map(|polygon| self.path_bbox(polygon, style.outline_width / 2.0))
+                    .reduce(|a, b| a.union(&b))?;
+                let (dx, dy) = style.shadow.as_ref()
+                    .map_or((0.0, 0.0), |shadow| Self::building_shadow_offset(building, shadow));
+                Some(bbox.union(&BoundingBox::new(bbox.min_x + dx, bbox.min_y + dy, bbox.max_x + dx, bbox.max_y + dy)))
+            }),
+            roads: GridIndex::build(INDEX_BUCKET_SIZE, &input.roads, |road| self.path_bbox(&road.path, 3.0)),
+            rails: GridIndex::build(INDEX_BUCKET_SIZE, &input.rails, |rail| self.path_bbox(rail, 2.0)),
+            tube_rails: GridIndex::build(INDEX_BUCKET_SIZE, &input.tube_rails, |rail| {
+                self.path_bbox(&rail.path, tube_rail_margin)
+            }),
+            councils: GridIndex::build(INDEX_BUCKET_SIZE, &input.councils, |council| {
+                Some(self.council_bbox(council))
+            }),
+            council_boundaries: GridIndex::build(INDEX_BUCKET_SIZE, &input.councils, |council| {
+                let style = self.theme.council_boundary.as_ref()?;
+                council.boundary.iter()
+                    .filter_map(|ring| self.path_bbox(ring, style.width / 2.0))
+                    .reduce(|a, b| a.union(&b))
+            }),
+            stations: GridIndex::build(INDEX_BUCKET_SIZE, &input.underground_stations, |station| {
+                Some(self.station_bbox(station))
+            }),
+            landmarks: GridIndex::build(INDEX_BUCKET_SIZE, &input.landmarks, |landmark| {
+                let (x, y) = self.project_absolute(&landmark.into());
+                Some(self.icons.get(&landmark.category)?.bbox(x, y))
+            }),
+            place_labels: GridIndex::build(INDEX_BUCKET_SIZE, place_labels, |label| {
+                Some(self.text_bbox(label.x, label.y, &label.text, label.style))
+            }),
+            waterway_labels: GridIndex::build(INDEX_BUCKET_SIZE, waterway_labels, |label| Some(label.bbox)),
+        }
     }
 
     fn stroke(width: f32) -> StrokeStyle {
@@ -206,237 +905,404 @@ impl DrawMapEtl<'_> {
         }
     }
 
-    fn draw_tube_rail(&self, dt: &mut DrawTarget, tube_rail: &semantic::TubeRail) {
-        fn wiggle(n: u64, n_max: u64, r: f64) -> (f64, f64) {
-            let angle = (n as f64) / (n_max as f64) * f64::consts::TAU;
-            (angle.cos() * r, angle.sin() * r)
-        }
-
-        let semantic_path = &tube_rail.path;
-        if semantic_path.len() < 2 {
+    fn draw_tube_rail(&self, dt: &mut DrawTarget, tube_rail: &semantic::TubeRail, bundles: &LineBundles) {
+        if tube_rail.path.len() < 2 {
             return;
         }
-        let mut pb = PathBuilder::new();
-
-        let offset_radius = 30.0;
-        let (dx, dy) = match tube_rail.line {
-            semantic::TubeLine::Bakerloo => wiggle(0, 14, offset_radius),
-            semantic::TubeLine::Central => wiggle(1, 14, offset_radius),
-            semantic::TubeLine::Circle => wiggle(2, 14, offset_radius),
-            semantic::TubeLine::District => wiggle(3, 14, offset_radius),
-            semantic::TubeLine::Dlr => wiggle(4, 14, offset_radius),
-            semantic::TubeLine::Elizabeth => wiggle(5, 14, offset_radius),
-            semantic::TubeLine::HammersmithAndCity => wiggle(6, 14, offset_radius),
-            semantic::TubeLine::Jubilee => wiggle(7, 14, offset_radius),
-            semantic::TubeLine::Metropolitan => wiggle(8, 14, offset_radius),
-            semantic::TubeLine::Northern => wiggle(9, 14, offset_radius),
-            semantic::TubeLine::Overground => wiggle(10, 14, offset_radius),
-            semantic::TubeLine::Piccadilly => wiggle(11, 14, offset_radius),
-            semantic::TubeLine::Victoria => wiggle(12, 14, offset_radius),
-            semantic::TubeLine::WaterlooAndCity => wiggle(13, 14, offset_radius),
-        };
-
-        let (dx, dy): (f32, f32) = (dx as f32, dy as f32);
-
-        let (x0, y0) = self.project_mercantor(
-            &semantic_path[0]
-            // &MapCoords {
-                // lat: semantic_path[0].lat + dy,
-                // lon: semantic_path[1].lon + dx,
-            // }
-        );
-        pb.move_to(x0 + dx, y0 + dy);
+        let points: Vec<(f32, f32)> = tube_rail.path.iter()
+            .map(|coords| self.project_absolute(coords))
+            .collect();
+        let offset_points = bundles.offset_path(tube_rail, &points, self.theme.tube_line_spacing);
 
-        for coords in &semantic_path[1..] {
-            let (x, y) = self.project_mercantor(
-                coords,
-                // &MapCoords {
-                    // lat: coords.lat + dy,
-                    // lon: coords.lon + dx,
-                // }
-            );
-            pb.line_to(x + dx, y + dy);
+        let mut pb = PathBuilder::new();
+        let (x0, y0) = offset_points[0];
+        pb.move_to(x0 - self.x_shift, y0 - self.y_shift);
+        for (x, y) in &offset_points[1..] {
+            pb.line_to(x - self.x_shift, y - self.y_shift);
         }
         let raquote_path = pb.finish();
 
         let draw_options = DrawOptions::new();
 
+        let style = &self.line_styles[&tube_rail.line];
         dt.stroke(
             &raquote_path,
-            &Source::Solid(
-                match tube_rail.line {
-                    semantic::TubeLine::Bakerloo => SolidSource::from_unpremultiplied_argb(0xff, 0x89, 0x4e, 0x24),
-                    semantic::TubeLine::Central => SolidSource::from_unpremultiplied_argb(0xff, 0xDC, 0x24, 0x1f),
-                    semantic::TubeLine::Circle => SolidSource::from_unpremultiplied_argb(0xff, 0xFF, 0xCE, 0x00),
-                    semantic::TubeLine::District => SolidSource::from_unpremultiplied_argb(0xff, 0x00, 0x72, 0x29),
-                    semantic::TubeLine::Dlr => SolidSource::from_unpremultiplied_argb(0xff, 0x00, 0xaf, 0xad),
-                    semantic::TubeLine::Elizabeth => SolidSource::from_unpremultiplied_argb(0xff, 0x69, 0x50, 0xa1),
-                    semantic::TubeLine::HammersmithAndCity => SolidSource::from_unpremultiplied_argb(0xff, 0xd7, 0x99, 0xaf),
-                    semantic::TubeLine::Jubilee => SolidSource::from_unpremultiplied_argb(0xff, 0x6a, 0x72, 0x78),
-                    semantic::TubeLine::Metropolitan => SolidSource::from_unpremultiplied_argb(0xff, 0x75, 0x10, 0x56),
-                    semantic::TubeLine::Northern => SolidSource::from_unpremultiplied_argb(0xff, 0x00, 0x00, 0x00),
-                    semantic::TubeLine::Overground => SolidSource::from_unpremultiplied_argb(0xff, 0xe8, 0x6a, 0x10),
-                    semantic::TubeLine::Piccadilly => SolidSource::from_unpremultiplied_argb(0xff, 0x00, 0x19, 0xa8),
-                    semantic::TubeLine::Victoria => SolidSource::from_unpremultiplied_argb(0xff, 0x00, 0xa0, 0xe2),
-                    semantic::TubeLine::WaterlooAndCity => SolidSource::from_unpremultiplied_argb(0xff, 0x76, 0xd0, 0xbd),
-                }
-            ),
-            &Self::stroke(5.0),
+            &style.color,
+            &StrokeStyle {
+                dash_array: style.dash_array.clone(),
+                ..Self::stroke(style.width)
+            },
             &draw_options,
         );
     }
 
-    fn draw_text(
+    /// Style for `line`, or `None` if the line shouldn't be drawn.
+    fn resolve_line_style(&self, line: &TransitLine) -> Option<TubeLineStyle<'a>> {
+        let candidates = [&line.reference, &Some(line.name.clone()), &line.network];
+        for candidate in candidates.into_iter().flatten() {
+            let themed = self.theme.tube_lines.iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(candidate));
+            if let Some((_, style)) = themed {
+                return Some(style.clone());
+            }
+        }
+
+        if !self.theme.transit_modes.contains(&line.mode) {
+            return None;
+        }
+        let color = line.colour.as_deref()
+            .and_then(parse_osm_colour)
+            .map_or_else(|| self.theme.rail_color.clone(), Source::Solid);
+        Some(TubeLineStyle {
+            color,
+            width: default_tube_line_width(),
+            dash_array: Vec::new(),
+            draw_order: 0,
+        })
+    }
+
+    fn apply_case(text: &str, style: &TextStyle) -> String {
+        match style.case {
+            TextCase::Original => text.to_string(),
+            TextCase::Upper => text.to_uppercase(),
+            TextCase::Lower => text.to_lowercase(),
+        }
+    }
+
+    /// Shapes `text` as a single line, with the baseline starting at the origin.
+    fn layout_text(&self, text: &str, style: &TextStyle) -> ShapedText {
+        let text = Self::apply_case(text, style);
+        self.fonts[style.font_path()].shape(&text, style.size, style.letter_spacing)
+    }
+
+    /// Shapes `text`, wrapped to the style's maximum width. Returns each line with the
+    /// offset of its baseline; the lines are centered vertically on baseline 0.
+    fn layout_lines(&self, text: &str, style: &TextStyle) -> Vec<(f32, ShapedText)> {
+        let chain = &self.fonts[style.font_path()];
+        let text = Self::apply_case(text, style);
+        let shape = |line: &str| chain.shape(line, style.size, style.letter_spacing);
+
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let lines: Vec<ShapedText> = match style.max_width {
+            Some(max_width) if words.len() > 1 => {
+                let widths: Vec<f32> = words.iter().map(|word| shape(word).width).collect();
+                let gap = shape(" ").width + 2.0 * style.size * style.letter_spacing;
+                let starts = wrap_words(&widths, gap, max_width, style.max_lines.unwrap_or(usize::MAX));
+                let ends = starts.iter().skip(1).copied().chain(std::iter::once(words.len()));
+                starts.iter().zip(ends)
+                    .map(|(start, end)| shape(&words[*start..end].join(" ")))
+                    .collect()
+            },
+            _ => vec![shape(&text)],
+        };
+
+        let line_gap = style.size * style.line_height;
+        let first_offset = -line_gap * (lines.len() - 1) as f32 / 2.0;
+        lines.into_iter()
+            .enumerate()
+            .map(|(idx, line)| (first_offset + line_gap * idx as f32, line))
+            .collect()
+    }
+
+    /// Bounding box of text drawn by `draw_text` at (x, y).
+    fn text_bbox(&self, x: f32, y: f32, text: &str, style: &TextStyle) -> BoundingBox {
+        self.layout_lines(text, style).iter()
+            .map(|(offset, line)| BoundingBox::new(
+                x - line.width * 0.5,
+                y + offset - line.ascent,
+                x + line.width * 0.5,
+                y + offset + line.descent,
+            ))
+            .reduce(|a, b| a.union(&b))
+            .unwrap_or_else(|| BoundingBox::new(x, y, x, y))
+            .grow(style.halo_width())
+    }
+
+    /// Where to draw `text` so that its bounding box is at (x, y), with `align` giving the
+    /// point of the box that is put there as fractions of its width and height. Also returns
+    /// the bounding box.
+    fn align_text(&self, text: &str, style: &TextStyle, (x, y): (f32, f32), align: (f32, f32)) -> ((f32, f32), BoundingBox) {
+        let bbox = self.text_bbox(0.0, 0.0, text, style);
+        let x_center = x + (0.5 - align.0) * bbox.width();
+        let baseline = y - align.1 * bbox.height() - bbox.min_y;
+        let aligned = BoundingBox::new(
+            x_center + bbox.min_x,
+            baseline + bbox.min_y,
+            x_center + bbox.max_x,
+            baseline + bbox.max_y,
+        );
+        ((x_center, baseline), aligned)
+    }
+
+    /// Draws `text` centered on (x, y), which is the baseline of single line text.
+    /// `default_color` is used if the style has no colour of its own.
+    fn draw_text(&self, dt: &mut DrawTarget, x: f32, y: f32, text: &str, style: &TextStyle, default_color: &Source) {
+        let mut glyphs = Vec::new();
+        let mut placements = Vec::new();
+        for (offset, line) in self.layout_lines(text, style) {
+            let x_start = x - line.width * 0.5;
+            placements.extend(line.glyphs.iter().map(|glyph| GlyphPlacement {
+                x: x_start + glyph.x,
+                y: y + offset + glyph.y,
+                angle: 0.0,
+            }));
+            glyphs.extend(line.glyphs);
+        }
+        self.draw_glyphs(dt, style, &glyphs, &placements, 0.0, default_color);
+    }
+
+    fn draw_path_label(&self, dt: &mut DrawTarget, label: &PathLabel, default_color: &Source) {
+        let placements: Vec<GlyphPlacement> = label.glyphs.iter()
+            .map(|glyph| GlyphPlacement {
+                x: glyph.x - self.x_shift,
+                y: glyph.y - self.y_shift,
+                angle: glyph.angle,
+            })
+            .collect();
+        // Moves the baseline down, so that lower case letters are centered on the line.
+        let baseline_shift = label.style.size * 0.3;
+        self.draw_glyphs(dt, label.style, &label.shaped, &placements, baseline_shift, default_color);
+    }
+
+    /// Draws glyph outlines as one path, with the halo stroked behind it.
+    /// `DrawTarget::draw_glyphs` can neither rotate single glyphs nor outline them.
+    fn draw_glyphs(
         &self,
         dt: &mut DrawTarget,
-        x: f32,
-        y: f32,
-        point_size: f32,
-        text: &str,
-        source: &Source,
-        letter_spacing: f32,
+        style: &TextStyle,
+        glyphs: &[ShapedGlyph],
+        placements: &[GlyphPlacement],
+        baseline_shift: f32,
+        default_color: &Source,
     ) {
-        let options = DrawOptions::new();
-        let mut start = fk::vec2f(x, y);
-        let mut ids = Vec::new();
-        let mut positions = Vec::new();
-        for c in text.chars() {
-            let id = self.font.glyph_for_char(c).unwrap();
-            ids.push(id);
-            positions.push(Point::new(start.x(), start.y()));
-            start += self.font.advance(id).unwrap() * point_size / 24. / 96. * 2.0 + fk::vec2f(point_size * letter_spacing, 0.0);
+        let Some(first) = placements.first() else {
+            return;
+        };
+        let chain = &self.fonts[style.font_path()];
+        let mut sink = GlyphPathSink {
+            pb: PathBuilder::new(),
+            scale: 1.0,
+            baseline_shift,
+            placement: *first,
+        };
+        for (glyph, placement) in glyphs.iter().zip(placements) {
+            sink.scale = style.size / chain.units_per_em(glyph.font);
+            sink.placement = *placement;
+            // Glyphs without an outline, like spaces, have nothing to draw.
+            let _ = chain.font(glyph.font).outline(glyph.id, fk::HintingOptions::None, &mut sink);
         }
-        let total_width: f32 = positions[positions.len() - 1].x - x + point_size / 2.0;
-        for position in &mut positions {
-            position.x -= total_width * 0.5;
+        let path = sink.pb.finish();
+
+        let draw_options = DrawOptions::new();
+        if let Some(halo) = &style.halo {
+            dt.stroke(&path, &halo.color, &Self::stroke(2.0 * halo.width), &draw_options);
         }
-        dt.draw_glyphs(&self.font, point_size, &ids, &positions, source, &options);
+        dt.fill(&path, style.color.as_ref().unwrap_or(default_color), &draw_options);
     }
 
-    fn draw_undergound_station(&self, dt: &mut DrawTarget, station: &TransportStation) {
-        let (x_center, y_center) = self.project_mercantor(&station.into());
-        let width = 94.0;
-        let height = 78.0;
-
-        let logo = match station.station_type {
-            semantic::TransportStationType::Underground => &self.underground_logo,
-            semantic::TransportStationType::Overground => &self.overground_logo,
-            semantic::TransportStationType::Dlr => &self.dlr_logo,
-            semantic::TransportStationType::ElizabethLine => &self.elizabeth_line_logo,
-        };
+    /// Bounding box of the station symbol, i.e. the logo or the interchange rings.
+    fn station_symbol_bbox(&self, station: &TransportStation) -> BoundingBox {
+        if station.is_interchange() {
+            let radius = INTERCHANGE_RING_RADIUS + self.theme.interchange.width;
+            BoundingBox::from_points(station.locations.iter().map(|coords| self.project_absolute(coords)))
+                .unwrap_or_else(|| {
+                    let (x, y) = self.project_absolute(&station.into());
+                    BoundingBox::new(x, y, x, y)
+                })
+                .grow(radius)
+        } else if let Some(icon) = self.station_icon(station) {
+            let (x_center, y_center) = self.project_absolute(&station.into());
+            icon.bbox(x_center, y_center)
+        } else {
+            let (x_center, y_center) = self.project_absolute(&station.into());
+            BoundingBox::new(x_center, y_center, x_center, y_center)
+                .grow(STATION_DOT_RADIUS + STATION_DOT_OUTLINE)
+        }
+    }
+
+    fn station_icon_name(&self, station: &TransportStation) -> Option<&'static str> {
+        match station.station_type {
+            semantic::TransportStationType::Underground => Some("underground"),
+            semantic::TransportStationType::Overground => Some("overground"),
+            semantic::TransportStationType::Dlr => Some("dlr"),
+            semantic::TransportStationType::ElizabethLine => Some("elizabeth_line"),
+            semantic::TransportStationType::NationalRail
+            | semantic::TransportStationType::Tram
+            | semantic::TransportStationType::CableCar => None,
+        }
+    }
 
-        let img = Image {
-            width: self.underground_logo.width,
-            height: self.underground_logo.height,
-            data: &logo.data,
+    fn station_icon(&self, station: &TransportStation) -> Option<&Icon> {
+        self.icons.get(self.station_icon_name(station)?)
+    }
+
+    /// Absolute position of the station name, centered below the symbol.
+    /// Wrapped names are moved down, so that their first line stays below the symbol.
+    fn station_label_anchor(&self, station: &TransportStation) -> (f32, f32) {
+        let (x_center, _) = self.project_absolute(&station.into());
+        let first_line_offset = self.layout_lines(&station.name, &self.theme.labels.station)
+            .first()
+            .map_or(0.0, |(offset, _)| *offset);
+        (x_center, self.station_symbol_bbox(station).max_y + STATION_LABEL_OFFSET - first_line_offset)
+    }
+
+    fn station_bbox(&self, station: &TransportStation) -> BoundingBox {
+        let (label_x, label_y) = self.station_label_anchor(station);
+        let label_bbox = self.text_bbox(label_x, label_y, &station.name, &self.theme.labels.station);
+        self.station_symbol_bbox(station).union(&label_bbox)
+    }
+
+    fn council_bbox(&self, council: &Council) -> BoundingBox {
+        let (x_center, y_center) = self.project_absolute(&council.center);
+        self.text_bbox(x_center, y_center, &council.name, &self.theme.labels.council)
+    }
+
+    /// Draws the classic interchange symbol: a ring for each location of the station,
+    /// joined by a bar where the station spans several locations.
+    fn draw_interchange(&self, dt: &mut DrawTarget, station: &TransportStation) {
+        let style = &self.theme.interchange;
+        let locations: Vec<(f32, f32)> = station.locations.iter()
+            .map(|coords| self.project_mercantor(coords))
+            .collect();
+        let draw_options = DrawOptions::new();
+
+        let connector = if locations.len() > 1 {
+            let mut pb = PathBuilder::new();
+            pb.move_to(locations[0].0, locations[0].1);
+            for (x, y) in &locations[1..] {
+                pb.line_to(*x, *y);
+            }
+            let connector = pb.finish();
+            dt.stroke(
+                &connector,
+                &style.stroke,
+                &Self::stroke(INTERCHANGE_CONNECTOR_WIDTH + 2.0 * style.width),
+                &draw_options,
+            );
+            Some(connector)
+        } else {
+            None
         };
 
-        let mut draw_options = DrawOptions::new();
-        draw_options.blend_mode = BlendMode::SrcOver;
+        for (x, y) in &locations {
+            self.draw_interchange_ring(dt, *x, *y);
+        }
 
-        dt.draw_image_with_size_at(
-            width,
-            height,
-            x_center - width / 2.0,
-            y_center - height / 2.0,
-            &img,
-            &draw_options,
-        );
+        // Drawing the inside of the connector last joins the rings into one symbol.
+        if let Some(connector) = connector {
+            dt.stroke(&connector, &style.fill, &Self::stroke(INTERCHANGE_CONNECTOR_WIDTH), &draw_options);
+        }
+    }
+
+    fn draw_interchange_ring(&self, dt: &mut DrawTarget, x: f32, y: f32) {
+        let style = &self.theme.interchange;
+        let draw_options = DrawOptions::new();
+        let mut pb = PathBuilder::new();
+        pb.arc(x, y, INTERCHANGE_RING_RADIUS, 0.0, std::f32::consts::TAU);
+        pb.close();
+        let ring = pb.finish();
+        dt.fill(&ring, &style.fill, &draw_options);
+        dt.stroke(&ring, &style.stroke, &Self::stroke(style.width), &draw_options);
+    }
 
+    fn draw_undergound_station(&self, dt: &mut DrawTarget, station: &TransportStation) {
+        let (label_x, label_y) = self.station_label_anchor(station);
+        if station.is_interchange() {
+            self.draw_interchange(dt, station);
+        } else {
+            self.draw_station_logo(dt, station);
+        }
         self.draw_text(
             dt,
-            x_center,
-            y_center + height / 2.0 + 35.0,
-            40.0,
+            label_x - self.x_shift,
+            label_y - self.y_shift,
             &station.name,
+            &self.theme.labels.station,
             &self.theme.text_color,
-            0.0,
         );
-        // dt.draw_image_at(x_center, y_center, &img, &DrawOptions::new());
     }
 
-    pub fn new<'a>(user_config: &'a UserConfig<'a>) -> DrawMapEtl<'a> {
-        let font = font_kit::loader::Loader::from_file(
-            &mut std::fs::File::open("resources/fonts/Domine-Bold.ttf").unwrap(), 0
-        ).unwrap();
+    /// Plain marker for station types we have no logo for.
+    fn draw_station_dot(&self, dt: &mut DrawTarget, x_center: f32, y_center: f32) {
+        let draw_options = DrawOptions::new();
+        let mut pb = PathBuilder::new();
+        pb.arc(x_center, y_center, STATION_DOT_RADIUS, 0.0, std::f32::consts::TAU);
+        pb.close();
+        let dot = pb.finish();
+        dt.fill(&dot, &self.theme.text_color, &draw_options);
+        dt.stroke(
+            &dot,
+            &Source::Solid(SolidSource::from_unpremultiplied_argb(255, 255, 255, 255)),
+            &Self::stroke(STATION_DOT_OUTLINE),
+            &draw_options,
+        );
+    }
 
-        DrawMapEtl {
+    fn draw_station_logo(&self, dt: &mut DrawTarget, station: &TransportStation) {
+        let (x_center, y_center) = self.project_mercantor(&station.into());
+        match self.station_icon(station) {
+            Some(icon) => icon.draw(dt, x_center, y_center),
+            None => self.draw_station_dot(dt, x_center, y_center),
+        }
+    }
+
+    pub fn new(user_config: &'a UserConfig<'a>, icons: IconRegistry, overlays: Overlays, textures: Textures) -> Result<DrawMapEtl<'a>> {
+        let fallback_fonts = user_config.theme.fallback_fonts.iter()
+            .map(|path| LoadedFont::load(path).map(Rc::new))
+            .collect::<Result<Vec<_>>>()?;
+        let labels = &user_config.theme.labels;
+        let styles = [&labels.station, &labels.council, &labels.waterway].into_iter()
+            .chain(labels.places.values())
+            .chain(user_config.theme.furniture.text_styles())
+            .chain(user_config.theme.grid.iter().flat_map(|grid| [&grid.label, &grid.index.style]))
+            .chain(user_config.theme.graticule.iter().map(|graticule| &graticule.label))
+            .chain(user_config.overlays.iter().filter_map(|overlay| overlay.label.as_ref()).map(|label| &label.style));
+        let mut fonts = HashMap::new();
+        for style in styles {
+            let path = style.font_path();
+            if fonts.contains_key(path) {
+                continue;
+            }
+            let mut chain = vec![Rc::new(LoadedFont::load(path)?)];
+            chain.extend(fallback_fonts.iter().cloned());
+            fonts.insert(path.to_string(), FontChain::new(chain));
+        }
+        let area_styles = &user_config.theme.areas;
+        if !area_styles.contains_key(&AreaType::Water) {
+            return Err("The theme has no style for water in areas, which also colours waterways.".into());
+        }
+        let unstyled: Vec<AreaType> = AreaType::ALL.into_iter()
+            .filter(|area_type| !area_styles.contains_key(area_type))
+            .collect();
+        if !unstyled.is_empty() {
+            warn!("Areas of types {:?} have no style in the theme and are not drawn.", unstyled);
+        }
+
+        Ok(DrawMapEtl {
             user_config,
-            underground_logo: Self::load_image("ug_2").unwrap(),
-            overground_logo: Self::load_image("overground").unwrap(),
-            elizabeth_line_logo: Self::load_image("elizabeth").unwrap(),
-            dlr_logo: Self::load_image("dlr").unwrap(),
-            gym_logo: Self::load_image("gym1").unwrap(),
-            lgbtq_logo: Self::load_image("lgbtq").unwrap(),
-            lgbtq_men_logo: Self::load_image("gay_men").unwrap(),
-            cocktail_logo: Self::load_image("cocktail").unwrap(),
-            climbing_boulder_logo: Self::load_image("climbing_boulder3").unwrap(),
-            climbing_rope_logo: Self::load_image("climbing_rope1").unwrap(),
-            climbing_outdoor_logo: Self::load_image("climbing_outdoor").unwrap(),
-            hospital_logo: Self::load_image("hospital").unwrap(),
-            music_logo: Self::load_image("music_venue").unwrap(),
-            font,
+            icons,
+            overlays,
+            fonts,
+            textures,
+            index: Vec::new(),
             theme: &user_config.theme,
-            temple_aetherius_society_logo: Self::load_image("aetherius_society").unwrap(),
-            temple_buddhist_logo: Self::load_image("buddhist-stupa").unwrap(),
-            temple_christian_logo: Self::load_image("crucifix1").unwrap(),
-            temple_hindu_logo: Self::load_image("hindu-om").unwrap(),
-            temple_humanist_logo: Self::load_image("humanism").unwrap(),
-            temple_jain_logo: Self::load_image("janism").unwrap(),
-            temple_jewish_logo: Self::load_image("judaism").unwrap(),
-            temple_muslim_logo: Self::load_image("islam").unwrap(),
-            temple_rastafarian_logo: Self::load_image("rastafarianism").unwrap(),
-            temple_rosicrucian_logo: Self::load_image("rosicrucianism").unwrap(),
-            temple_scientologist_logo: Self::load_image("scientology").unwrap(),
-            temple_self_realization_fellowship_logo: Self::load_image("self-realization-fellowship").unwrap(),
-            temple_sikh_logo: Self::load_image("sikh").unwrap(),
-            tree_logo: Self::load_image("tree").unwrap(),
+            stats: EtlStats::default(),
             x_shift: 0.0,
             y_shift: 0.0,
-        }
-        //DrawMapEtl {
-        //    user_config,
-        //    underground_logo: Self::load_image("lgbtq").unwrap(),
-        //    overground_logo: Self::load_image("lgbtq").unwrap(),
-        //    elizabeth_line_logo: Self::load_image("lgbtq").unwrap(),
-        //    dlr_logo: Self::load_image("lgbtq").unwrap(),
-        //    lgbtq_logo: Self::load_image("lgbtq").unwrap(),
-        //    cocktail_logo: Self::load_image("lgbtq").unwrap(),
-        //    font,
-        //    theme: &user_config.theme,
-        //    temple_aetherius_society_logo: Self::load_image("lgbtq").unwrap(),
-        //    temple_buddhist_logo: Self::load_image("lgbtq").unwrap(),
-        //    temple_christian_logo: Self::load_image("lgbtq").unwrap(),
-        //    temple_hindu_logo: Self::load_image("lgbtq").unwrap(),
-        //    temple_humanist_logo: Self::load_image("lgbtq").unwrap(),
-        //    temple_jain_logo: Self::load_image("lgbtq").unwrap(),
-        //    temple_jewish_logo: Self::load_image("lgbtq").unwrap(),
-        //    temple_muslim_logo: Self::load_image("lgbtq").unwrap(),
-        //    temple_rastafarian_logo: Self::load_image("lgbtq").unwrap(),
-        //    temple_rosicrucian_logo: Self::load_image("lgbtq").unwrap(),
-        //    temple_scientologist_logo: Self::load_image("lgbtq").unwrap(),
-        //    temple_self_realization_fellowship_logo: Self::load_image("lgbtq").unwrap(),
-        //    temple_sikh_logo: Self::load_image("lgbtq").unwrap(),
-        //}
+            line_styles: HashMap::new(),
+        })
     }
 
     fn extract_semantic_map_elements(&self, dir: &Path) -> Result<SemanticMapElements> {
-        let input_file_path = dir.join(semantic_map::OUTPUT_FILE_NAME);
-        let mut input_file = File::open(input_file_path)?;
-
-        let mut buf_vec: Vec<u8> = Vec::new();
-        input_file.read_to_end(&mut buf_vec).expect("Could not read note cache.");
-
-        let elements: SemanticMapElements = unsafe {
-            rkyv::from_bytes_unchecked(&buf_vec).expect("Could not deserialize node cache.")
-        };
-        Ok(elements)
+        artifact::read(&dir.join(semantic_map::OUTPUT_FILE_NAME))
     }
 
+    /// Loads `resources/images/{name}.png`. Palette images and images with fewer or more
+    /// bits per channel are converted to 8 bit colour first.
     fn load_image(name: &str) -> Result<OwnedImage> {
-        let decoder = png::Decoder::new(
-            File::open(format!("resources/images/{}.png", name))?
-        );
+        let path = format!("resources/images/{}.png", name);
+        let mut decoder = png::Decoder::new(File::open(&path)?);
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
 
         let mut reader = decoder.read_info()?;
 
@@ -445,46 +1311,26 @@ impl DrawMapEtl<'_> {
         let info = reader.next_frame(&mut buf)?;
 
         if info.bit_depth != BitDepth::Eight {
-            return Err("Unsupported bit depth".into())
-        }
-
-        let buf_u32 = match info.color_type {
-            ColorType::Rgba => {
-                let mut buf_u32 = vec![0_u32; reader.output_buffer_size() / 4];
-                for (idx_u32, ptr_u32) in buf_u32.iter_mut().enumerate() {
-                    let idx_u8 = 4 * idx_u32;
-                    let b = buf[idx_u8] as u32;
-                    let g = buf[idx_u8 + 1] as u32;
-                    let r = buf[idx_u8 + 2] as u32;
-                    let a = buf[idx_u8 + 3] as u32;
-
-                    *ptr_u32 =
-                        r
-                        + (g << 8)
-                        + (b << 16)
-                        + (a << 24)
-                    ;
-                }
-                buf_u32
-            },
-            ColorType::GrayscaleAlpha => {
-                let mut buf_u32 = vec![0_u32; reader.output_buffer_size() / 2];
-                for (idx_u32, ptr_u32) in buf_u32.iter_mut().enumerate() {
-                    let idx_u8 = 2 * idx_u32;
-                    let g = buf[idx_u8] as u32;
-                    let a = buf[idx_u8 + 1] as u32;
-
-                    *ptr_u32 =
-                        g
-                        + (g << 8)
-                        + (g << 16)
-                        + (a << 24)
-                    ;
-                }
-                buf_u32
+            return Err(format!("unsupported PNG bit depth {:?} in {}", info.bit_depth, path).into())
+        }
+
+        let channels = match info.color_type {
+            ColorType::Rgba | ColorType::Rgb | ColorType::GrayscaleAlpha | ColorType::Grayscale => {
+                info.color_type.samples()
             },
-            _ => todo!(),
+            other => return Err(format!("unsupported PNG colour type {:?} in {}", other, path).into()),
         };
+        let buf_u32 = buf[..info.buffer_size()].chunks_exact(channels)
+            .map(|px| {
+                let (r, g, b, a) = match info.color_type {
+                    ColorType::Rgba => (px[0], px[1], px[2], px[3]),
+                    ColorType::Rgb => (px[0], px[1], px[2], 0xff),
+                    ColorType::GrayscaleAlpha => (px[0], px[0], px[0], px[1]),
+                    _ => (px[0], px[0], px[0], 0xff),
+                };
+                (a as u32) << 24 | (r as u32) << 16 | (g as u32) << 8 | b as u32
+            })
+            .collect();
 
         Ok(OwnedImage {
             width: info.width.try_into()?,
@@ -493,12 +1339,14 @@ impl DrawMapEtl<'_> {
         })
     }
 
+    /// Fills all polygons of the area as one path with the even-odd rule, so that inner
+    /// rings of multipolygons, like islands in a river, are left out.
     fn draw_area(&self, dt: &mut DrawTarget, area: &Area) {
+        let mut pb = PathBuilder::new();
         for polygon in &area.area_polygons {
             if polygon.len() < 2 {
-                return;
+                continue;
             }
-            let mut pb = PathBuilder::new();
             let (x0, y0) = self.project_mercantor(&polygon[0]);
             pb.move_to(x0, y0);
 
@@ -506,75 +1354,210 @@ impl DrawMapEtl<'_> {
                 let (x, y) = self.project_mercantor(coords);
                 pb.line_to(x, y);
             }
-            let raquote_path = pb.finish();
+            pb.close();
+        }
+        let mut raquote_path = pb.finish();
+        raquote_path.winding = Winding::EvenOdd;
 
-            let draw_options = DrawOptions::new();
+        let draw_options = DrawOptions::new();
+        let style = &self.theme.areas[&area.area_type];
 
-            dt.fill(
-                &raquote_path,
-                match area.area_type {
-                    semantic::AreaType::Park => &self.theme.park_color,
-                    semantic::AreaType::Wood => &self.theme.wood_color,
-                    semantic::AreaType::Water => &self.theme.water_color,
-                },
-                &draw_options,
-            );
+        dt.fill(
+            &raquote_path,
+            &style.color,
+            &draw_options,
+        );
+        if let Some(pattern) = &style.pattern {
+            self.draw_pattern(dt, &raquote_path, area, pattern);
         }
     }
 
-    fn draw_landmark(&self, dt: &mut DrawTarget, landmark: &Landmark) {
-        let (x_center, y_center) = self.project_mercantor(&landmark.into());
-        let width = 58.0;
-        let height = 48.0;
-
-        if let semantic::LandmarkType::TubeEmergencyExit = landmark.landmark_type {
-            return
-        }
-
-        let logo = match landmark.landmark_type {
-            semantic::LandmarkType::LgbtqMen => &self.lgbtq_men_logo,
-            semantic::LandmarkType::Lgbtq => &self.lgbtq_logo,
-            semantic::LandmarkType::CocktailBar => &self.cocktail_logo,
-            semantic::LandmarkType::ClimbingBoulder => &self.climbing_boulder_logo,
-            semantic::LandmarkType::ClimbingRope => &self.climbing_rope_logo,
-            semantic::LandmarkType::ClimbingOutdoor => &self.climbing_outdoor_logo,
-            semantic::LandmarkType::Gym => &self.gym_logo,
-            semantic::LandmarkType::Hospital => &self.hospital_logo,
-            semantic::LandmarkType::MusicVenue => &self.music_logo,
-            semantic::LandmarkType::TempleAetheriusSociety => &self.temple_aetherius_society_logo,
-            semantic::LandmarkType::TempleBuddhist => &self.temple_buddhist_logo,
-            semantic::LandmarkType::TempleChristian => &self.temple_christian_logo,
-            semantic::LandmarkType::TempleHindu => &self.temple_hindu_logo,
-            semantic::LandmarkType::TempleHumanist => &self.temple_humanist_logo,
-            semantic::LandmarkType::TempleJain => &self.temple_jain_logo,
-            semantic::LandmarkType::TempleJewish => &self.temple_jewish_logo,
-            semantic::LandmarkType::TempleMuslim => &self.temple_muslim_logo,
-            semantic::LandmarkType::TempleRastafarian => &self.temple_rastafarian_logo,
-            semantic::LandmarkType::TempleRosicucian => &self.temple_rosicrucian_logo,
-            semantic::LandmarkType::TempleScientologist => &self.temple_scientologist_logo,
-            semantic::LandmarkType::TempleSelfRealizationFellowship => &self.temple_self_realization_fellowship_logo,
-            semantic::LandmarkType::TempleSikh => &self.temple_sikh_logo,
-            semantic::LandmarkType::Tree => &self.tree_logo,
-            semantic::LandmarkType::TubeEmergencyExit => todo!(),
+    fn draw_pattern(&self, dt: &mut DrawTarget, area_path: &raqote::Path, area: &Area, pattern: &Pattern) {
+        let draw_options = DrawOptions::new();
+        let shift = (self.x_shift, self.y_shift);
+        if let Pattern::Texture { image } = pattern {
+            // Textures::load has loaded every texture of the theme.
+            let Some(texture) = self.textures.get(image) else {
+                return;
+            };
+            let image = Image {
+                width: texture.width,
+                height: texture.height,
+                data: &texture.data,
+            };
+            // Maps cell coordinates to absolute ones, so the tiling lines up across cells.
+            let source = Source::Image(image, ExtendMode::Repeat, FilterMode::Nearest, Transform::translation(self.x_shift, self.y_shift));
+            dt.fill(area_path, &source, &draw_options);
+            return;
+        }
+
+        // Only lay out the pattern where the area and the cell overlap.
+        let cell_bbox = BoundingBox::new(
+            self.x_shift,
+            self.y_shift,
+            self.x_shift + dt.width() as f32,
+            self.y_shift + dt.height() as f32,
+        );
+        let Some(area_bbox) = area.area_polygons.iter()
+            .filter_map(|polygon| self.path_bbox(polygon, 0.0))
+            .reduce(|a, b| a.union(&b))
+        else {
+            return;
         };
+        if !area_bbox.intersects(&cell_bbox) {
+            return;
+        }
+        let bbox = BoundingBox::new(
+            area_bbox.min_x.max(cell_bbox.min_x),
+            area_bbox.min_y.max(cell_bbox.min_y),
+            area_bbox.max_x.min(cell_bbox.max_x),
+            area_bbox.max_y.min(cell_bbox.max_y),
+        );
+
+        dt.push_clip(area_path);
+        match pattern {
+            Pattern::Hatch { color, spacing, angle_deg, width } => {
+                let lines = hatch_path(&bbox, shift, *spacing, *angle_deg);
+                dt.stroke(&lines, color, &Self::stroke(*width), &draw_options);
+            },
+            Pattern::Dots { color, spacing, radius } => {
+                let mut pb = PathBuilder::new();
+                for point in grid_points(&bbox.grow(*radius), shift, *spacing) {
+                    push_dot(&mut pb, point, *radius);
+                }
+                dt.fill(&pb.finish(), color, &draw_options);
+            },
+            Pattern::Symbols { color, symbol, spacing, size, width } => {
+                let mut pb = PathBuilder::new();
+                for point in grid_points(&bbox.grow(*size), shift, *spacing) {
+                    push_symbol(&mut pb, *symbol, point, *size);
+                }
+                dt.stroke(&pb.finish(), color, &Self::stroke(*width), &draw_options);
+            },
+            Pattern::Texture { .. } => {},
+        }
+        dt.pop_clip();
+    }
 
-        let img = Image {
-            width: self.underground_logo.width,
-            height: self.underground_logo.height,
-            data: &logo.data,
+    fn building_shadow_offset(building: &Building, shadow: &BuildingShadow) -> (f32, f32) {
+        let height_m = building.height_m
+            .or_else(|| building.levels.map(|levels| levels as f32 * BUILDING_LEVEL_HEIGHT_M))
+            .unwrap_or(DEFAULT_BUILDING_HEIGHT_M);
+        let (sin, cos) = shadow.angle_deg.to_radians().sin_cos();
+        let length = height_m * shadow.px_per_m;
+        (cos * length, sin * length)
+    }
+
+    /// The shadow is the area swept by the footprint when moving it by the shadow offset:
+    /// the moved footprint plus one quad per wall. All parts are wound clockwise, so the
+    /// non-zero rule fills their union.
+    fn draw_building_shadow(&self, dt: &mut DrawTarget, building: &Building) {
+        let Some(shadow) = self.theme.buildings.as_ref().and_then(|style| style.shadow.as_ref()) else {
+            return;
+        };
+        let (dx, dy) = Self::building_shadow_offset(building, shadow);
+        let mut pb = PathBuilder::new();
+        let mut push_clockwise = |points: &mut Vec<(f32, f32)>| {
+            if signed_area(points) < 0.0 {
+                points.reverse();
+            }
+            pb.move_to(points[0].0, points[0].1);
+            for (x, y) in &points[1..] {
+                pb.line_to(*x, *y);
+            }
+            pb.close();
         };
+        for polygon in &building.polygons {
+            let points: Vec<(f32, f32)> = polygon.iter().map(|coords| self.project_mercantor(coords)).collect();
+            if points.len() < 3 {
+                continue;
+            }
+            push_clockwise(&mut points.iter().map(|(x, y)| (x + dx, y + dy)).collect());
+            for pair in points.windows(2) {
+                let (a, b) = (pair[0], pair[1]);
+                push_clockwise(&mut vec![a, b, (b.0 + dx, b.1 + dy), (a.0 + dx, a.1 + dy)]);
+            }
+        }
+        dt.fill(&pb.finish(), &shadow.color, &DrawOptions::new());
+    }
 
-        let mut draw_options = DrawOptions::new();
-        draw_options.blend_mode = BlendMode::SrcOver;
+    fn draw_building(&self, dt: &mut DrawTarget, building: &Building) {
+        let Some(style) = &self.theme.buildings else {
+            return;
+        };
+        let mut pb = PathBuilder::new();
+        for polygon in &building.polygons {
+            let Some((first, rest)) = polygon.split_first() else {
+                continue;
+            };
+            let (x0, y0) = self.project_mercantor(first);
+            pb.move_to(x0, y0);
+            for coords in rest {
+                let (x, y) = self.project_mercantor(coords);
+                pb.line_to(x, y);
+            }
+            pb.close();
+        }
+        let mut path = pb.finish();
+        path.winding = Winding::EvenOdd;
+        let draw_options = DrawOptions::new();
+        dt.fill(&path, &style.color, &draw_options);
+        if style.outline_width > 0.0 {
+            dt.stroke(&path, &style.outline_color, &Self::stroke(style.outline_width), &draw_options);
+        }
+    }
 
-        // dt.draw_image_at(
-        dt.draw_image_with_size_at(
-            width,
-            height,
-            x_center - width / 2.0,
-            y_center - height / 2.0,
-            &img,
-            &draw_options,
+    fn draw_waterway(&self, dt: &mut DrawTarget, waterway: &Waterway) {
+        let Some((first, rest)) = waterway.path.split_first() else {
+            return;
+        };
+        let mut pb = PathBuilder::new();
+        let (x0, y0) = self.project_mercantor(first);
+        pb.move_to(x0, y0);
+        for coords in rest {
+            let (x, y) = self.project_mercantor(coords);
+            pb.line_to(x, y);
+        }
+        dt.stroke(
+            &pb.finish(),
+            &self.theme.areas[&AreaType::Water].color,
+            &Self::stroke(waterway_width(waterway.waterway_type)),
+            &DrawOptions::new(),
+        );
+    }
+
+    fn draw_landmark(&self, dt: &mut DrawTarget, landmark: &Landmark) {
+        let Some(icon) = self.icons.get(&landmark.category) else {
+            return;
+        };
+        let (x_center, y_center) = self.project_mercantor(&landmark.into());
+        icon.draw(dt, x_center, y_center);
+    }
+
+    fn draw_council_boundary(&self, dt: &mut DrawTarget, council: &Council) {
+        let Some(style) = &self.theme.council_boundary else {
+            return;
+        };
+        let mut pb = PathBuilder::new();
+        for ring in &council.boundary {
+            let Some((first, rest)) = ring.split_first() else {
+                continue;
+            };
+            let (x0, y0) = self.project_mercantor(first);
+            pb.move_to(x0, y0);
+            for coords in rest {
+                let (x, y) = self.project_mercantor(coords);
+                pb.line_to(x, y);
+            }
+        }
+        dt.stroke(
+            &pb.finish(),
+            &style.color,
+            &StrokeStyle {
+                dash_array: style.dash_array.clone(),
+                ..Self::stroke(style.width)
+            },
+            &DrawOptions::new(),
         );
     }
 
@@ -585,12 +1568,9 @@ impl DrawMapEtl<'_> {
             dt,
             x_center,
             y_center,
-            120.0,
             &council.name,
-            &Source::Solid(
-                SolidSource::from_unpremultiplied_argb(255, 100, 100, 100),
-            ),
-            0.7,
+            &self.theme.labels.council,
+            &self.theme.council_name_color,
         );
     }
 }
@@ -618,65 +1598,147 @@ impl Etl for DrawMapEtl<'_> {
     }
 
     fn transform(&mut self, input: Self::Input) -> Result<Self::Output> {
+        let mut input = input;
+        let grid = self.grid();
+        // The index lists every street, including the short ones generalization drops.
+        if let Some(grid) = &grid {
+            self.index = self.build_index(grid, &input);
+        }
+        self.generalize(&mut input);
+        self.line_styles = input.transit_lines.values()
+            .filter_map(|line| Some((line.id, self.resolve_line_style(line)?)))
+            .collect();
+        for line in input.transit_lines.values().filter(|line| !self.line_styles.contains_key(&line.id)) {
+            self.stats.unknown_value("line", &line.name);
+        }
+        let area_count = input.areas.len();
+        input.areas.retain(|area| self.theme.areas.contains_key(&area.area_type));
+        self.stats.dropped("area type without theme colour", area_count - input.areas.len());
+        // Stable sort, so that indices into areas are in stacking order.
+        input.areas.sort_by_key(|area| area.area_type);
+        // Track of lines that aren't drawn is left out of the plain rails by the semantic
+        // map, so it falls back to being drawn as one.
+        let (tube_rails, unstyled_rails): (Vec<TubeRail>, Vec<TubeRail>) = input.tube_rails.drain(..)
+            .partition(|rail| self.line_styles.contains_key(&rail.line));
+        self.stats.count("tube_rails/drawn_as_rail", unstyled_rails.len());
+        input.tube_rails = tube_rails;
+        input.rails.extend(unstyled_rails.into_iter().map(|rail| rail.path));
+        let landmarks_without_icon = input.landmarks.iter()
+            .filter(|landmark| self.icons.get(&landmark.category).is_none())
+            .count();
+        self.stats.dropped("landmark without icon", landmarks_without_icon);
+        // Sort once up front, so that indices into tube_rails are in draw order.
+        input.tube_rails.sort_by_key(|rail| (self.line_styles[&rail.line].draw_order, rail.line));
+        let bundles = LineBundles::new(&input.tube_rails);
+        let (place_labels, waterway_labels) = self.place_labels(&input);
+        self.stats.count("areas", input.areas.len());
+        self.stats.count("tube_rails", input.tube_rails.len());
+        self.stats.count("place_labels", place_labels.len());
+        self.stats.count("waterway_labels", waterway_labels.len());
+        let indices = self.build_spatial_indices(&input, &bundles, &place_labels, &waterway_labels);
+        let overlays = self.layout_overlays();
+        let furniture: Vec<FurnitureBlock> = self.layout_furniture(&input);
+        let graticule = self.graticule_lines();
+
         let mut dts = Vec::new();
-        let cell_size = 4096 * 2;
-        for cell_x in (0..self.user_config.width_px).step_by(cell_size) {
+        for cell_x in (0..self.user_config.width_px).step_by(CELL_SIZE) {
             let mut dt_col = Vec::new();
-            for cell_y in (0..self.user_config.height_px).step_by(cell_size) {
-                let cell_width = cell_size.min((self.user_config.width_px - cell_x).try_into()?);
-                let cell_height = cell_size.min((self.user_config.height_px - cell_y).try_into()?);
+            for cell_y in (0..self.user_config.height_px).step_by(CELL_SIZE) {
+                let cell_width = CELL_SIZE.min((self.user_config.width_px - cell_x).try_into()?);
+                let cell_height = CELL_SIZE.min((self.user_config.height_px - cell_y).try_into()?);
                 let mut dt = DrawTarget::new(
                     cell_width.try_into()?,
                     cell_height.try_into()?,
                 );
                 self.x_shift = cell_x as f32;
                 self.y_shift = cell_y as f32;
+                let cell_bbox = BoundingBox::new(
+                    self.x_shift,
+                    self.y_shift,
+                    self.x_shift + cell_width as f32,
+                    self.y_shift + cell_height as f32,
+                );
 
                 if let Source::Solid(s) = self.theme.background_color {
                     dt.clear(s);
                 } else {
                     panic!("All colours are solid sources!");
                 }
+                self.draw_overlays(&mut dt, &overlays, MapLayer::Background, &cell_bbox);
 
-                for area in &input.areas {
-                    self.draw_area(&mut dt, &area);
+                for idx in indices.areas.query(&cell_bbox) {
+                    self.draw_area(&mut dt, &input.areas[idx]);
                 }
-                for road in &input.roads {
-                    self.draw_semantic_path(&mut dt, &road, &PathStyle::Road);
+                self.draw_overlays(&mut dt, &overlays, MapLayer::Areas, &cell_bbox);
+                for idx in indices.waterways.query(&cell_bbox) {
+                    self.draw_waterway(&mut dt, &input.waterways[idx]);
                 }
-                for rail in &input.rails {
-                    self.draw_semantic_path(&mut dt, &rail, &PathStyle::Rail);
+                self.draw_overlays(&mut dt, &overlays, MapLayer::Waterways, &cell_bbox);
+                // All shadows go first, so that they never cover a neighbouring building.
+                let buildings = indices.buildings.query(&cell_bbox);
+                for idx in &buildings {
+                    self.draw_building_shadow(&mut dt, &input.buildings[*idx]);
                 }
-
-                let mut sorted_rails = input.tube_rails.clone();
-                sorted_rails.sort_by(
-                    |rail_a, rail_b| {
-                        if rail_a.line < rail_b.line {
-                            Ordering::Less
-                        } else if rail_a.line == rail_b.line {
-                            Ordering::Equal
-                        } else {
-                            Ordering::Greater
-                        }
-                    }
-                );
-
-                for rail in sorted_rails {
-                    self.draw_tube_rail(&mut dt, &rail);
+                for idx in &buildings {
+                    self.draw_building(&mut dt, &input.buildings[*idx]);
                 }
-                for council in &input.councils {
-                    self.draw_council(&mut dt, council);
+                self.draw_overlays(&mut dt, &overlays, MapLayer::Buildings, &cell_bbox);
+                for idx in indices.council_boundaries.query(&cell_bbox) {
+                    self.draw_council_boundary(&mut dt, &input.councils[idx]);
                 }
-                for station in &input.underground_stations {
-                    self.draw_undergound_station(&mut dt, station);
+                self.draw_overlays(&mut dt, &overlays, MapLayer::CouncilBoundaries, &cell_bbox);
+                for idx in indices.roads.query(&cell_bbox) {
+                    self.draw_semantic_path(&mut dt, &input.roads[idx].path, &PathStyle::Road);
                 }
-                for landmark in &input.landmarks {
-                    self.draw_landmark(&mut dt, landmark);
+                self.draw_overlays(&mut dt, &overlays, MapLayer::Roads, &cell_bbox);
+                for idx in indices.rails.query(&cell_bbox) {
+                    self.draw_semantic_path(&mut dt, &input.rails[idx], &PathStyle::Rail);
+                }
+                self.draw_overlays(&mut dt, &overlays, MapLayer::Rails, &cell_bbox);
+                for idx in indices.tube_rails.query(&cell_bbox) {
+                    self.draw_tube_rail(&mut dt, &input.tube_rails[idx], &bundles);
+                }
+                self.draw_overlays(&mut dt, &overlays, MapLayer::TubeRails, &cell_bbox);
+                for idx in indices.place_labels.query(&cell_bbox) {
+                    let label = &place_labels[idx];
+                    self.draw_text(
+                        &mut dt,
+                        label.x - self.x_shift,
+                        label.y - self.y_shift,
+                        &label.text,
+                        label.style,
+                        &self.theme.place_name_color,
+                    );
+                }
+                self.draw_overlays(&mut dt, &overlays, MapLayer::PlaceLabels, &cell_bbox);
+                for idx in indices.waterway_labels.query(&cell_bbox) {
+                    self.draw_path_label(&mut dt, &waterway_labels[idx], &self.theme.water_name_color);
+                }
+                self.draw_overlays(&mut dt, &overlays, MapLayer::WaterwayLabels, &cell_bbox);
+                for idx in indices.councils.query(&cell_bbox) {
+                    self.draw_council(&mut dt, &input.councils[idx]);
+                }
+                self.draw_overlays(&mut dt, &overlays, MapLayer::Councils, &cell_bbox);
+                for idx in indices.stations.query(&cell_bbox) {
+                    self.draw_undergound_station(&mut dt, &input.underground_stations[idx]);
+                }
+                self.draw_overlays(&mut dt, &overlays, MapLayer::Stations, &cell_bbox);
+                for idx in indices.landmarks.query(&cell_bbox) {
+                    self.draw_landmark(&mut dt, &input.landmarks[idx]);
+                }
+                self.draw_overlays(&mut dt, &overlays, MapLayer::Landmarks, &cell_bbox);
+                self.draw_graticule(&mut dt, &graticule);
+                if let Some(grid) = &grid {
+                    self.draw_grid(&mut dt, grid);
+                }
+                for block in furniture.iter().filter(|block| block.bbox.intersects(&cell_bbox)) {
+                    self.draw_furniture(&mut dt, block);
                 }
                 dt_col.push(dt);
             }
             dts.push(dt_col);
         }
+        self.stats.count("cells", dts.iter().map(Vec::len).sum());
         Ok(dts)
     }
 
@@ -689,6 +1751,14 @@ impl Etl for DrawMapEtl<'_> {
                     .map_err(|e| format!("Couldn't write png for x={}, y={}: {}", x, y, e))?;
             }
         }
+        if self.theme.grid.is_some() {
+            write_index_csv(&dir.join(INDEX_CSV_FILE_NAME), &self.index)?;
+            self.write_index_pages(dir, &self.index)?;
+        }
         Ok(())
     }
+
+    fn stats(&mut self) -> &mut EtlStats {
+        &mut self.stats
+    }
 }
@@ -0,0 +1,598 @@
+use std::collections::HashSet;
+
+use raqote::{DrawOptions, DrawTarget, PathBuilder, Source};
+use serde::Deserialize;
+
+use crate::data::semantic::{MapCoords, SemanticMapElements};
+
+use super::serialize_color::{deserialize, deserialize_optional};
+use super::spatial_index::BoundingBox;
+use super::{
+    plain_text_style, DrawMapEtl, FontWeight, TextCase, TextStyle, INTERCHANGE_RING_RADIUS,
+    STATION_DOT_OUTLINE, STATION_DOT_RADIUS,
+};
+
+/// Required by the OSM licence, so it is always drawn.
+const ATTRIBUTION: &str = "© OpenStreetMap contributors";
+
+/// Space between the lines of a block and between legend symbols and their labels, as a
+/// fraction of the text size.
+const FURNITURE_GAP: f32 = 0.5;
+
+/// Where a piece of furniture goes on the map.
+#[derive(Deserialize, Clone, Copy)]
+pub struct Position {
+    pub anchor: Anchor,
+    /// Distance from the map edges the furniture is anchored to, in px.
+    #[serde(default = "default_margin")]
+    pub margin: f32,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Anchor {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+impl Anchor {
+    /// Horizontal and vertical position as fractions of the map size, from the top left.
+    fn fractions(&self) -> (f32, f32) {
+        match self {
+            Anchor::TopLeft => (0.0, 0.0),
+            Anchor::Top => (0.5, 0.0),
+            Anchor::TopRight => (1.0, 0.0),
+            Anchor::Left => (0.0, 0.5),
+            Anchor::Center => (0.5, 0.5),
+            Anchor::Right => (1.0, 0.5),
+            Anchor::BottomLeft => (0.0, 1.0),
+            Anchor::Bottom => (0.5, 1.0),
+            Anchor::BottomRight => (1.0, 1.0),
+        }
+    }
+}
+
+fn default_margin() -> f32 {
+    80.0
+}
+
+/// Background drawn behind every piece of furniture, so that it stays readable on top of
+/// the map.
+#[derive(Deserialize)]
+pub struct Panel<'a> {
+    #[serde(deserialize_with = "deserialize")]
+    pub color: Source<'a>,
+    /// Space around the content, in px.
+    #[serde(default = "default_panel_padding")]
+    pub padding: f32,
+}
+
+fn default_panel_padding() -> f32 {
+    40.0
+}
+
+#[derive(Deserialize)]
+pub struct Caption<'a> {
+    pub text: String,
+    pub style: TextStyle<'a>,
+}
+
+/// Text lines are aligned to the side of the map the block is anchored to.
+#[derive(Deserialize)]
+pub struct TitleBlock<'a> {
+    pub position: Position,
+    pub title: Caption<'a>,
+    #[serde(default)]
+    pub subtitle: Option<Caption<'a>>,
+}
+
+/// Lists the symbols of the stations and landmarks present on the map, labelled as in the
+/// icon manifest.
+#[derive(Deserialize)]
+pub struct Legend<'a> {
+    pub position: Position,
+    #[serde(default)]
+    pub heading: Option<Caption<'a>>,
+    pub style: TextStyle<'a>,
+    #[serde(default = "default_legend_columns")]
+    pub columns: usize,
+    /// Label of the dot drawn for stations without an icon.
+    #[serde(default = "default_station_label")]
+    pub station_label: String,
+    #[serde(default = "default_interchange_label")]
+    pub interchange_label: String,
+}
+
+fn default_legend_columns() -> usize {
+    1
+}
+
+fn default_station_label() -> String {
+    "Station".to_string()
+}
+
+fn default_interchange_label() -> String {
+    "Interchange".to_string()
+}
+
+/// A bar of alternating segments, as long as the longest round distance that fits into
+/// `max_width`, measured through the middle of the map.
+#[derive(Deserialize)]
+pub struct ScaleBar<'a> {
+    pub position: Position,
+    pub style: TextStyle<'a>,
+    #[serde(default = "default_scale_bar_max_width")]
+    pub max_width: f32,
+    #[serde(default = "default_scale_bar_height")]
+    pub height: f32,
+    #[serde(default = "default_scale_bar_segments")]
+    pub segments: usize,
+    /// Defaults to `text_color`.
+    #[serde(default, deserialize_with = "deserialize_optional")]
+    pub color: Option<Source<'a>>,
+}
+
+fn default_scale_bar_max_width() -> f32 {
+    1200.0
+}
+
+fn default_scale_bar_height() -> f32 {
+    16.0
+}
+
+fn default_scale_bar_segments() -> usize {
+    4
+}
+
+#[derive(Deserialize)]
+pub struct NorthArrow<'a> {
+    pub position: Position,
+    /// Height of the arrow, in px.
+    #[serde(default = "default_north_arrow_size")]
+    pub size: f32,
+    /// Defaults to `text_color`.
+    #[serde(default, deserialize_with = "deserialize_optional")]
+    pub color: Option<Source<'a>>,
+    /// An "N" is drawn above the arrow if this is set.
+    #[serde(default)]
+    pub label: Option<TextStyle<'a>>,
+}
+
+fn default_north_arrow_size() -> f32 {
+    160.0
+}
+
+#[derive(Deserialize)]
+pub struct Attribution<'a> {
+    pub position: Position,
+    pub style: TextStyle<'a>,
+}
+
+impl Default for Attribution<'_> {
+    fn default() -> Self {
+        Attribution {
+            position: Position { anchor: Anchor::BottomRight, margin: default_margin() },
+            style: plain_text_style(FontWeight::Regular, 28.0, 0.0, TextCase::Original),
+        }
+    }
+}
+
+/// Everything drawn on top of the map rather than on the map itself. Only the attribution
+/// is drawn if nothing is configured.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Furniture<'a> {
+    pub panel: Option<Panel<'a>>,
+    pub title: Option<TitleBlock<'a>>,
+    pub legend: Option<Legend<'a>>,
+    pub scale_bar: Option<ScaleBar<'a>>,
+    pub north_arrow: Option<NorthArrow<'a>>,
+    pub attribution: Attribution<'a>,
+}
+
+impl<'a> Furniture<'a> {
+    pub fn text_styles(&self) -> Vec<&TextStyle<'a>> {
+        let mut styles = vec![&self.attribution.style];
+        if let Some(title) = &self.title {
+            styles.push(&title.title.style);
+            styles.extend(title.subtitle.as_ref().map(|subtitle| &subtitle.style));
+        }
+        if let Some(legend) = &self.legend {
+            styles.push(&legend.style);
+            styles.extend(legend.heading.as_ref().map(|heading| &heading.style));
+        }
+        styles.extend(self.scale_bar.as_ref().map(|scale_bar| &scale_bar.style));
+        styles.extend(self.north_arrow.as_ref().and_then(|arrow| arrow.label.as_ref()));
+        styles
+    }
+}
+
+enum LegendSymbol {
+    Icon(&'static str),
+    Landmark(String),
+    Dot,
+    Interchange,
+}
+
+enum FurnitureItem<'a> {
+    /// Text drawn by `draw_text` at (x, y).
+    Text { x: f32, y: f32, text: String, style: &'a TextStyle<'a> },
+    Icon { name: String, x: f32, y: f32 },
+    StationDot { x: f32, y: f32 },
+    InterchangeRing { x: f32, y: f32 },
+    /// From (x, y) at the top left.
+    ScaleBar { x: f32, y: f32, width: f32, style: &'a ScaleBar<'a> },
+    /// Centered on (x, y).
+    NorthArrow { x: f32, y: f32, style: &'a NorthArrow<'a> },
+}
+
+/// A piece of furniture, laid out around its own origin and moved into place as a whole.
+pub struct FurnitureBlock<'a> {
+    /// Absolute, including the panel.
+    pub bbox: BoundingBox,
+    /// Absolute position of the origin of the items.
+    offset: (f32, f32),
+    items: Vec<FurnitureItem<'a>>,
+}
+
+/// Longest distance up to `max_m` that is 1, 2 or 5 times a power of ten.
+fn round_length(max_m: f64) -> f64 {
+    let power = 10f64.powi(max_m.log10().floor() as i32);
+    [5.0, 2.0, 1.0].into_iter()
+        .map(|factor| factor * power)
+        .find(|length| *length <= max_m)
+        .unwrap_or(power)
+}
+
+fn format_length(m: f64) -> String {
+    if m >= 1000.0 {
+        format!("{} km", m / 1000.0)
+    } else {
+        format!("{} m", m)
+    }
+}
+
+impl<'a> DrawMapEtl<'a> {
+    /// Lays out all configured furniture in absolute pixel coordinates.
+    pub(super) fn layout_furniture(&self, input: &SemanticMapElements) -> Vec<FurnitureBlock<'a>> {
+        let furniture = &self.theme.furniture;
+        let mut blocks = Vec::new();
+        if let Some(title) = &furniture.title {
+            blocks.push(self.layout_title(title));
+        }
+        if let Some(legend) = &furniture.legend {
+            blocks.extend(self.layout_legend(legend, input));
+        }
+        if let Some(scale_bar) = &furniture.scale_bar {
+            blocks.push(self.layout_scale_bar(scale_bar));
+        }
+        if let Some(north_arrow) = &furniture.north_arrow {
+            blocks.push(self.layout_north_arrow(north_arrow));
+        }
+        blocks.push(self.layout_attribution(&furniture.attribution));
+        blocks
+    }
+
+    fn furniture_text(
+        &self,
+        text: &str,
+        style: &'a TextStyle<'a>,
+        position: (f32, f32),
+        align: (f32, f32),
+    ) -> (FurnitureItem<'a>, BoundingBox) {
+        let ((x, y), bbox) = self.align_text(text, style, position, align);
+        (FurnitureItem::Text { x, y, text: text.to_string(), style }, bbox)
+    }
+
+    /// Moves the items, which cover `content`, to `position`.
+    fn place_furniture(&self, position: &Position, items: Vec<FurnitureItem<'a>>, content: BoundingBox) -> FurnitureBlock<'a> {
+        let padding = self.theme.furniture.panel.as_ref().map_or(0.0, |panel| panel.padding);
+        let width = content.width() + 2.0 * padding;
+        let height = content.height() + 2.0 * padding;
+        let (fx, fy) = position.anchor.fractions();
+        // Centered furniture ignores the margin along the centered axis.
+        let min_x = fx * (self.user_config.width_px as f32 - width) + (1.0 - 2.0 * fx) * position.margin;
+        let min_y = fy * (self.user_config.height_px as f32 - height) + (1.0 - 2.0 * fy) * position.margin;
+        FurnitureBlock {
+            bbox: BoundingBox::new(min_x, min_y, min_x + width, min_y + height),
+            offset: (min_x + padding - content.min_x, min_y + padding - content.min_y),
+            items,
+        }
+    }
+
+    fn layout_title(&self, title: &'a TitleBlock<'a>) -> FurnitureBlock<'a> {
+        let captions: Vec<&Caption> = std::iter::once(&title.title).chain(&title.subtitle).collect();
+        let width = captions.iter()
+            .map(|caption| self.text_bbox(0.0, 0.0, &caption.text, &caption.style).width())
+            .fold(0.0, f32::max);
+        let (align, _) = title.position.anchor.fractions();
+
+        let mut items = Vec::new();
+        let mut content = BoundingBox::new(0.0, 0.0, 0.0, 0.0);
+        let mut top = 0.0;
+        for caption in captions {
+            let (item, bbox) = self.furniture_text(&caption.text, &caption.style, (align * width, top), (align, 0.0));
+            top = bbox.max_y + caption.style.size * FURNITURE_GAP;
+            content = content.union(&bbox);
+            items.push(item);
+        }
+        self.place_furniture(&title.position, items, content)
+    }
+
+    /// Symbols of the stations and landmarks on the map, leaving out those of the
+    /// extract outside it.
+    fn legend_entries(&self, legend: &Legend, input: &SemanticMapElements) -> Vec<(LegendSymbol, String)> {
+        let (width, height) = (self.user_config.width_px as f32, self.user_config.height_px as f32);
+        let on_map = |coords: &MapCoords| {
+            let (x, y) = self.project_absolute(coords);
+            (0.0..=width).contains(&x) && (0.0..=height).contains(&y)
+        };
+        let mut station_icons = HashSet::new();
+        let mut dots = false;
+        let mut interchanges = false;
+        for station in input.underground_stations.iter().filter(|station| on_map(&(*station).into())) {
+            if station.is_interchange() {
+                interchanges = true;
+            } else {
+                match self.station_icon_name(station).filter(|name| self.icons.get(name).is_some()) {
+                    Some(name) => { station_icons.insert(name); },
+                    None => dots = true,
+                }
+            }
+        }
+        let landmarks: HashSet<&str> = input.landmarks.iter()
+            .filter(|landmark| on_map(&(*landmark).into()))
+            .map(|landmark| landmark.category.as_str())
+            .filter(|category| self.icons.get(category).is_some())
+            .collect();
+
+        let label = |name: &str| self.icons.get(name).map_or(name, |icon| icon.label()).to_string();
+        let mut stations: Vec<_> = station_icons.into_iter()
+            .map(|name| (LegendSymbol::Icon(name), label(name)))
+            .collect();
+        stations.sort_by(|a, b| a.1.cmp(&b.1));
+        let mut landmarks: Vec<_> = landmarks.into_iter()
+            .map(|category| (LegendSymbol::Landmark(category.to_string()), label(category)))
+            .collect();
+        landmarks.sort_by(|a, b| a.1.cmp(&b.1));
+
+        let mut entries = stations;
+        if dots {
+            entries.push((LegendSymbol::Dot, legend.station_label.clone()));
+        }
+        if interchanges {
+            entries.push((LegendSymbol::Interchange, legend.interchange_label.clone()));
+        }
+        entries.extend(landmarks);
+        entries
+    }
+
+    /// Bounding box of the legend symbol centered on the origin.
+    fn legend_symbol_bbox(&self, symbol: &LegendSymbol) -> BoundingBox {
+        let icon_name = match symbol {
+            LegendSymbol::Icon(name) => *name,
+            LegendSymbol::Landmark(name) => name.as_str(),
+            LegendSymbol::Dot => {
+                return BoundingBox::new(0.0, 0.0, 0.0, 0.0).grow(STATION_DOT_RADIUS + STATION_DOT_OUTLINE);
+            },
+            LegendSymbol::Interchange => {
+                return BoundingBox::new(0.0, 0.0, 0.0, 0.0).grow(INTERCHANGE_RING_RADIUS + self.theme.interchange.width);
+            },
+        };
+        let bbox = self.icons.get(icon_name)
+            .map_or(BoundingBox::new(0.0, 0.0, 0.0, 0.0), |icon| icon.bbox(0.0, 0.0));
+        let (dx, dy) = ((bbox.min_x + bbox.max_x) / 2.0, (bbox.min_y + bbox.max_y) / 2.0);
+        BoundingBox::new(bbox.min_x - dx, bbox.min_y - dy, bbox.max_x - dx, bbox.max_y - dy)
+    }
+
+    fn legend_symbol_item(&self, symbol: LegendSymbol, x: f32, y: f32) -> FurnitureItem<'a> {
+        let icon = |name: &str| {
+            // Icons are placed by their anchor, which need not be their center.
+            let bbox = self.icons.get(name)
+                .map_or(BoundingBox::new(0.0, 0.0, 0.0, 0.0), |icon| icon.bbox(0.0, 0.0));
+            FurnitureItem::Icon {
+                name: name.to_string(),
+                x: x - (bbox.min_x + bbox.max_x) / 2.0,
+                y: y - (bbox.min_y + bbox.max_y) / 2.0,
+            }
+        };
+        match symbol {
+            LegendSymbol::Icon(name) => icon(name),
+            LegendSymbol::Landmark(name) => icon(&name),
+            LegendSymbol::Dot => FurnitureItem::StationDot { x, y },
+            LegendSymbol::Interchange => FurnitureItem::InterchangeRing { x, y },
+        }
+    }
+
+    /// Returns `None` if there is nothing to explain.
+    fn layout_legend(&self, legend: &'a Legend<'a>, input: &SemanticMapElements) -> Option<FurnitureBlock<'a>> {
+        let entries = self.legend_entries(legend, input);
+        if entries.is_empty() {
+            return None;
+        }
+        let gap = legend.style.size * FURNITURE_GAP;
+        let symbol_boxes: Vec<BoundingBox> = entries.iter()
+            .map(|(symbol, _)| self.legend_symbol_bbox(symbol))
+            .collect();
+        let label_boxes: Vec<BoundingBox> = entries.iter()
+            .map(|(_, label)| self.text_bbox(0.0, 0.0, label, &legend.style))
+            .collect();
+        let symbol_width = symbol_boxes.iter().map(BoundingBox::width).fold(0.0, f32::max);
+        let row_height = symbol_boxes.iter().chain(&label_boxes)
+            .map(BoundingBox::height)
+            .fold(0.0, f32::max);
+
+        let mut items = Vec::new();
+        let mut content = BoundingBox::new(0.0, 0.0, 0.0, 0.0);
+        let mut rows_top = 0.0;
+        if let Some(heading) = &legend.heading {
+            let (item, bbox) = self.furniture_text(&heading.text, &heading.style, (0.0, 0.0), (0.0, 0.0));
+            rows_top = bbox.max_y + heading.style.size * FURNITURE_GAP;
+            content = content.union(&bbox);
+            items.push(item);
+        }
+
+        let rows = entries.len().div_ceil(legend.columns.max(1));
+        let mut column_x = 0.0;
+        let mut entries = entries.into_iter().zip(label_boxes).peekable();
+        while entries.peek().is_some() {
+            let mut column_width: f32 = 0.0;
+            for (row, ((symbol, label), label_bbox)) in entries.by_ref().take(rows).enumerate() {
+                let y_center = rows_top + row as f32 * (row_height + gap) + row_height / 2.0;
+                items.push(self.legend_symbol_item(symbol, column_x + symbol_width / 2.0, y_center));
+                let label_x = column_x + symbol_width + gap;
+                let (item, bbox) = self.furniture_text(&label, &legend.style, (label_x, y_center), (0.0, 0.5));
+                column_width = column_width.max(symbol_width + gap + label_bbox.width());
+                content = content.union(&bbox);
+                items.push(item);
+            }
+            content = content.union(&BoundingBox::new(
+                column_x,
+                rows_top,
+                column_x + symbol_width,
+                rows_top + rows as f32 * (row_height + gap) - gap,
+            ));
+            column_x += column_width + 2.0 * gap;
+        }
+        Some(self.place_furniture(&legend.position, items, content))
+    }
+
+    /// Metres per px along the horizontal through the middle of the map.
+    fn metres_per_px(&self) -> f64 {
+        let config = self.user_config;
+        let lat = config.top_left_lat - config.height_px as f64 / 2.0 / config.px_per_deg_lat;
+        let lon = config.top_left_lon + config.width_px as f64 / 2.0 / config.px_per_deg_lon;
+        let center = MapCoords { lat, lon };
+        // Measured over many pixels, as a single one is too short for f64 precision.
+        let px = 1000.0;
+        let east = MapCoords { lat, lon: lon + px / config.px_per_deg_lon };
+        center.distance_m(&east) / px
+    }
+
+    fn layout_scale_bar(&self, scale_bar: &'a ScaleBar<'a>) -> FurnitureBlock<'a> {
+        let metres_per_px = self.metres_per_px();
+        let length_m = round_length(scale_bar.max_width as f64 * metres_per_px);
+        let width = (length_m / metres_per_px) as f32;
+        let bar_top = scale_bar.style.size * FURNITURE_GAP;
+
+        let (zero, zero_bbox) = self.furniture_text("0", &scale_bar.style, (0.0, 0.0), (0.5, 1.0));
+        let (end, end_bbox) = self.furniture_text(&format_length(length_m), &scale_bar.style, (width, 0.0), (0.5, 1.0));
+        let content = zero_bbox
+            .union(&end_bbox)
+            .union(&BoundingBox::new(0.0, bar_top, width, bar_top + scale_bar.height));
+        let items = vec![
+            zero,
+            end,
+            FurnitureItem::ScaleBar { x: 0.0, y: bar_top, width, style: scale_bar },
+        ];
+        self.place_furniture(&scale_bar.position, items, content)
+    }
+
+    fn layout_north_arrow(&self, north_arrow: &'a NorthArrow<'a>) -> FurnitureBlock<'a> {
+        let half = north_arrow.size / 2.0;
+        let mut content = BoundingBox::new(-0.3 * north_arrow.size, -half, 0.3 * north_arrow.size, half);
+        let mut items = vec![FurnitureItem::NorthArrow { x: 0.0, y: 0.0, style: north_arrow }];
+        if let Some(style) = &north_arrow.label {
+            let (item, bbox) = self.furniture_text("N", style, (0.0, -half - style.size * FURNITURE_GAP), (0.5, 1.0));
+            content = content.union(&bbox);
+            items.push(item);
+        }
+        self.place_furniture(&north_arrow.position, items, content)
+    }
+
+    fn layout_attribution(&self, attribution: &'a Attribution<'a>) -> FurnitureBlock<'a> {
+        let (align, _) = attribution.position.anchor.fractions();
+        let (item, bbox) = self.furniture_text(ATTRIBUTION, &attribution.style, (0.0, 0.0), (align, 0.0));
+        self.place_furniture(&attribution.position, vec![item], bbox)
+    }
+
+    fn draw_scale_bar(&self, dt: &mut DrawTarget, (x, y): (f32, f32), width: f32, style: &ScaleBar) {
+        let color = style.color.as_ref().unwrap_or(&self.theme.text_color);
+        let draw_options = DrawOptions::new();
+        let segments = style.segments.max(1);
+        let segment_width = width / segments as f32;
+        let mut filled = PathBuilder::new();
+        for segment in (0..segments).step_by(2) {
+            filled.rect(x + segment as f32 * segment_width, y, segment_width, style.height);
+        }
+        dt.fill(&filled.finish(), color, &draw_options);
+
+        let mut outline = PathBuilder::new();
+        outline.rect(x, y, width, style.height);
+        dt.stroke(&outline.finish(), color, &Self::stroke(style.height * 0.15), &draw_options);
+    }
+
+    /// An arrow pointing up, with its left half filled.
+    fn draw_north_arrow(&self, dt: &mut DrawTarget, (x, y): (f32, f32), style: &NorthArrow) {
+        let color = style.color.as_ref().unwrap_or(&self.theme.text_color);
+        let draw_options = DrawOptions::new();
+        let size = style.size;
+        let tip = (x, y - size / 2.0);
+        let notch = (x, y + size * 0.25);
+
+        let mut left = PathBuilder::new();
+        left.move_to(tip.0, tip.1);
+        left.line_to(x - size * 0.3, y + size / 2.0);
+        left.line_to(notch.0, notch.1);
+        left.close();
+        dt.fill(&left.finish(), color, &draw_options);
+
+        let mut outline = PathBuilder::new();
+        outline.move_to(tip.0, tip.1);
+        outline.line_to(x - size * 0.3, y + size / 2.0);
+        outline.line_to(notch.0, notch.1);
+        outline.line_to(x + size * 0.3, y + size / 2.0);
+        outline.close();
+        dt.stroke(&outline.finish(), color, &Self::stroke(size * 0.03), &draw_options);
+    }
+
+    pub(super) fn draw_furniture(&self, dt: &mut DrawTarget, block: &FurnitureBlock) {
+        if let Some(panel) = &self.theme.furniture.panel {
+            let mut pb = PathBuilder::new();
+            pb.rect(
+                block.bbox.min_x - self.x_shift,
+                block.bbox.min_y - self.y_shift,
+                block.bbox.width(),
+                block.bbox.height(),
+            );
+            dt.fill(&pb.finish(), &panel.color, &DrawOptions::new());
+        }
+
+        let shift = |x: f32, y: f32| (x + block.offset.0 - self.x_shift, y + block.offset.1 - self.y_shift);
+        for item in &block.items {
+            match item {
+                FurnitureItem::Text { x, y, text, style } => {
+                    let (x, y) = shift(*x, *y);
+                    self.draw_text(dt, x, y, text, style, &self.theme.text_color);
+                },
+                FurnitureItem::Icon { name, x, y } => {
+                    if let Some(icon) = self.icons.get(name) {
+                        let (x, y) = shift(*x, *y);
+                        icon.draw(dt, x, y);
+                    }
+                },
+                FurnitureItem::StationDot { x, y } => {
+                    let (x, y) = shift(*x, *y);
+                    self.draw_station_dot(dt, x, y);
+                },
+                FurnitureItem::InterchangeRing { x, y } => {
+                    let (x, y) = shift(*x, *y);
+                    self.draw_interchange_ring(dt, x, y);
+                },
+                FurnitureItem::ScaleBar { x, y, width, style } => {
+                    self.draw_scale_bar(dt, shift(*x, *y), *width, style);
+                },
+                FurnitureItem::NorthArrow { x, y, style } => {
+                    self.draw_north_arrow(dt, shift(*x, *y), style);
+                },
+            }
+        }
+    }
+}
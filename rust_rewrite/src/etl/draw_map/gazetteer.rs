@@ -0,0 +1,378 @@
+use std::{collections::{BTreeSet, HashMap}, fs, path::Path};
+
+use raqote::{DrawOptions, DrawTarget, PathBuilder, Source};
+use serde::Deserialize;
+
+use crate::data::semantic::SemanticMapElements;
+use crate::errors::Result;
+
+use super::serialize_color::deserialize;
+use super::{plain_text_style, DrawMapEtl, FontWeight, TextCase, TextStyle};
+
+pub const INDEX_CSV_FILE_NAME: &str = "index.csv";
+
+/// A–Z style reference grid over the map. Named streets, stations and landmarks are
+/// indexed by the squares they are in.
+#[derive(Deserialize)]
+pub struct GridStyle<'a> {
+    /// Number of squares across, lettered from A. Squares are square, so the number of
+    /// rows follows from the map's height.
+    pub columns: usize,
+    #[serde(deserialize_with = "deserialize")]
+    pub color: Source<'a>,
+    #[serde(default = "default_grid_width")]
+    pub width: f32,
+    /// Letters and numbers along the map edges.
+    pub label: TextStyle<'a>,
+    #[serde(default)]
+    pub index: IndexPageStyle<'a>,
+}
+
+fn default_grid_width() -> f32 {
+    2.0
+}
+
+/// Layout of the typeset index, in px.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct IndexPageStyle<'a> {
+    pub title: String,
+    pub width: f32,
+    pub height: f32,
+    pub margin: f32,
+    pub columns: usize,
+    /// Only the font, size and weight are used.
+    pub style: TextStyle<'a>,
+}
+
+impl Default for IndexPageStyle<'_> {
+    fn default() -> Self {
+        // A4 at 300 dpi.
+        IndexPageStyle {
+            title: "Index".to_string(),
+            width: 2480.0,
+            height: 3508.0,
+            margin: 150.0,
+            columns: 4,
+            style: plain_text_style(FontWeight::Regular, 30.0, 0.0, TextCase::Original),
+        }
+    }
+}
+
+pub struct Grid {
+    square_size: f32,
+    columns: usize,
+    rows: usize,
+    width: f32,
+    height: f32,
+}
+
+impl Grid {
+    pub fn new(columns: usize, width: f32, height: f32) -> Grid {
+        let columns = columns.max(1);
+        let square_size = width / columns as f32;
+        Grid {
+            square_size,
+            columns,
+            rows: (height / square_size).ceil().max(1.0) as usize,
+            width,
+            height,
+        }
+    }
+
+    /// Column and row of the square containing (x, y), or `None` outside the map.
+    fn square(&self, x: f32, y: f32) -> Option<(usize, usize)> {
+        if x < 0.0 || y < 0.0 || x >= self.width || y >= self.height {
+            return None;
+        }
+        let column = ((x / self.square_size) as usize).min(self.columns - 1);
+        let row = ((y / self.square_size) as usize).min(self.rows - 1);
+        Some((column, row))
+    }
+
+    /// Squares a path passes through, found by walking it in steps shorter than a square.
+    fn path_squares(&self, points: &[(f32, f32)]) -> BTreeSet<(usize, usize)> {
+        let step = self.square_size / 4.0;
+        let mut squares: BTreeSet<(usize, usize)> = points.iter()
+            .filter_map(|(x, y)| self.square(*x, *y))
+            .collect();
+        for segment in points.windows(2) {
+            let ((x0, y0), (x1, y1)) = (segment[0], segment[1]);
+            let steps = ((x1 - x0).hypot(y1 - y0) / step).ceil() as usize;
+            for idx in 1..steps {
+                let t = idx as f32 / steps as f32;
+                squares.extend(self.square(x0 + t * (x1 - x0), y0 + t * (y1 - y0)));
+            }
+        }
+        squares
+    }
+
+    /// A to Z, then AA, AB and so on, like spreadsheet columns.
+    fn column_name(column: usize) -> String {
+        let mut name = Vec::new();
+        let mut remaining = column + 1;
+        while remaining > 0 {
+            remaining -= 1;
+            name.push((b'A' + (remaining % 26) as u8) as char);
+            remaining /= 26;
+        }
+        name.iter().rev().collect()
+    }
+
+    fn reference(&self, (column, row): (usize, usize)) -> String {
+        format!("{}{}", Self::column_name(column), row + 1)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum IndexKind {
+    Street,
+    Station,
+    Landmark,
+}
+
+impl IndexKind {
+    fn name(&self) -> &'static str {
+        match self {
+            IndexKind::Street => "street",
+            IndexKind::Station => "station",
+            IndexKind::Landmark => "landmark",
+        }
+    }
+}
+
+/// A named feature of the map and the grid squares it is in.
+pub struct IndexEntry {
+    name: String,
+    kind: IndexKind,
+    references: Vec<String>,
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+pub fn write_index_csv(path: &Path, entries: &[IndexEntry]) -> Result<()> {
+    let mut csv = String::from("name,type,squares\n");
+    for entry in entries {
+        csv += &format!(
+            "{},{},{}\n",
+            csv_field(&entry.name),
+            entry.kind.name(),
+            entry.references.join(" "),
+        );
+    }
+    fs::write(path, csv)?;
+    Ok(())
+}
+
+/// One line of the typeset index.
+enum IndexLine<'e> {
+    /// Initial letter starting a group of entries.
+    Heading(char),
+    /// References that did not fit next to the name go on their own line.
+    Entry { entry: &'e IndexEntry, wrapped: bool },
+    References(&'e IndexEntry),
+}
+
+impl<'a> DrawMapEtl<'a> {
+    pub(super) fn grid(&self) -> Option<Grid> {
+        let style = self.theme.grid.as_ref()?;
+        Some(Grid::new(
+            style.columns,
+            self.user_config.width_px as f32,
+            self.user_config.height_px as f32,
+        ))
+    }
+
+    /// Alphabetical index of all named features within the map. Streets split into several
+    /// ways are listed once, with the squares of all ways.
+    pub(super) fn build_index(&self, grid: &Grid, input: &SemanticMapElements) -> Vec<IndexEntry> {
+        let mut squares: HashMap<(String, IndexKind), BTreeSet<(usize, usize)>> = HashMap::new();
+        let mut add = |name: &str, kind: IndexKind, found: BTreeSet<(usize, usize)>| {
+            if !found.is_empty() {
+                squares.entry((name.to_string(), kind)).or_default().extend(found);
+            }
+        };
+        for road in &input.roads {
+            if let Some(name) = &road.name {
+                let points: Vec<(f32, f32)> = road.path.iter().map(|coords| self.project_absolute(coords)).collect();
+                add(name, IndexKind::Street, grid.path_squares(&points));
+            }
+        }
+        for station in &input.underground_stations {
+            let (x, y) = self.project_absolute(&station.into());
+            add(&station.name, IndexKind::Station, grid.square(x, y).into_iter().collect());
+        }
+        for landmark in &input.landmarks {
+            if let Some(name) = &landmark.name {
+                let (x, y) = self.project_absolute(&landmark.into());
+                add(name, IndexKind::Landmark, grid.square(x, y).into_iter().collect());
+            }
+        }
+
+        let mut entries: Vec<IndexEntry> = squares.into_iter()
+            .map(|((name, kind), squares)| IndexEntry {
+                name,
+                kind,
+                references: squares.into_iter().map(|square| grid.reference(square)).collect(),
+            })
+            .collect();
+        entries.sort_by_cached_key(|entry| (entry.name.to_lowercase(), entry.kind, entry.name.clone()));
+        entries
+    }
+
+    /// Draws the grid lines and labels the squares along all four edges of the map.
+    pub(super) fn draw_grid(&self, dt: &mut DrawTarget, grid: &Grid) {
+        let Some(style) = &self.theme.grid else {
+            return;
+        };
+        let mut pb = PathBuilder::new();
+        for column in 1..grid.columns {
+            let x = column as f32 * grid.square_size - self.x_shift;
+            pb.move_to(x, -self.y_shift);
+            pb.line_to(x, grid.height - self.y_shift);
+        }
+        for row in 1..grid.rows {
+            let y = row as f32 * grid.square_size - self.y_shift;
+            pb.move_to(-self.x_shift, y);
+            pb.line_to(grid.width - self.x_shift, y);
+        }
+        dt.stroke(&pb.finish(), &style.color, &Self::stroke(style.width), &DrawOptions::new());
+
+        let size = style.label.size;
+        for column in 0..grid.columns {
+            let name = Grid::column_name(column);
+            let x = (column as f32 + 0.5) * grid.square_size - self.x_shift;
+            self.draw_text(dt, x, size * 1.5 - self.y_shift, &name, &style.label, &style.color);
+            self.draw_text(dt, x, grid.height - size * 0.8 - self.y_shift, &name, &style.label, &style.color);
+        }
+        for row in 0..grid.rows {
+            let number = (row + 1).to_string();
+            let y_center = (row as f32 + 0.5) * grid.square_size;
+            // Roughly half the height of digits, which centers them on the row.
+            let baseline = y_center.min(grid.height - size) + size * 0.35 - self.y_shift;
+            self.draw_text(dt, size * 1.2 - self.x_shift, baseline, &number, &style.label, &style.color);
+            self.draw_text(dt, grid.width - size * 1.2 - self.x_shift, baseline, &number, &style.label, &style.color);
+        }
+    }
+
+    /// Typesets the index onto as many SVG pages as needed, in columns grouped by initial
+    /// letter. Stations are set in bold and landmarks in italics.
+    pub(super) fn write_index_pages(&self, dir: &Path, entries: &[IndexEntry]) -> Result<()> {
+        let Some(grid_style) = &self.theme.grid else {
+            return Ok(());
+        };
+        let page = &grid_style.index;
+        let chain = &self.fonts[page.style.font_path()];
+        let family = chain.font(0).family_name();
+        let size = page.style.size;
+        let line_height = size * 1.4;
+        let gap = size;
+        let columns = page.columns.max(1);
+        let column_width = (page.width - 2.0 * page.margin - (columns - 1) as f32 * gap) / columns as f32;
+        let width_of = |text: &str| chain.shape(text, size, 0.0).width;
+
+        let mut lines = Vec::new();
+        let mut initial = None;
+        for entry in entries {
+            let first = entry.name.chars().next().map(|c| c.to_uppercase().next().unwrap_or(c));
+            if first != initial {
+                initial = first;
+                lines.extend(first.map(IndexLine::Heading));
+            }
+            let references = entry.references.join(", ");
+            let wrapped = width_of(&entry.name) + gap + width_of(&references) > column_width;
+            lines.push(IndexLine::Entry { entry, wrapped });
+            if wrapped {
+                lines.push(IndexLine::References(entry));
+            }
+        }
+
+        let title_height = size * 4.0;
+        let mut pages: Vec<String> = Vec::new();
+        // Past the last column, so that the first line starts a page.
+        let mut column = columns;
+        let mut y = 0.0;
+        for line in lines {
+            let height = match line {
+                IndexLine::Heading(_) => line_height * 2.0,
+                _ => line_height,
+            };
+            if column == columns || y + height > page.height - page.margin {
+                column += 1;
+                if column >= columns {
+                    column = 0;
+                    pages.push(format!(
+                        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" viewBox=\"0 0 {w} {h}\">\n\
+                         <rect width=\"{w}\" height=\"{h}\" fill=\"white\"/>\n\
+                         <g font-family=\"{family}\" font-size=\"{size}\">\n",
+                        w = page.width,
+                        h = page.height,
+                        family = escape_xml(&family),
+                    ));
+                }
+                y = page.margin;
+                if pages.len() == 1 {
+                    y += title_height;
+                }
+            }
+            let svg = pages.last_mut().expect("a page was started above");
+            let x = page.margin + column as f32 * (column_width + gap);
+            let right = x + column_width;
+            y += height;
+            match line {
+                IndexLine::Heading(letter) => {
+                    *svg += &format!(
+                        "<text x=\"{}\" y=\"{}\" font-size=\"{}\" font-weight=\"bold\">{}</text>\n",
+                        x, y - line_height * 0.2, size * 1.6, escape_xml(&letter.to_string()),
+                    );
+                },
+                IndexLine::Entry { entry, wrapped } => {
+                    let font_style = match entry.kind {
+                        IndexKind::Street => "",
+                        IndexKind::Station => " font-weight=\"bold\"",
+                        IndexKind::Landmark => " font-style=\"italic\"",
+                    };
+                    *svg += &format!("<text x=\"{}\" y=\"{}\"{}>{}</text>\n", x, y, font_style, escape_xml(&entry.name));
+                    if !wrapped {
+                        *svg += &format!(
+                            "<text x=\"{}\" y=\"{}\" text-anchor=\"end\">{}</text>\n",
+                            right, y, entry.references.join(", "),
+                        );
+                    }
+                },
+                IndexLine::References(entry) => {
+                    *svg += &format!(
+                        "<text x=\"{}\" y=\"{}\" text-anchor=\"end\">{}</text>\n",
+                        right, y, entry.references.join(", "),
+                    );
+                },
+            }
+        }
+
+        if let Some(first) = pages.first_mut() {
+            *first += &format!(
+                "<text x=\"{}\" y=\"{}\" font-size=\"{}\" font-weight=\"bold\">{}</text>\n\
+                 <text x=\"{}\" y=\"{}\">Stations in bold, landmarks in italics.</text>\n",
+                page.margin, page.margin + size * 2.0, size * 2.0, escape_xml(&page.title),
+                page.margin, page.margin + size * 3.2,
+            );
+        }
+        for (idx, svg) in pages.into_iter().enumerate() {
+            fs::write(dir.join(format!("index_page_{}.svg", idx + 1)), svg + "</g>\n</svg>\n")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+use serde::Deserialize;
+
+use crate::data::semantic::{MapCoords, Path};
+
+/// How aggressively to generalize one class of features before drawing it. All sizes are
+/// in pixels at the render resolution, so the same settings work for previews and posters.
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct GeneralizationStyle {
+    /// Douglas–Peucker tolerance. Vertices closer than this to the simplified line are
+    /// dropped. 0 disables simplification.
+    #[serde(default)]
+    pub tolerance_px: f32,
+
+    /// Number of Chaikin corner-cutting passes applied after simplification.
+    #[serde(default)]
+    pub smoothing_iterations: u32,
+
+    /// Features whose bounding box is smaller than this in both dimensions are dropped.
+    #[serde(default)]
+    pub min_size_px: f32,
+
+    /// Rings enclosing less than this many square pixels are dropped. Only meaningful for
+    /// closed rings, 0 disables the check.
+    #[serde(default)]
+    pub min_area_px: f32,
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct Generalization {
+    #[serde(default)]
+    pub roads: GeneralizationStyle,
+
+    #[serde(default)]
+    pub rails: GeneralizationStyle,
+
+    #[serde(default)]
+    pub tube_rails: GeneralizationStyle,
+
+    #[serde(default)]
+    pub areas: GeneralizationStyle,
+
+    #[serde(default)]
+    pub waterways: GeneralizationStyle,
+
+    #[serde(default)]
+    pub buildings: GeneralizationStyle,
+
+    #[serde(default)]
+    pub boundaries: GeneralizationStyle,
+}
+
+fn segment_distance(p: (f32, f32), a: (f32, f32), b: (f32, f32)) -> f32 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let len_sq = dx * dx + dy * dy;
+    let t = if len_sq == 0.0 {
+        0.0
+    } else {
+        (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / len_sq).clamp(0.0, 1.0)
+    };
+    let (cx, cy) = (a.0 + t * dx, a.1 + t * dy);
+    ((p.0 - cx).powi(2) + (p.1 - cy).powi(2)).sqrt()
+}
+
+/// Shoelace formula. Positive for rings running clockwise on screen, where y points down.
+pub fn signed_area(points: &[(f32, f32)]) -> f32 {
+    points.iter()
+        .zip(points.iter().cycle().skip(1))
+        .map(|(a, b)| a.0 * b.1 - b.0 * a.1)
+        .sum::<f32>() / 2.0
+}
+
+/// Douglas–Peucker simplification. Returns the indices of the points to keep, which
+/// always include the first and last point.
+pub fn simplify_indices(points: &[(f32, f32)], tolerance: f32) -> Vec<usize> {
+    if points.len() < 3 || tolerance <= 0.0 {
+        return (0..points.len()).collect();
+    }
+
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+
+    let mut stack = vec![(0, points.len() - 1)];
+    while let Some((start, end)) = stack.pop() {
+        let mut max_distance = 0.0;
+        let mut max_idx = start;
+        for idx in start + 1..end {
+            let distance = segment_distance(points[idx], points[start], points[end]);
+            if distance > max_distance {
+                max_distance = distance;
+                max_idx = idx;
+            }
+        }
+        if max_distance > tolerance {
+            keep[max_idx] = true;
+            stack.push((start, max_idx));
+            stack.push((max_idx, end));
+        }
+    }
+
+    keep.iter()
+        .enumerate()
+        .filter(|(_, keep)| **keep)
+        .map(|(idx, _)| idx)
+        .collect()
+}
+
+fn lerp(a: &MapCoords, b: &MapCoords, t: f64) -> MapCoords {
+    MapCoords {
+        lat: a.lat + (b.lat - a.lat) * t,
+        lon: a.lon + (b.lon - a.lon) * t,
+    }
+}
+
+/// Chaikin corner cutting. Closed rings (first point equal to the last) stay closed, open
+/// paths keep their end points so that connecting lines still meet.
+pub fn smooth(path: &Path, iterations: u32) -> Path {
+    let mut path = path.clone();
+    for _ in 0..iterations {
+        if path.len() < 3 {
+            break;
+        }
+        let closed = path[0] == path[path.len() - 1];
+        let mut smoothed = Vec::with_capacity(path.len() * 2);
+        if !closed {
+            smoothed.push(path[0].clone());
+        }
+        for segment in path.windows(2) {
+            smoothed.push(lerp(&segment[0], &segment[1], 0.25));
+            smoothed.push(lerp(&segment[0], &segment[1], 0.75));
+        }
+        if closed {
+            smoothed.push(smoothed[0].clone());
+        } else {
+            smoothed.push(path[path.len() - 1].clone());
+        }
+        path = smoothed;
+    }
+    path
+}
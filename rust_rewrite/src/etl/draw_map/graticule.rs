@@ -0,0 +1,397 @@
+use raqote::{DrawOptions, DrawTarget, PathBuilder, Source, StrokeStyle};
+use serde::{de, Deserialize, Deserializer};
+
+use crate::data::semantic::MapCoords;
+
+use super::serialize_color::deserialize;
+use super::{DrawMapEtl, TextStyle};
+
+/// Points per grid line of projected grids, whose lines are slightly curved on the map.
+const PROJECTED_LINE_POINTS: usize = 16;
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GraticuleLines {
+    /// Meridians and parallels.
+    LatLon {
+        #[serde(deserialize_with = "deserialize_interval")]
+        interval_deg: f64,
+    },
+    /// Ordnance Survey National Grid, labelled in km.
+    Osgb {
+        #[serde(deserialize_with = "deserialize_interval")]
+        interval_m: f64,
+    },
+}
+
+/// Rejects intervals that `multiples` can't step through.
+fn deserialize_interval<'de, D>(deserializer: D) -> Result<f64, D::Error>
+    where D: Deserializer<'de> {
+    let interval = f64::deserialize(deserializer)?;
+    if !interval.is_finite() || interval <= 0.0 {
+        return Err(de::Error::invalid_value(de::Unexpected::Float(interval), &"a positive graticule interval"));
+    }
+    Ok(interval)
+}
+
+/// Lines of a geographic or projected grid over the whole map, with ticks and labels where
+/// they meet the map edges.
+#[derive(Deserialize)]
+pub struct GraticuleStyle<'a> {
+    pub lines: GraticuleLines,
+    #[serde(deserialize_with = "deserialize")]
+    pub color: Source<'a>,
+    #[serde(default = "default_graticule_width")]
+    pub width: f32,
+    #[serde(default)]
+    pub dash_array: Vec<f32>,
+    /// Only the ticks along the edges are drawn if this is unset.
+    #[serde(default = "default_draw_lines")]
+    pub draw_lines: bool,
+    /// In px.
+    #[serde(default = "default_tick_length")]
+    pub tick_length: f32,
+    pub label: TextStyle<'a>,
+}
+
+fn default_graticule_width() -> f32 {
+    1.5
+}
+
+fn default_draw_lines() -> bool {
+    true
+}
+
+fn default_tick_length() -> f32 {
+    30.0
+}
+
+/// A grid line in absolute pixel coordinates.
+pub struct GraticuleLine {
+    points: Vec<(f32, f32)>,
+    label: String,
+}
+
+#[derive(Clone, Copy)]
+enum Edge {
+    Top,
+    Bottom,
+    Left,
+    Right,
+}
+
+/// E.g. 51°30'N, with seconds only if there are any. ASCII quotes stand in for the prime
+/// signs, which the bundled fonts lack.
+fn format_angle(value: f64, positive: char, negative: char) -> String {
+    let seconds = (value.abs() * 3600.0).round() as u64;
+    let hemisphere = if value < 0.0 && seconds > 0 { negative } else { positive };
+    let (degrees, minutes, seconds) = (seconds / 3600, seconds % 3600 / 60, seconds % 60);
+    if seconds == 0 {
+        format!("{}°{:02}'{}", degrees, minutes, hemisphere)
+    } else {
+        format!("{}°{:02}'{:02}\"{}", degrees, minutes, seconds, hemisphere)
+    }
+}
+
+/// Multiples of `interval` from `min` to `max`.
+fn multiples(min: f64, max: f64, interval: f64) -> impl Iterator<Item = f64> {
+    let first = (min / interval).ceil() as i64;
+    let last = (max / interval).floor() as i64;
+    (first..=last).map(move |idx| idx as f64 * interval)
+}
+
+/// Conversion between WGS84 and the OS National Grid, following "A guide to coordinate
+/// systems in Great Britain" by Ordnance Survey. The Helmert transformation between the
+/// datums is accurate to a few metres.
+mod osgb {
+    struct Ellipsoid {
+        a: f64,
+        b: f64,
+    }
+
+    impl Ellipsoid {
+        fn e2(&self) -> f64 {
+            1.0 - (self.b * self.b) / (self.a * self.a)
+        }
+    }
+
+    const WGS84: Ellipsoid = Ellipsoid { a: 6_378_137.000, b: 6_356_752.314_1 };
+    const AIRY_1830: Ellipsoid = Ellipsoid { a: 6_377_563.396, b: 6_356_256.909 };
+
+    /// WGS84 to OSGB36 as translation in m, scale in ppm and rotation in arc seconds.
+    const HELMERT: [f64; 7] = [-446.448, 125.157, -542.060, 20.4894, -0.1502, -0.2470, -0.8421];
+
+    const F0: f64 = 0.9996012717;
+    const LAT0_DEG: f64 = 49.0;
+    const LON0_DEG: f64 = -2.0;
+    const E0: f64 = 400_000.0;
+    const N0: f64 = -100_000.0;
+
+    fn to_cartesian(lat: f64, lon: f64, ellipsoid: &Ellipsoid) -> [f64; 3] {
+        let (lat, lon) = (lat.to_radians(), lon.to_radians());
+        let nu = ellipsoid.a / (1.0 - ellipsoid.e2() * lat.sin().powi(2)).sqrt();
+        [
+            nu * lat.cos() * lon.cos(),
+            nu * lat.cos() * lon.sin(),
+            (1.0 - ellipsoid.e2()) * nu * lat.sin(),
+        ]
+    }
+
+    fn from_cartesian([x, y, z]: [f64; 3], ellipsoid: &Ellipsoid) -> (f64, f64) {
+        let e2 = ellipsoid.e2();
+        let p = x.hypot(y);
+        let mut lat = z.atan2(p * (1.0 - e2));
+        for _ in 0..10 {
+            let nu = ellipsoid.a / (1.0 - e2 * lat.sin().powi(2)).sqrt();
+            lat = (z + e2 * nu * lat.sin()).atan2(p);
+        }
+        (lat.to_degrees(), y.atan2(x).to_degrees())
+    }
+
+    /// Applies the Helmert transformation, or its approximate inverse with `sign` -1.
+    fn helmert([x, y, z]: [f64; 3], sign: f64) -> [f64; 3] {
+        let [tx, ty, tz, s, rx, ry, rz] = HELMERT.map(|param| param * sign);
+        let s = 1.0 + s * 1e-6;
+        let [rx, ry, rz] = [rx, ry, rz].map(|arc_seconds| (arc_seconds / 3600.0).to_radians());
+        [
+            tx + s * x - rz * y + ry * z,
+            ty + rz * x + s * y - rx * z,
+            tz - ry * x + rx * y + s * z,
+        ]
+    }
+
+    /// Meridional arc from the true origin to `lat`, in m.
+    fn meridional_arc(lat: f64) -> f64 {
+        let Ellipsoid { a, b } = AIRY_1830;
+        let n = (a - b) / (a + b);
+        let (n2, n3) = (n * n, n * n * n);
+        let lat0 = LAT0_DEG.to_radians();
+        let (d, s) = (lat - lat0, lat + lat0);
+        b * F0 * (
+            (1.0 + n + 1.25 * n2 + 1.25 * n3) * d
+            - (3.0 * n + 3.0 * n2 + 21.0 / 8.0 * n3) * d.sin() * s.cos()
+            + (15.0 / 8.0 * n2 + 15.0 / 8.0 * n3) * (2.0 * d).sin() * (2.0 * s).cos()
+            - 35.0 / 24.0 * n3 * (3.0 * d).sin() * (3.0 * s).cos()
+        )
+    }
+
+    /// Radii of curvature in the prime vertical and the meridian, scaled by F0.
+    fn radii(lat: f64) -> (f64, f64) {
+        let (a, e2) = (AIRY_1830.a, AIRY_1830.e2());
+        let denominator = 1.0 - e2 * lat.sin().powi(2);
+        (a * F0 / denominator.sqrt(), a * F0 * (1.0 - e2) / denominator.powf(1.5))
+    }
+
+    /// Easting and northing of a WGS84 position.
+    pub fn from_wgs84(lat: f64, lon: f64) -> (f64, f64) {
+        let (lat, lon) = from_cartesian(helmert(to_cartesian(lat, lon, &WGS84), 1.0), &AIRY_1830);
+        let (lat, dlon) = (lat.to_radians(), (lon - LON0_DEG).to_radians());
+        let (nu, rho) = radii(lat);
+        let eta2 = nu / rho - 1.0;
+        let (sin, cos, tan2) = (lat.sin(), lat.cos(), lat.tan().powi(2));
+
+        let i = meridional_arc(lat) + N0;
+        let ii = nu / 2.0 * sin * cos;
+        let iii = nu / 24.0 * sin * cos.powi(3) * (5.0 - tan2 + 9.0 * eta2);
+        let iiia = nu / 720.0 * sin * cos.powi(5) * (61.0 - 58.0 * tan2 + tan2 * tan2);
+        let iv = nu * cos;
+        let v = nu / 6.0 * cos.powi(3) * (nu / rho - tan2);
+        let vi = nu / 120.0 * cos.powi(5) * (5.0 - 18.0 * tan2 + tan2 * tan2 + 14.0 * eta2 - 58.0 * tan2 * eta2);
+
+        let northing = i + ii * dlon.powi(2) + iii * dlon.powi(4) + iiia * dlon.powi(6);
+        let easting = E0 + iv * dlon + v * dlon.powi(3) + vi * dlon.powi(5);
+        (easting, northing)
+    }
+
+    /// WGS84 latitude and longitude of a grid position.
+    pub fn to_wgs84(easting: f64, northing: f64) -> (f64, f64) {
+        let a = AIRY_1830.a;
+        let mut lat = (northing - N0) / (a * F0) + LAT0_DEG.to_radians();
+        // Converges to 0.01 mm within a few steps, capped in case rounding keeps it from
+        // getting there.
+        for _ in 0..10 {
+            let remainder = northing - N0 - meridional_arc(lat);
+            if remainder.abs() < 1e-5 {
+                break;
+            }
+            lat += remainder / (a * F0);
+        }
+
+        let (nu, rho) = radii(lat);
+        let eta2 = nu / rho - 1.0;
+        let (tan, sec) = (lat.tan(), 1.0 / lat.cos());
+        let tan2 = tan * tan;
+        let vii = tan / (2.0 * rho * nu);
+        let viii = tan / (24.0 * rho * nu.powi(3)) * (5.0 + 3.0 * tan2 + eta2 - 9.0 * tan2 * eta2);
+        let ix = tan / (720.0 * rho * nu.powi(5)) * (61.0 + 90.0 * tan2 + 45.0 * tan2 * tan2);
+        let x = sec / nu;
+        let xi = sec / (6.0 * nu.powi(3)) * (nu / rho + 2.0 * tan2);
+        let xii = sec / (120.0 * nu.powi(5)) * (5.0 + 28.0 * tan2 + 24.0 * tan2 * tan2);
+        let xiia = sec / (5040.0 * nu.powi(7)) * (61.0 + 662.0 * tan2 + 1320.0 * tan2 * tan2 + 720.0 * tan2.powi(3));
+
+        let de = easting - E0;
+        let lat = lat - vii * de.powi(2) + viii * de.powi(4) - ix * de.powi(6);
+        let lon = LON0_DEG.to_radians() + x * de - xi * de.powi(3) + xii * de.powi(5) - xiia * de.powi(7);
+        from_cartesian(helmert(to_cartesian(lat.to_degrees(), lon.to_degrees(), &AIRY_1830), -1.0), &WGS84)
+    }
+}
+
+impl<'a> DrawMapEtl<'a> {
+    /// Latitude and longitude of a point given in absolute pixel coordinates.
+    fn unproject_absolute(&self, x: f64, y: f64) -> MapCoords {
+        MapCoords {
+            lat: self.user_config.top_left_lat - y / self.user_config.px_per_deg_lat,
+            lon: self.user_config.top_left_lon + x / self.user_config.px_per_deg_lon,
+        }
+    }
+
+    pub(super) fn graticule_lines(&self) -> Vec<GraticuleLine> {
+        let Some(style) = &self.theme.graticule else {
+            return Vec::new();
+        };
+        let (width, height) = (self.user_config.width_px as f64, self.user_config.height_px as f64);
+        let top_left = self.unproject_absolute(0.0, 0.0);
+        let bottom_right = self.unproject_absolute(width, height);
+
+        match style.lines {
+            GraticuleLines::LatLon { interval_deg } => {
+                let meridians = multiples(top_left.lon, bottom_right.lon, interval_deg)
+                    .map(|lon| GraticuleLine {
+                        points: vec![
+                            self.project_absolute(&MapCoords { lat: top_left.lat, lon }),
+                            self.project_absolute(&MapCoords { lat: bottom_right.lat, lon }),
+                        ],
+                        label: format_angle(lon, 'E', 'W'),
+                    });
+                let parallels = multiples(bottom_right.lat, top_left.lat, interval_deg)
+                    .map(|lat| GraticuleLine {
+                        points: vec![
+                            self.project_absolute(&MapCoords { lat, lon: top_left.lon }),
+                            self.project_absolute(&MapCoords { lat, lon: bottom_right.lon }),
+                        ],
+                        label: format_angle(lat, 'N', 'S'),
+                    });
+                meridians.chain(parallels).collect()
+            },
+            GraticuleLines::Osgb { interval_m } => {
+                // The grid is rotated against the map, so the range is taken over all edges.
+                let edge_points = (0..=PROJECTED_LINE_POINTS).flat_map(|idx| {
+                    let t = idx as f64 / PROJECTED_LINE_POINTS as f64;
+                    [(t * width, 0.0), (t * width, height), (0.0, t * height), (width, t * height)]
+                });
+                let (mut min_e, mut min_n) = (f64::INFINITY, f64::INFINITY);
+                let (mut max_e, mut max_n) = (f64::NEG_INFINITY, f64::NEG_INFINITY);
+                for (x, y) in edge_points {
+                    let coords = self.unproject_absolute(x, y);
+                    let (easting, northing) = osgb::from_wgs84(coords.lat, coords.lon);
+                    (min_e, max_e) = (min_e.min(easting), max_e.max(easting));
+                    (min_n, max_n) = (min_n.min(northing), max_n.max(northing));
+                }
+
+                let line = |from: (f64, f64), to: (f64, f64), label: String| {
+                    let points = (0..=PROJECTED_LINE_POINTS)
+                        .map(|idx| {
+                            let t = idx as f64 / PROJECTED_LINE_POINTS as f64;
+                            let (lat, lon) = osgb::to_wgs84(
+                                from.0 + t * (to.0 - from.0),
+                                from.1 + t * (to.1 - from.1),
+                            );
+                            self.project_absolute(&MapCoords { lat, lon })
+                        })
+                        .collect();
+                    GraticuleLine { points, label }
+                };
+                let km = |m: f64| format!("{}", (m / 1000.0).round());
+                let eastings = multiples(min_e, max_e, interval_m)
+                    .map(|easting| line((easting, min_n), (easting, max_n), km(easting)));
+                let northings = multiples(min_n, max_n, interval_m)
+                    .map(|northing| line((min_e, northing), (max_e, northing), km(northing)));
+                eastings.chain(northings).collect()
+            },
+        }
+    }
+
+    /// Where `line` crosses each edge of the map.
+    fn edge_crossings(&self, line: &GraticuleLine) -> Vec<(Edge, f32, f32)> {
+        let (width, height) = (self.user_config.width_px as f32, self.user_config.height_px as f32);
+        let mut crossings = Vec::new();
+        for segment in line.points.windows(2) {
+            let ((x0, y0), (x1, y1)) = (segment[0], segment[1]);
+            for (edge, horizontal, value) in [
+                (Edge::Top, true, 0.0),
+                (Edge::Bottom, true, height),
+                (Edge::Left, false, 0.0),
+                (Edge::Right, false, width),
+            ] {
+                let (start, end) = if horizontal { (y0, y1) } else { (x0, x1) };
+                if start == end || (start - value) * (end - value) > 0.0 {
+                    continue;
+                }
+                let t = (value - start) / (end - start);
+                let (x, y) = (x0 + t * (x1 - x0), y0 + t * (y1 - y0));
+                // Lines through a corner would get labels hanging off the map.
+                let along = if horizontal { 0.0 < x && x < width } else { 0.0 < y && y < height };
+                // Crossings at a vertex are found by both of its segments.
+                let duplicate = crossings.last().is_some_and(|&(_, px, py): &(Edge, f32, f32)| {
+                    (px - x).abs() < 0.5 && (py - y).abs() < 0.5
+                });
+                if along && !duplicate {
+                    crossings.push((edge, x, y));
+                }
+            }
+        }
+        crossings
+    }
+
+    pub(super) fn draw_graticule(&self, dt: &mut DrawTarget, lines: &[GraticuleLine]) {
+        let Some(style) = &self.theme.graticule else {
+            return;
+        };
+        let draw_options = DrawOptions::new();
+        let shift = |(x, y): (f32, f32)| (x - self.x_shift, y - self.y_shift);
+
+        if style.draw_lines {
+            let mut pb = PathBuilder::new();
+            for line in lines {
+                let Some((first, rest)) = line.points.split_first() else {
+                    continue;
+                };
+                let (x0, y0) = shift(*first);
+                pb.move_to(x0, y0);
+                for point in rest {
+                    let (x, y) = shift(*point);
+                    pb.line_to(x, y);
+                }
+            }
+            let stroke = StrokeStyle {
+                dash_array: style.dash_array.clone(),
+                ..Self::stroke(style.width)
+            };
+            dt.stroke(&pb.finish(), &style.color, &stroke, &draw_options);
+        }
+
+        let tick = style.tick_length;
+        let gap = style.label.size * 0.3;
+        let mut ticks = PathBuilder::new();
+        for line in lines {
+            for (edge, x, y) in self.edge_crossings(line) {
+                // Ticks point into the map and labels follow them.
+                let (direction, align) = match edge {
+                    Edge::Top => ((0.0, 1.0), (0.5, 0.0)),
+                    Edge::Bottom => ((0.0, -1.0), (0.5, 1.0)),
+                    Edge::Left => ((1.0, 0.0), (0.0, 0.5)),
+                    Edge::Right => ((-1.0, 0.0), (1.0, 0.5)),
+                };
+                let (x0, y0) = shift((x, y));
+                ticks.move_to(x0, y0);
+                ticks.line_to(x0 + direction.0 * tick, y0 + direction.1 * tick);
+
+                let label_at = (x + direction.0 * (tick + gap), y + direction.1 * (tick + gap));
+                let (position, _) = self.align_text(&line.label, &style.label, label_at, align);
+                let (label_x, label_y) = shift(position);
+                self.draw_text(dt, label_x, label_y, &line.label, &style.label, &style.color);
+            }
+        }
+        dt.stroke(&ticks.finish(), &style.color, &Self::stroke(style.width * 2.0), &draw_options);
+    }
+}
@@ -0,0 +1,180 @@
+use std::{collections::HashMap, path::Path};
+
+use raqote::{BlendMode, DrawOptions, DrawTarget, Image, Source};
+use resvg::{tiny_skia, usvg};
+
+use crate::data::icons::{IconManifest, IconSpec};
+use crate::errors::Result;
+
+use super::spatial_index::BoundingBox;
+use super::{DrawMapEtl, OwnedImage, Theme};
+
+/// Icons the drawing code refers to by name rather than through the manifest's landmark
+/// categories.
+const STATION_ICONS: [&str; 4] = ["underground", "overground", "elizabeth_line", "dlr"];
+
+pub struct Icon {
+    image: OwnedImage,
+    width: f32,
+    height: f32,
+    anchor: (f32, f32),
+    label: String,
+}
+
+impl Icon {
+    /// Bounding box of the icon placed at (x, y).
+    pub fn bbox(&self, x: f32, y: f32) -> BoundingBox {
+        let min_x = x - self.anchor.0 * self.width;
+        let min_y = y - self.anchor.1 * self.height;
+        BoundingBox::new(min_x, min_y, min_x + self.width, min_y + self.height)
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn draw(&self, dt: &mut DrawTarget, x: f32, y: f32) {
+        let img = Image {
+            width: self.image.width,
+            height: self.image.height,
+            data: &self.image.data,
+        };
+
+        let mut draw_options = DrawOptions::new();
+        draw_options.blend_mode = BlendMode::SrcOver;
+
+        let bbox = self.bbox(x, y);
+        if self.image.width as f32 == self.width && self.image.height as f32 == self.height {
+            // Rasterized at the final size, so only whole pixel offsets keep it sharp.
+            dt.draw_image_at(bbox.min_x.round(), bbox.min_y.round(), &img, &draw_options);
+        } else {
+            dt.draw_image_with_size_at(
+                self.width,
+                self.height,
+                bbox.min_x,
+                bbox.min_y,
+                &img,
+                &draw_options,
+            );
+        }
+    }
+}
+
+/// Rasterizes images named with an `.svg` extension at the icon's size, keeping their
+/// aspect ratio, scaled to fit the icon's size and centered in it. Other images are
+/// loaded from `resources/images/{name}.png`.
+fn load_icon_image(spec: &IconSpec) -> Result<OwnedImage> {
+    if Path::new(&spec.image).extension().is_none_or(|extension| extension != "svg") {
+        return DrawMapEtl::load_image(&spec.image);
+    }
+    let svg_path = format!("resources/images/{}", spec.image);
+
+    let data = std::fs::read(&svg_path)?;
+    let tree = usvg::Tree::from_data(&data, &usvg::Options::default())
+        .map_err(|e| format!("Could not parse {}: {}", svg_path, e))?;
+    let width = spec.width.round().max(1.0) as u32;
+    let height = spec.height.round().max(1.0) as u32;
+    let mut pixmap = tiny_skia::Pixmap::new(width, height)
+        .ok_or(format!("Invalid icon size {}x{}", width, height))?;
+    let size = tree.size();
+    let scale = (width as f32 / size.width()).min(height as f32 / size.height());
+    let transform = tiny_skia::Transform::from_row(
+        scale,
+        0.0,
+        0.0,
+        scale,
+        (width as f32 - size.width() * scale) / 2.0,
+        (height as f32 - size.height() * scale) / 2.0,
+    );
+    resvg::render(&tree, transform, &mut pixmap.as_mut());
+
+    // tiny-skia uses premultiplied RGBA, raqote premultiplied ARGB in native endianness.
+    let data = pixmap.pixels().iter()
+        .map(|px| {
+            (px.alpha() as u32) << 24
+            | (px.red() as u32) << 16
+            | (px.green() as u32) << 8
+            | px.blue() as u32
+        })
+        .collect();
+    Ok(OwnedImage {
+        width: width.try_into()?,
+        height: height.try_into()?,
+        data,
+    })
+}
+
+/// Replaces the colour of every pixel by `color`, keeping its coverage.
+fn tint(image: &mut OwnedImage, color: &Source) -> Result<()> {
+    let Source::Solid(color) = color else {
+        return Err("Icon colours must be solid".into());
+    };
+    let scale = |channel: u8, alpha: u32| (channel as u32 * alpha / 255) & 0xff;
+    for px in image.data.iter_mut() {
+        let alpha = *px >> 24;
+        *px = scale(color.a, alpha) << 24
+            | scale(color.r, alpha) << 16
+            | scale(color.g, alpha) << 8
+            | scale(color.b, alpha);
+    }
+    Ok(())
+}
+
+/// All icons of the manifest, loaded once up front.
+pub struct IconRegistry {
+    icons: HashMap<String, Icon>,
+}
+
+impl IconRegistry {
+    /// Fails listing every problem at once, e.g. all image files that couldn't be loaded
+    /// and all categories without an icon.
+    pub fn load(manifest: &IconManifest, theme: &Theme) -> Result<IconRegistry> {
+        let mut problems = Vec::new();
+
+        let required = STATION_ICONS.into_iter()
+            .chain(manifest.landmarks.iter().map(|landmark| landmark.category.as_str()));
+        for name in required {
+            if !manifest.icons.contains_key(name) {
+                problems.push(format!("no icon for {}", name));
+            }
+        }
+
+        for name in theme.icons.keys() {
+            if !manifest.icons.contains_key(name) {
+                problems.push(format!("theme colours unknown icon {}", name));
+            }
+        }
+
+        let mut icons = HashMap::new();
+        for (name, spec) in &manifest.icons {
+            let image = load_icon_image(spec).and_then(|mut image| {
+                if let Some(style) = theme.icons.get(name) {
+                    tint(&mut image, &style.color)?;
+                }
+                Ok(image)
+            });
+            match image {
+                Ok(image) => {
+                    icons.insert(name.clone(), Icon {
+                        image,
+                        width: spec.width,
+                        height: spec.height,
+                        anchor: spec.anchor,
+                        label: spec.label.clone().unwrap_or_else(|| name.clone()),
+                    });
+                },
+                Err(e) => problems.push(format!("icon {} ({}): {}", name, spec.image, e.message)),
+            }
+        }
+
+        if !problems.is_empty() {
+            problems.sort();
+            return Err(format!("Invalid icon manifest: {}", problems.join("; ")).into());
+        }
+        Ok(IconRegistry { icons })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Icon> {
+        self.icons.get(name)
+    }
+}
@@ -0,0 +1,164 @@
+use super::spatial_index::{BoundingBox, GridIndex};
+
+/// Greedy label placement. Labels are offered in order of importance and each one takes
+/// the first of its candidate positions that doesn't overlap anything placed before it.
+/// Placement happens once for the whole map, so labels never get cut at cell borders.
+pub struct LabelPlacer {
+    occupied: GridIndex,
+    count: usize,
+}
+
+impl LabelPlacer {
+    pub fn new(bucket_size: f32) -> Self {
+        LabelPlacer {
+            occupied: GridIndex::new(bucket_size),
+            count: 0,
+        }
+    }
+
+    /// Marks `bbox` as taken, e.g. by a station symbol that labels must not cover.
+    pub fn block(&mut self, bbox: BoundingBox) {
+        self.occupied.insert(self.count, Some(bbox));
+        self.count += 1;
+    }
+
+    pub fn is_free(&self, bbox: &BoundingBox) -> bool {
+        self.occupied.query(bbox).is_empty()
+    }
+
+    /// Returns the index of the first free candidate and marks it as taken, or `None` if
+    /// every candidate collides.
+    pub fn try_place(&mut self, candidates: &[BoundingBox]) -> Option<usize> {
+        let idx = candidates.iter().position(|candidate| self.is_free(candidate))?;
+        self.block(candidates[idx]);
+        Some(idx)
+    }
+}
+
+/// Origin and baseline direction of one glyph of a label that follows a line.
+#[derive(Debug, Clone, Copy)]
+pub struct GlyphPlacement {
+    pub x: f32,
+    pub y: f32,
+    /// Clockwise from the x axis, in radians.
+    pub angle: f32,
+}
+
+pub fn path_length(points: &[(f32, f32)]) -> f32 {
+    points.windows(2)
+        .map(|pair| (pair[1].0 - pair[0].0).hypot(pair[1].1 - pair[0].1))
+        .sum()
+}
+
+/// Point at `distance` along the polyline, clamped to its ends.
+fn point_at(points: &[(f32, f32)], distance: f32) -> (f32, f32) {
+    let mut remaining = distance.max(0.0);
+    for pair in points.windows(2) {
+        let length = (pair[1].0 - pair[0].0).hypot(pair[1].1 - pair[0].1);
+        if remaining <= length && length > 0.0 {
+            let t = remaining / length;
+            return (pair[0].0 + t * (pair[1].0 - pair[0].0), pair[0].1 + t * (pair[1].1 - pair[0].1));
+        }
+        remaining -= length;
+    }
+    points[points.len() - 1]
+}
+
+/// Lays out glyphs along `points`, starting `start` px from the beginning of the line.
+/// `offsets` are the glyph origins along the baseline and `width` the total advance, as
+/// returned by the text layout. Each glyph is turned to follow the chord to the next glyph.
+///
+/// Returns `None` if the text doesn't fit on the line or the line turns by more than
+/// `max_bend` radians between two glyphs, which would make the text hard to read.
+pub fn place_along_path(
+    points: &[(f32, f32)],
+    start: f32,
+    offsets: &[f32],
+    width: f32,
+    max_bend: f32,
+) -> Option<Vec<GlyphPlacement>> {
+    if points.len() < 2 || start < 0.0 || start + width > path_length(points) {
+        return None;
+    }
+    let ends = offsets.iter().skip(1).copied().chain(std::iter::once(width));
+    let mut placements: Vec<GlyphPlacement> = Vec::with_capacity(offsets.len());
+    for (offset, end) in offsets.iter().zip(ends) {
+        let (x, y) = point_at(points, start + offset);
+        let (end_x, end_y) = point_at(points, start + end);
+        let angle = if (end_x - x).hypot(end_y - y) > f32::EPSILON {
+            (end_y - y).atan2(end_x - x)
+        } else {
+            placements.last().map_or(0.0, |previous| previous.angle)
+        };
+        if let Some(previous) = placements.last() {
+            let bend = (angle - previous.angle + std::f32::consts::PI)
+                .rem_euclid(2.0 * std::f32::consts::PI) - std::f32::consts::PI;
+            if bend.abs() > max_bend {
+                return None;
+            }
+        }
+        placements.push(GlyphPlacement { x, y, angle });
+    }
+    Some(placements)
+}
+
+/// Splits words into lines no wider than `max_width`, using as few lines as possible and
+/// then making the lines as even as possible. `widths` are the widths of the words and
+/// `gap` the width added between two words on the same line. With at most `max_lines`
+/// lines, the widest line may end up wider than `max_width`.
+///
+/// Returns the index of the first word of each line.
+pub fn wrap_words(widths: &[f32], gap: f32, max_width: f32, max_lines: usize) -> Vec<usize> {
+    let max_lines = max_lines.clamp(1, widths.len().max(1));
+    let mut starts = vec![0];
+    for lines in 1..=max_lines {
+        let (balanced, widest) = balanced_breaks(widths, gap, lines);
+        starts = balanced;
+        if widest <= max_width {
+            break;
+        }
+    }
+    starts
+}
+
+/// Breaks words into exactly `lines` lines, minimizing the width of the widest line.
+/// Returns the first word of each line and the width of the widest line.
+fn balanced_breaks(widths: &[f32], gap: f32, lines: usize) -> (Vec<usize>, f32) {
+    let count = widths.len();
+    if count == 0 || lines <= 1 {
+        let width = widths.iter().sum::<f32>() + gap * count.saturating_sub(1) as f32;
+        return (vec![0], width);
+    }
+    let line_width = |start: usize, end: usize| {
+        widths[start..end].iter().sum::<f32>() + gap * (end - start - 1) as f32
+    };
+
+    // widest[k][end]: smallest possible widest line when the first `end` words take k + 1
+    // lines. first[k][end]: first word of the last of those lines.
+    let mut widest = vec![vec![f32::INFINITY; count + 1]; lines];
+    let mut first = vec![vec![0; count + 1]; lines];
+    for (end, width) in widest[0].iter_mut().enumerate().skip(1) {
+        *width = line_width(0, end);
+    }
+    for k in 1..lines {
+        for end in (k + 1)..=count {
+            for start in k..end {
+                let width = widest[k - 1][start].max(line_width(start, end));
+                if width < widest[k][end] {
+                    widest[k][end] = width;
+                    first[k][end] = start;
+                }
+            }
+        }
+    }
+
+    let mut starts = Vec::with_capacity(lines);
+    let mut end = count;
+    for k in (1..lines).rev() {
+        end = first[k][end];
+        starts.push(end);
+    }
+    starts.push(0);
+    starts.reverse();
+    (starts, widest[lines - 1][count])
+}
@@ -0,0 +1,120 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::data::semantic::{MapCoords, TransitLineId, TubeRail};
+
+/// Undirected track segment between two consecutive nodes, stored with its end points in
+/// a canonical order so that rails running in opposite directions share the same key.
+#[derive(Hash, PartialEq, Eq)]
+struct SegmentKey(MapCoords, MapCoords);
+
+impl SegmentKey {
+    /// Returns the key and whether `a -> b` runs against the canonical direction.
+    fn new(a: &MapCoords, b: &MapCoords) -> (Self, bool) {
+        if (a.lat, a.lon) <= (b.lat, b.lon) {
+            (SegmentKey(a.clone(), b.clone()), false)
+        } else {
+            (SegmentKey(b.clone(), a.clone()), true)
+        }
+    }
+}
+
+/// Knows which lines run along each piece of track, so that lines sharing track can be
+/// drawn side by side like on the official tube map. Lines are always ordered the same
+/// way, which keeps them from swapping sides between consecutive segments.
+pub struct LineBundles {
+    segments: HashMap<SegmentKey, BTreeSet<TransitLineId>>,
+}
+
+impl LineBundles {
+    pub fn new(tube_rails: &[TubeRail]) -> Self {
+        let mut segments: HashMap<SegmentKey, BTreeSet<TransitLineId>> = HashMap::new();
+        for rail in tube_rails {
+            for segment in rail.path.windows(2) {
+                let (key, _) = SegmentKey::new(&segment[0], &segment[1]);
+                segments.entry(key).or_default().insert(rail.line);
+            }
+        }
+        LineBundles { segments }
+    }
+
+    /// Largest number of lines sharing a single segment.
+    pub fn max_bundle_size(&self) -> usize {
+        self.segments.values().map(|lines| lines.len()).max().unwrap_or(0)
+    }
+
+    /// Signed offset of `line` from the track center, in multiples of the line spacing,
+    /// measured along the normal of the canonical segment direction.
+    fn slot(&self, key: &SegmentKey, line: &TransitLineId) -> f32 {
+        match self.segments.get(key) {
+            Some(lines) => {
+                let position = lines.iter().position(|other| other == line).unwrap_or(0);
+                position as f32 - (lines.len() - 1) as f32 / 2.0
+            },
+            None => 0.0,
+        }
+    }
+
+    /// Offsets the projected points of `rail` so that it runs parallel to the other lines
+    /// sharing its track, `spacing` pixels apart. `points` must be the projection of
+    /// `rail.path`. Consecutive offset segments are joined at the intersection of their
+    /// offset lines, or by a short jog where the number of lines changes.
+    pub fn offset_path(&self, rail: &TubeRail, points: &[(f32, f32)], spacing: f32) -> Vec<(f32, f32)> {
+        if points.len() < 2 {
+            return points.to_vec();
+        }
+
+        // Direction and offset vector of every segment, in the rail's own direction.
+        let mut directions = Vec::with_capacity(points.len() - 1);
+        let mut offsets = Vec::with_capacity(points.len() - 1);
+        for (idx, segment) in points.windows(2).enumerate() {
+            let (key, reversed) = SegmentKey::new(&rail.path[idx], &rail.path[idx + 1]);
+            let (dx, dy) = (segment[1].0 - segment[0].0, segment[1].1 - segment[0].1);
+            let len = (dx * dx + dy * dy).sqrt();
+            let (ux, uy) = if len == 0.0 { (0.0, 0.0) } else { (dx / len, dy / len) };
+            // The normal is taken relative to the canonical direction, so that lines keep
+            // their side regardless of which way the way was mapped.
+            let sign = if reversed { -1.0 } else { 1.0 };
+            let distance = self.slot(&key, &rail.line) * spacing * sign;
+            directions.push((ux, uy));
+            offsets.push((-uy * distance, ux * distance));
+        }
+
+        let mut offset_points = Vec::with_capacity(points.len());
+        offset_points.push((points[0].0 + offsets[0].0, points[0].1 + offsets[0].1));
+        for idx in 1..points.len() - 1 {
+            let point = points[idx];
+            let (o1, o2) = (offsets[idx - 1], offsets[idx]);
+            let (d1, d2) = (directions[idx - 1], directions[idx]);
+
+            // Intersect (point + o1 + s * d1) with (point + o2 + t * d2).
+            let cross = d1.0 * d2.1 - d1.1 * d2.0;
+            let (wx, wy) = (o2.0 - o1.0, o2.1 - o1.1);
+            let max_offset = (o1.0.hypot(o1.1)).max(o2.0.hypot(o2.1));
+            let joined = if cross.abs() > 1e-3 {
+                let s = (wx * d2.1 - wy * d2.0) / cross;
+                let (jx, jy) = (o1.0 + s * d1.0, o1.1 + s * d1.1);
+                // Very sharp corners would produce a far-away spike, jog instead.
+                if jx.hypot(jy) <= 4.0 * max_offset.max(spacing) {
+                    Some((point.0 + jx, point.1 + jy))
+                } else {
+                    None
+                }
+            } else if wx.hypot(wy) < 1e-3 {
+                Some((point.0 + o1.0, point.1 + o1.1))
+            } else {
+                None
+            };
+
+            match joined {
+                Some(joined) => offset_points.push(joined),
+                None => {
+                    offset_points.push((point.0 + o1.0, point.1 + o1.1));
+                    offset_points.push((point.0 + o2.0, point.1 + o2.1));
+                },
+            }
+        }
+        let last = points.len() - 1;
+        offset_points.push((points[last].0 + offsets[last - 1].0, points[last].1 + offsets[last - 1].1));
+        offset_points
+    }
+}
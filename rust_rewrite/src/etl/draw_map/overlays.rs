@@ -0,0 +1,326 @@
+use std::path::Path;
+
+use raqote::{DrawOptions, DrawTarget, PathBuilder, Source, StrokeStyle, Winding};
+use serde::Deserialize;
+
+use crate::data::overlays::{load_overlay, OverlayFeature, OverlayGeometry};
+use crate::data::semantic::pole_of_inaccessibility;
+use crate::errors::Result;
+
+use super::labels::path_length;
+use super::serialize_color::{deserialize, deserialize_optional};
+use super::spatial_index::{BoundingBox, GridIndex};
+use super::{place_upright, DrawMapEtl, IconRegistry, PathLabel, PlacedLabel, ShapedText, TextStyle, INDEX_BUCKET_SIZE};
+
+/// Labels of overlay polygons are placed at their pole of inaccessibility, to within this.
+const OVERLAY_LABEL_PRECISION_M: f64 = 1.0;
+
+/// Built-in layers of the map, in drawing order. Overlays are drawn on top of one of them.
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MapLayer {
+    Background,
+    Areas,
+    Waterways,
+    Buildings,
+    CouncilBoundaries,
+    Roads,
+    Rails,
+    TubeRails,
+    PlaceLabels,
+    WaterwayLabels,
+    Councils,
+    Stations,
+    #[default]
+    Landmarks,
+}
+
+/// A GeoJSON, GPX or KML file drawn on top of the map, e.g. a walking tour.
+#[derive(Deserialize)]
+pub struct OverlayLayer<'a> {
+    /// Relative to the repository root, like `data_path`. The format follows the extension:
+    /// `.geojson` or `.json`, `.gpx` or `.kml`.
+    pub path: String,
+    /// Overlays on top of the same layer are drawn in the order they are listed.
+    #[serde(default)]
+    pub above: MapLayer,
+    /// Lines, polygon outlines and the outline of point markers. Nothing is stroked if unset.
+    #[serde(default)]
+    pub stroke: Option<OverlayStroke<'a>>,
+    /// Polygons and point markers.
+    #[serde(default, deserialize_with = "deserialize_optional")]
+    pub fill: Option<Source<'a>>,
+    /// Icon of the icon manifest drawn at points instead of a marker.
+    #[serde(default)]
+    pub icon: Option<String>,
+    /// Radius of the marker drawn at points without an icon.
+    #[serde(default = "default_marker_radius")]
+    pub marker_radius: f32,
+    #[serde(default)]
+    pub label: Option<OverlayLabel<'a>>,
+}
+
+#[derive(Deserialize)]
+pub struct OverlayStroke<'a> {
+    #[serde(deserialize_with = "deserialize")]
+    pub color: Source<'a>,
+    #[serde(default = "default_overlay_width")]
+    pub width: f32,
+    #[serde(default)]
+    pub dash_array: Vec<f32>,
+}
+
+/// Labels points below their marker, polygons at their pole of inaccessibility and lines
+/// along the line, at its middle, a quarter from either end or else on its longest segment.
+#[derive(Deserialize)]
+pub struct OverlayLabel<'a> {
+    /// Property holding the label text, e.g. `name`. Features without it are not labelled.
+    pub property: String,
+    pub style: TextStyle<'a>,
+}
+
+fn default_marker_radius() -> f32 {
+    10.0
+}
+
+fn default_overlay_width() -> f32 {
+    6.0
+}
+
+/// Features of all overlay layers, loaded once up front.
+pub struct Overlays {
+    /// By layer, in the order of the config.
+    features: Vec<Vec<OverlayFeature>>,
+}
+
+impl Overlays {
+    /// Fails listing every problem at once, e.g. all files that couldn't be read and all
+    /// unknown icons.
+    pub fn load(layers: &[OverlayLayer], icons: &IconRegistry) -> Result<Overlays> {
+        let mut problems = Vec::new();
+        let mut features = Vec::new();
+        for layer in layers {
+            if let Some(icon) = &layer.icon {
+                if icons.get(icon).is_none() {
+                    problems.push(format!("overlay {}: unknown icon {}", layer.path, icon));
+                }
+            }
+            match load_overlay(&Path::new("..").join(&layer.path)) {
+                Ok(layer_features) => features.push(layer_features),
+                Err(e) => problems.push(format!("overlay {}: {}", layer.path, e.message)),
+            }
+        }
+
+        if !problems.is_empty() {
+            return Err(format!("Invalid overlays: {}", problems.join("; ")).into());
+        }
+        Ok(Overlays { features })
+    }
+}
+
+/// An overlay feature in absolute pixel coordinates.
+enum OverlayShape {
+    Point((f32, f32)),
+    Line(Vec<(f32, f32)>),
+    Polygon(Vec<Vec<(f32, f32)>>),
+}
+
+enum OverlayLabelPlacement<'a> {
+    Text(PlacedLabel<'a>),
+    Path(PathLabel<'a>),
+}
+
+/// An overlay layer ready to be drawn cell by cell.
+pub struct OverlayLayout<'a> {
+    style: &'a OverlayLayer<'a>,
+    shapes: Vec<OverlayShape>,
+    shape_index: GridIndex,
+    labels: Vec<OverlayLabelPlacement<'a>>,
+    label_index: GridIndex,
+}
+
+fn points_path(pb: &mut PathBuilder, points: &[(f32, f32)], (dx, dy): (f32, f32)) {
+    let Some(((x0, y0), rest)) = points.split_first() else {
+        return;
+    };
+    pb.move_to(x0 - dx, y0 - dy);
+    for (x, y) in rest {
+        pb.line_to(x - dx, y - dy);
+    }
+}
+
+impl<'a> DrawMapEtl<'a> {
+    /// Bounding box of the icon or marker drawn at (x, y).
+    fn overlay_point_bbox(&self, style: &OverlayLayer, (x, y): (f32, f32)) -> BoundingBox {
+        match style.icon.as_deref().and_then(|name| self.icons.get(name)) {
+            Some(icon) => icon.bbox(x, y),
+            None => {
+                let stroke_width = style.stroke.as_ref().map_or(0.0, |stroke| stroke.width);
+                BoundingBox::new(x, y, x, y).grow(style.marker_radius + stroke_width / 2.0)
+            },
+        }
+    }
+
+    fn overlay_shape_bbox(&self, style: &OverlayLayer, shape: &OverlayShape) -> Option<BoundingBox> {
+        let margin = style.stroke.as_ref().map_or(0.0, |stroke| stroke.width / 2.0);
+        match shape {
+            OverlayShape::Point(point) => Some(self.overlay_point_bbox(style, *point)),
+            OverlayShape::Line(points) => BoundingBox::from_points(points.iter().copied())
+                .map(|bbox| bbox.grow(margin)),
+            OverlayShape::Polygon(rings) => BoundingBox::from_points(rings.first()?.iter().copied())
+                .map(|bbox| bbox.grow(margin)),
+        }
+    }
+
+    fn place_overlay_label(&self, style: &'a TextStyle<'a>, feature: &OverlayFeature, shape: &OverlayShape, bbox: &BoundingBox, text: &str) -> Option<OverlayLabelPlacement<'a>> {
+        let (x, y) = match shape {
+            // Just below the marker.
+            OverlayShape::Point(_) => {
+                let ((x, y), _) = self.align_text(text, style, ((bbox.min_x + bbox.max_x) / 2.0, bbox.max_y), (0.5, 0.0));
+                (x, y)
+            },
+            OverlayShape::Line(points) => {
+                let ShapedText { glyphs: shaped, width, .. } = self.layout_text(text, style);
+                let offsets: Vec<f32> = shaped.iter().map(|glyph| glyph.x).collect();
+                let longest_segment = points.windows(2)
+                    .max_by(|a, b| path_length(a).total_cmp(&path_length(b)))?;
+                let glyphs = [0.5, 0.25, 0.75].into_iter()
+                    .find_map(|fraction| place_upright(points, fraction, &offsets, width))
+                    .or_else(|| place_upright(longest_segment, 0.5, &offsets, width))?;
+                let bbox = BoundingBox::from_points(glyphs.iter().map(|glyph| (glyph.x, glyph.y)))?
+                    .grow(style.size * 0.75 + style.halo_width());
+                return Some(OverlayLabelPlacement::Path(PathLabel { shaped, glyphs, style, bbox }));
+            },
+            // Centred inside the polygon, where the middle of a concave one may lie outside.
+            OverlayShape::Polygon(_) => {
+                let pole = match &feature.geometry {
+                    OverlayGeometry::Polygon(rings) => pole_of_inaccessibility(rings, OVERLAY_LABEL_PRECISION_M),
+                    _ => None,
+                };
+                let center = pole.map_or(((bbox.min_x + bbox.max_x) / 2.0, (bbox.min_y + bbox.max_y) / 2.0), |pole| {
+                    self.project_absolute(&pole)
+                });
+                let ((x, y), _) = self.align_text(text, style, center, (0.5, 0.5));
+                (x, y)
+            },
+        };
+        Some(OverlayLabelPlacement::Text(PlacedLabel { text: text.to_string(), style, x, y }))
+    }
+
+    /// Projects all overlay features and places their labels. Labels are always drawn, even
+    /// if they overlap the map's own labels.
+    pub(super) fn layout_overlays(&self) -> Vec<OverlayLayout<'a>> {
+        let project = |path: &[_]| path.iter().map(|coords| self.project_absolute(coords)).collect();
+        self.user_config.overlays.iter()
+            .zip(&self.overlays.features)
+            .map(|(style, features)| {
+                let shapes: Vec<OverlayShape> = features.iter()
+                    .map(|feature| match &feature.geometry {
+                        OverlayGeometry::Point(coords) => OverlayShape::Point(self.project_absolute(coords)),
+                        OverlayGeometry::Line(path) => OverlayShape::Line(project(path)),
+                        OverlayGeometry::Polygon(rings) => OverlayShape::Polygon(
+                            rings.iter().map(|ring| project(ring)).collect()
+                        ),
+                    })
+                    .collect();
+                let shape_index = GridIndex::build(INDEX_BUCKET_SIZE, &shapes, |shape| {
+                    self.overlay_shape_bbox(style, shape)
+                });
+
+                let mut labels = Vec::new();
+                if let Some(label) = &style.label {
+                    for (feature, shape) in features.iter().zip(&shapes) {
+                        let Some(text) = feature.properties.get(&label.property) else {
+                            continue;
+                        };
+                        let Some(bbox) = self.overlay_shape_bbox(style, shape) else {
+                            continue;
+                        };
+                        labels.extend(self.place_overlay_label(&label.style, feature, shape, &bbox, text));
+                    }
+                }
+                let label_index = GridIndex::build(INDEX_BUCKET_SIZE, &labels, |label| match label {
+                    OverlayLabelPlacement::Text(label) => Some(self.text_bbox(label.x, label.y, &label.text, label.style)),
+                    OverlayLabelPlacement::Path(label) => Some(label.bbox),
+                });
+
+                OverlayLayout { style, shapes, shape_index, labels, label_index }
+            })
+            .collect()
+    }
+
+    fn draw_overlay_shape(&self, dt: &mut DrawTarget, style: &OverlayLayer, shape: &OverlayShape) {
+        let draw_options = DrawOptions::new();
+        let shift = (self.x_shift, self.y_shift);
+        let stroke_style = style.stroke.as_ref().map(|stroke| StrokeStyle {
+            dash_array: stroke.dash_array.clone(),
+            ..Self::stroke(stroke.width)
+        });
+        match shape {
+            OverlayShape::Point((x, y)) => {
+                let (x, y) = (x - self.x_shift, y - self.y_shift);
+                if let Some(icon) = style.icon.as_deref().and_then(|name| self.icons.get(name)) {
+                    icon.draw(dt, x, y);
+                    return;
+                }
+                let mut pb = PathBuilder::new();
+                pb.arc(x, y, style.marker_radius, 0.0, std::f32::consts::TAU);
+                pb.close();
+                let marker = pb.finish();
+                if let Some(fill) = &style.fill {
+                    dt.fill(&marker, fill, &draw_options);
+                }
+                if let (Some(stroke), Some(stroke_style)) = (&style.stroke, &stroke_style) {
+                    dt.stroke(&marker, &stroke.color, stroke_style, &draw_options);
+                }
+            },
+            OverlayShape::Line(points) => {
+                let (Some(stroke), Some(stroke_style)) = (&style.stroke, &stroke_style) else {
+                    return;
+                };
+                let mut pb = PathBuilder::new();
+                points_path(&mut pb, points, shift);
+                dt.stroke(&pb.finish(), &stroke.color, stroke_style, &draw_options);
+            },
+            // Holes are left out by the even-odd rule, as for areas.
+            OverlayShape::Polygon(rings) => {
+                let mut pb = PathBuilder::new();
+                for ring in rings {
+                    points_path(&mut pb, ring, shift);
+                    pb.close();
+                }
+                let mut path = pb.finish();
+                path.winding = Winding::EvenOdd;
+                if let Some(fill) = &style.fill {
+                    dt.fill(&path, fill, &draw_options);
+                }
+                if let (Some(stroke), Some(stroke_style)) = (&style.stroke, &stroke_style) {
+                    dt.stroke(&path, &stroke.color, stroke_style, &draw_options);
+                }
+            },
+        }
+    }
+
+    /// Draws the overlays on top of `layer` that intersect the cell, labels after all
+    /// shapes of an overlay.
+    pub(super) fn draw_overlays(&self, dt: &mut DrawTarget, overlays: &[OverlayLayout], layer: MapLayer, cell_bbox: &BoundingBox) {
+        for overlay in overlays.iter().filter(|overlay| overlay.style.above == layer) {
+            for idx in overlay.shape_index.query(cell_bbox) {
+                self.draw_overlay_shape(dt, overlay.style, &overlay.shapes[idx]);
+            }
+            for idx in overlay.label_index.query(cell_bbox) {
+                match &overlay.labels[idx] {
+                    OverlayLabelPlacement::Text(label) => self.draw_text(
+                        dt,
+                        label.x - self.x_shift,
+                        label.y - self.y_shift,
+                        &label.text,
+                        label.style,
+                        &self.theme.text_color,
+                    ),
+                    OverlayLabelPlacement::Path(label) => self.draw_path_label(dt, label, &self.theme.text_color),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+
+use raqote::{Path, PathBuilder, Source};
+use serde::Deserialize;
+
+use crate::errors::Result;
+
+use super::serialize_color::deserialize;
+use super::spatial_index::BoundingBox;
+use super::{DrawMapEtl, OwnedImage, Theme};
+
+/// Repeating fill drawn over an area's colour. Patterns are laid out on a grid anchored at
+/// the top left of the whole map rather than of the cell, so they continue seamlessly
+/// across cell borders.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Pattern<'a> {
+    /// Parallel lines `spacing` px apart.
+    Hatch {
+        #[serde(deserialize_with = "deserialize")]
+        color: Source<'a>,
+        spacing: f32,
+        /// Clockwise from horizontal.
+        #[serde(default)]
+        angle_deg: f32,
+        #[serde(default = "default_line_width")]
+        width: f32,
+    },
+    /// Dots on a staggered grid.
+    Dots {
+        #[serde(deserialize_with = "deserialize")]
+        color: Source<'a>,
+        spacing: f32,
+        radius: f32,
+    },
+    /// Small line drawings on a staggered grid.
+    Symbols {
+        #[serde(deserialize_with = "deserialize")]
+        color: Source<'a>,
+        symbol: PatternSymbol,
+        spacing: f32,
+        size: f32,
+        #[serde(default = "default_line_width")]
+        width: f32,
+    },
+    /// Tiled PNG from `resources/images`, named without the extension.
+    Texture {
+        image: String,
+    },
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum PatternSymbol {
+    Tree,
+    Marsh,
+}
+
+fn default_line_width() -> f32 {
+    1.5
+}
+
+/// Images of all texture patterns of the theme, loaded once up front.
+pub struct Textures {
+    images: HashMap<String, OwnedImage>,
+}
+
+impl Textures {
+    /// Fails listing every texture that couldn't be loaded, e.g. missing files or PNGs in
+    /// unsupported formats.
+    pub fn load(theme: &Theme) -> Result<Textures> {
+        let mut problems = Vec::new();
+        let mut images = HashMap::new();
+        for style in theme.areas.values() {
+            let Some(Pattern::Texture { image }) = &style.pattern else {
+                continue;
+            };
+            if images.contains_key(image) {
+                continue;
+            }
+            match DrawMapEtl::load_image(image) {
+                Ok(texture) => {
+                    images.insert(image.clone(), texture);
+                },
+                Err(e) => problems.push(format!("texture {}: {}", image, e.message)),
+            }
+        }
+
+        if !problems.is_empty() {
+            problems.sort();
+            problems.dedup();
+            return Err(format!("Invalid theme: {}", problems.join("; ")).into());
+        }
+        Ok(Textures { images })
+    }
+
+    pub fn get(&self, image: &str) -> Option<&OwnedImage> {
+        self.images.get(image)
+    }
+}
+
+/// Hatch lines covering `bbox`, which is in absolute pixel coordinates. The lines are
+/// returned in cell coordinates, i.e. moved by `-shift`.
+pub fn hatch_path(bbox: &BoundingBox, shift: (f32, f32), spacing: f32, angle_deg: f32) -> Path {
+    let (sin, cos) = angle_deg.to_radians().sin_cos();
+    let (direction, normal) = ((cos, sin), (-sin, cos));
+    let corners = [
+        (bbox.min_x, bbox.min_y),
+        (bbox.max_x, bbox.min_y),
+        (bbox.min_x, bbox.max_y),
+        (bbox.max_x, bbox.max_y),
+    ];
+    let project = |axis: (f32, f32)| {
+        corners.iter()
+            .map(|corner| corner.0 * axis.0 + corner.1 * axis.1)
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), t| (min.min(t), max.max(t)))
+    };
+    let (min_offset, max_offset) = project(normal);
+    let (min_along, max_along) = project(direction);
+
+    let mut pb = PathBuilder::new();
+    if spacing > 0.0 {
+        let first = (min_offset / spacing).ceil() as i64;
+        let last = (max_offset / spacing).floor() as i64;
+        for line in first..=last {
+            let offset = line as f32 * spacing;
+            let point = |along: f32| {
+                (
+                    offset * normal.0 + along * direction.0 - shift.0,
+                    offset * normal.1 + along * direction.1 - shift.1,
+                )
+            };
+            let (x0, y0) = point(min_along);
+            let (x1, y1) = point(max_along);
+            pb.move_to(x0, y0);
+            pb.line_to(x1, y1);
+        }
+    }
+    pb.finish()
+}
+
+/// Points of a staggered grid within `bbox`, in cell coordinates. Every other row is
+/// moved by half the spacing, which looks less mechanical than a square grid.
+pub fn grid_points(bbox: &BoundingBox, shift: (f32, f32), spacing: f32) -> Vec<(f32, f32)> {
+    if spacing <= 0.0 {
+        return Vec::new();
+    }
+    let mut points = Vec::new();
+    let first_row = (bbox.min_y / spacing).floor() as i64;
+    let last_row = (bbox.max_y / spacing).ceil() as i64;
+    let first_column = (bbox.min_x / spacing).floor() as i64 - 1;
+    let last_column = (bbox.max_x / spacing).ceil() as i64;
+    for row in first_row..=last_row {
+        let stagger = if row.rem_euclid(2) == 1 { spacing / 2.0 } else { 0.0 };
+        for column in first_column..=last_column {
+            points.push((
+                column as f32 * spacing + stagger - shift.0,
+                row as f32 * spacing - shift.1,
+            ));
+        }
+    }
+    points
+}
+
+pub fn push_dot(pb: &mut PathBuilder, (x, y): (f32, f32), radius: f32) {
+    pb.move_to(x + radius, y);
+    pb.arc(x, y, radius, 0.0, 2.0 * std::f32::consts::PI);
+    pb.close();
+}
+
+/// Adds the outline of `symbol`, centered on (x, y), for stroking.
+pub fn push_symbol(pb: &mut PathBuilder, symbol: PatternSymbol, (x, y): (f32, f32), size: f32) {
+    match symbol {
+        PatternSymbol::Tree => {
+            // A round crown on a short trunk.
+            let radius = size * 0.3;
+            let crown_y = y - size * 0.2;
+            pb.move_to(x + radius, crown_y);
+            pb.arc(x, crown_y, radius, 0.0, 2.0 * std::f32::consts::PI);
+            pb.close();
+            pb.move_to(x, crown_y + radius);
+            pb.line_to(x, y + size * 0.5);
+        }
+        PatternSymbol::Marsh => {
+            // Tufts of grass over a line of water.
+            let half = size * 0.5;
+            pb.move_to(x - half, y + size * 0.25);
+            pb.line_to(x + half, y + size * 0.25);
+            for dx in [-0.3, 0.0, 0.3] {
+                let height = if dx == 0.0 { size * 0.6 } else { size * 0.4 };
+                pb.move_to(x + dx * size, y + size * 0.1);
+                pb.line_to(x + dx * size, y + size * 0.1 - height);
+            }
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+
+/// Axis-aligned bounding box in absolute (unshifted) pixel coordinates.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingBox {
+    pub min_x: f32,
+    pub min_y: f32,
+    pub max_x: f32,
+    pub max_y: f32,
+}
+
+impl BoundingBox {
+    pub fn new(min_x: f32, min_y: f32, max_x: f32, max_y: f32) -> Self {
+        BoundingBox { min_x, min_y, max_x, max_y }
+    }
+
+    /// Smallest box containing all points, or `None` if there are no points.
+    pub fn from_points<I: IntoIterator<Item = (f32, f32)>>(points: I) -> Option<Self> {
+        let mut points = points.into_iter();
+        let (x0, y0) = points.next()?;
+        let mut bbox = BoundingBox::new(x0, y0, x0, y0);
+        for (x, y) in points {
+            bbox.min_x = bbox.min_x.min(x);
+            bbox.min_y = bbox.min_y.min(y);
+            bbox.max_x = bbox.max_x.max(x);
+            bbox.max_y = bbox.max_y.max(y);
+        }
+        Some(bbox)
+    }
+
+    /// Box grown by `margin` pixels on every side, e.g. to account for stroke width.
+    pub fn grow(&self, margin: f32) -> Self {
+        BoundingBox::new(
+            self.min_x - margin,
+            self.min_y - margin,
+            self.max_x + margin,
+            self.max_y + margin,
+        )
+    }
+
+    pub fn width(&self) -> f32 {
+        self.max_x - self.min_x
+    }
+
+    pub fn height(&self) -> f32 {
+        self.max_y - self.min_y
+    }
+
+    pub fn union(&self, other: &BoundingBox) -> Self {
+        BoundingBox::new(
+            self.min_x.min(other.min_x),
+            self.min_y.min(other.min_y),
+            self.max_x.max(other.max_x),
+            self.max_y.max(other.max_y),
+        )
+    }
+
+    pub fn intersects(&self, other: &BoundingBox) -> bool {
+        self.min_x <= other.max_x
+            && other.min_x <= self.max_x
+            && self.min_y <= other.max_y
+            && other.min_y <= self.max_y
+    }
+}
+
+/// Uniform grid over feature bounding boxes. Features are referred to by their index in
+/// the collection they were inserted from, so a render cell can look up which features
+/// it actually needs to draw instead of letting raqote clip everything.
+pub struct GridIndex {
+    bucket_size: f32,
+    buckets: HashMap<(i64, i64), Vec<usize>>,
+    boxes: Vec<Option<BoundingBox>>,
+}
+
+impl GridIndex {
+    pub fn new(bucket_size: f32) -> Self {
+        GridIndex {
+            bucket_size,
+            buckets: HashMap::new(),
+            boxes: Vec::new(),
+        }
+    }
+
+    /// Builds an index over `items`, using `bbox_fn` to compute the (already grown)
+    /// bounding box of each item. Items without a bounding box are never returned.
+    pub fn build<T, F>(bucket_size: f32, items: &[T], bbox_fn: F) -> Self
+    where F: Fn(&T) -> Option<BoundingBox> {
+        let mut index = GridIndex::new(bucket_size);
+        for (id, item) in items.iter().enumerate() {
+            index.insert(id, bbox_fn(item));
+        }
+        index
+    }
+
+    fn bucket_range(&self, bbox: &BoundingBox) -> (i64, i64, i64, i64) {
+        (
+            (bbox.min_x / self.bucket_size).floor() as i64,
+            (bbox.min_y / self.bucket_size).floor() as i64,
+            (bbox.max_x / self.bucket_size).floor() as i64,
+            (bbox.max_y / self.bucket_size).floor() as i64,
+        )
+    }
+
+    pub fn insert(&mut self, id: usize, bbox: Option<BoundingBox>) {
+        if self.boxes.len() <= id {
+            self.boxes.resize(id + 1, None);
+        }
+        self.boxes[id] = bbox;
+
+        if let Some(bbox) = bbox {
+            let (bx0, by0, bx1, by1) = self.bucket_range(&bbox);
+            for bx in bx0..=bx1 {
+                for by in by0..=by1 {
+                    self.buckets.entry((bx, by)).or_default().push(id);
+                }
+            }
+        }
+    }
+
+    /// Ids of all features whose bounding box intersects `bbox`, in ascending order so
+    /// that the original draw order is preserved.
+    pub fn query(&self, bbox: &BoundingBox) -> Vec<usize> {
+        let (bx0, by0, bx1, by1) = self.bucket_range(bbox);
+        let mut ids = Vec::new();
+        for bx in bx0..=bx1 {
+            for by in by0..=by1 {
+                if let Some(bucket) = self.buckets.get(&(bx, by)) {
+                    ids.extend(bucket.iter().copied().filter(|id| {
+                        self.boxes[*id].is_some_and(|item_bbox| item_bbox.intersects(bbox))
+                    }));
+                }
+            }
+        }
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+}
@@ -0,0 +1,138 @@
+use std::{fs, rc::Rc, sync::Arc};
+
+use rustybuzz::{ttf_parser::Tag, Face, Feature, UnicodeBuffer};
+
+use crate::errors::Result;
+
+use super::fk;
+
+/// A font file, shaped with rustybuzz and drawn with font-kit.
+pub struct LoadedFont {
+    /// Parsed once, as shaping is done for every label.
+    face: Face<'static>,
+    font: fk::Font,
+    /// Read when loading, as glyph outlines are scaled by it for every glyph drawn.
+    units_per_em: f32,
+}
+
+impl LoadedFont {
+    pub fn load(path: &str) -> Result<LoadedFont> {
+        let data = Arc::new(
+            fs::read(path).map_err(|e| format!("Could not read font {}: {}", path, e))?
+        );
+        // Fonts are loaded once and used until the program exits, so the face can
+        // borrow the data for good.
+        let leaked: &'static Arc<Vec<u8>> = Box::leak(Box::new(data.clone()));
+        let face = Face::from_slice(leaked, 0)
+            .ok_or(format!("Could not parse font {}", path))?;
+        let units_per_em = face.units_per_em() as f32;
+        let font = fk::Font::from_bytes(data, 0)
+            .map_err(|e| format!("Could not load font {}: {}", path, e))?;
+        Ok(LoadedFont { face, font, units_per_em })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ShapedGlyph {
+    /// Index of the font in the chain.
+    pub font: usize,
+    pub id: u32,
+    /// Origin of the glyph relative to the start of the baseline, in px.
+    pub x: f32,
+    pub y: f32,
+}
+
+pub struct ShapedText {
+    pub glyphs: Vec<ShapedGlyph>,
+    /// Total advance, in px.
+    pub width: f32,
+    /// Extent of the first font above and below the baseline, in px.
+    pub ascent: f32,
+    pub descent: f32,
+}
+
+/// A font followed by the fonts used in turn for characters it has no glyph for.
+pub struct FontChain {
+    fonts: Vec<Rc<LoadedFont>>,
+}
+
+/// Characters shaped with the font of the preceding character, e.g. combining accents.
+fn follows_previous(c: char) -> bool {
+    c.is_whitespace()
+        || matches!(c, '\u{200c}' | '\u{200d}' | '\u{fe00}'..='\u{fe0f}')
+        || matches!(c, '\u{0300}'..='\u{036f}' | '\u{1ab0}'..='\u{1aff}' | '\u{1dc0}'..='\u{1dff}' | '\u{20d0}'..='\u{20ff}' | '\u{fe20}'..='\u{fe2f}')
+}
+
+impl FontChain {
+    pub fn new(fonts: Vec<Rc<LoadedFont>>) -> FontChain {
+        assert!(!fonts.is_empty(), "a font chain needs at least one font");
+        FontChain { fonts }
+    }
+
+    pub fn font(&self, idx: usize) -> &fk::Font {
+        &self.fonts[idx].font
+    }
+
+    /// Font units per em of the font at `idx`.
+    pub fn units_per_em(&self, idx: usize) -> f32 {
+        self.fonts[idx].units_per_em
+    }
+
+    /// Shapes `text` at `size` px per em, adding `letter_spacing` em after each character.
+    /// Characters no font of the chain has are drawn with the first font's missing glyph.
+    pub fn shape(&self, text: &str, size: f32, letter_spacing: f32) -> ShapedText {
+        // Runs of text drawn with the same font, as (font, start, end) byte ranges.
+        let mut runs: Vec<(usize, usize, usize)> = Vec::new();
+        for (idx, c) in text.char_indices() {
+            let font = match runs.last() {
+                Some(&(previous, _, _)) if follows_previous(c) => previous,
+                _ => self.fonts.iter().position(|font| font.face.glyph_index(c).is_some()).unwrap_or(0),
+            };
+            match runs.last_mut() {
+                Some((previous, _, end)) if *previous == font => *end = idx + c.len_utf8(),
+                _ => runs.push((font, idx, idx + c.len_utf8())),
+            }
+        }
+
+        // Ligatures would hide the spacing between their letters.
+        let features = if letter_spacing != 0.0 {
+            vec![Feature::new(Tag::from_bytes(b"liga"), 0, ..)]
+        } else {
+            Vec::new()
+        };
+        let spacing = size * letter_spacing;
+        let mut glyphs = Vec::new();
+        let mut pen = 0.0;
+        for (font, start, end) in runs {
+            let face = &self.fonts[font].face;
+            let scale = size / self.units_per_em(font);
+            let mut buffer = UnicodeBuffer::new();
+            buffer.push_str(&text[start..end]);
+            buffer.guess_segment_properties();
+            let shaped = rustybuzz::shape(face, &features, buffer);
+            for (info, position) in shaped.glyph_infos().iter().zip(shaped.glyph_positions()) {
+                glyphs.push(ShapedGlyph {
+                    font,
+                    id: info.glyph_id,
+                    x: pen + position.x_offset as f32 * scale,
+                    y: -position.y_offset as f32 * scale,
+                });
+                pen += position.x_advance as f32 * scale;
+                // Marks have no advance and stay on their base character.
+                if position.x_advance != 0 {
+                    pen += spacing;
+                }
+            }
+        }
+        let width = if glyphs.is_empty() { 0.0 } else { pen - spacing };
+
+        let primary = &self.fonts[0].face;
+        let scale = size / self.units_per_em(0);
+        ShapedText {
+            glyphs,
+            width,
+            ascent: primary.ascender() as f32 * scale,
+            descent: -(primary.descender() as f32) * scale,
+        }
+    }
+}
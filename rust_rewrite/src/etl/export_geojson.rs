@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+
+use crate::data::osm::OsmElementRef;
+use crate::data::semantic::{self, ring_contains, MapCoords, SemanticMapElements, TransitLine, TransitLineId};
+use crate::errors::Result;
+
+use super::{artifact, semantic_map, Etl};
+use super::stats::EtlStats;
+
+pub const ETL_NAME: &str = "export_geojson";
+pub const OUTPUT_DIR_NAME: &str = "geojson";
+
+/// Writes each collection of the semantic map to its own GeoJSON file, so that what was
+/// extracted from OSM can be inspected in e.g. QGIS.
+pub struct ExportGeoJsonEtl {
+    stats: EtlStats,
+}
+
+/// GeoJSON positions are longitude first.
+fn position(coords: &MapCoords) -> Value {
+    json!([coords.lon, coords.lat])
+}
+
+fn positions(path: &[MapCoords]) -> Value {
+    path.iter().map(position).collect()
+}
+
+fn point(lat: f64, lon: f64) -> Value {
+    json!({ "type": "Point", "coordinates": [lon, lat] })
+}
+
+fn line_string(path: &[MapCoords]) -> Value {
+    json!({ "type": "LineString", "coordinates": positions(path) })
+}
+
+fn is_closed(ring: &[MapCoords]) -> bool {
+    !ring.is_empty() && ring.first() == ring.last()
+}
+
+/// GeoJSON linear rings repeat their first position at the end.
+fn linear_ring(ring: &[MapCoords]) -> Value {
+    let mut coordinates: Vec<Value> = ring.iter().map(position).collect();
+    if !is_closed(ring) {
+        coordinates.push(position(&ring[0]));
+    }
+    Value::Array(coordinates)
+}
+
+/// Turns rings combined with the even-odd rule into a MultiPolygon. A ring inside an odd
+/// number of other rings is a hole of the innermost of them. Rings that have fewer than
+/// four positions once closed enclose nothing and are dropped, as RFC 7946 requires.
+fn multi_polygon(rings: &[semantic::Path]) -> Value {
+    let rings: Vec<&semantic::Path> = rings.iter()
+        .filter(|ring| ring.len() + usize::from(!is_closed(ring)) >= 4)
+        .collect();
+    let containing: Vec<Vec<usize>> = rings.iter().enumerate()
+        .map(|(idx, ring)| (0..rings.len())
+            .filter(|other| *other != idx && ring_contains(rings[*other], &ring[0]))
+            .collect())
+        .collect();
+
+    let mut polygons: Vec<Vec<Value>> = Vec::new();
+    let mut polygon_of_outer = HashMap::new();
+    for (idx, ring) in rings.iter().enumerate() {
+        if containing[idx].len().is_multiple_of(2) {
+            polygon_of_outer.insert(idx, polygons.len());
+            polygons.push(vec![linear_ring(ring)]);
+        }
+    }
+    for (idx, ring) in rings.iter().enumerate() {
+        if containing[idx].len().is_multiple_of(2) {
+            continue;
+        }
+        // The innermost containing ring is contained by all the others.
+        let outer = containing[idx].iter()
+            .max_by_key(|other| containing[**other].len())
+            .and_then(|other| polygon_of_outer.get(other));
+        match outer {
+            Some(polygon) => polygons[*polygon].push(linear_ring(ring)),
+            None => polygons.push(vec![linear_ring(ring)]),
+        }
+    }
+    json!({ "type": "MultiPolygon", "coordinates": polygons })
+}
+
+fn feature(geometry: Value, properties: Value) -> Value {
+    json!({ "type": "Feature", "geometry": geometry, "properties": properties })
+}
+
+fn transit_line_properties(id: &TransitLineId, line: Option<&TransitLine>) -> Value {
+    match line {
+        Some(line) => json!({
+            "line": line.name,
+            "line_id": id,
+            "ref": line.reference,
+            "colour": line.colour,
+            "network": line.network,
+            "mode": format!("{:?}", line.mode),
+        }),
+        None => json!({ "line_id": id }),
+    }
+}
+
+impl ExportGeoJsonEtl {
+    pub fn new() -> ExportGeoJsonEtl {
+        ExportGeoJsonEtl {
+            stats: EtlStats::default(),
+        }
+    }
+
+    fn output_path(dir: &Path) -> PathBuf {
+        dir.join(OUTPUT_DIR_NAME)
+    }
+
+    fn extract_semantic_map_elements(&self, dir: &Path) -> Result<SemanticMapElements> {
+        artifact::read(&dir.join(semantic_map::OUTPUT_FILE_NAME))
+    }
+}
+
+impl Etl for ExportGeoJsonEtl {
+    type Input = SemanticMapElements;
+
+    /// Features by collection name.
+    type Output = Vec<(&'static str, Vec<Value>)>;
+
+    fn etl_name(&self) -> &str {
+        ETL_NAME
+    }
+
+    /// Exporting is quick, so the files are always rewritten rather than risking stale ones
+    /// after the semantic map was rebuilt.
+    fn is_cached(&self, _dir: &Path) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn clean(&self, dir: &Path) -> Result<()> {
+        fs::remove_dir_all(Self::output_path(dir))?;
+        Ok(())
+    }
+
+    fn extract(&mut self, dir: &Path) -> Result<Self::Input> {
+        self.extract_semantic_map_elements(dir)
+    }
+
+    fn transform(&mut self, input: Self::Input) -> Result<Self::Output> {
+        let line_names = |lines: &[TransitLineId]| -> Vec<String> {
+            lines.iter()
+                .filter_map(|id| input.transit_lines.get(id))
+                .map(|line| line.name.clone())
+                .collect()
+        };
+
+        let stations = input.underground_stations.iter()
+            .map(|station| feature(point(station.lat, station.lon), json!({
+                "name": station.name,
+                "type": format!("{:?}", station.station_type),
+                "network": station.network,
+                "lines": line_names(&station.lines),
+                "interchange": station.is_interchange(),
+                "osm_elements": station.osm_elements.iter().map(OsmElementRef::to_string).collect::<Vec<_>>(),
+            })))
+            .collect();
+        let rails = input.rails.iter()
+            .map(|rail| feature(line_string(rail), json!({})))
+            .collect();
+        let roads = input.roads.iter()
+            .map(|road| feature(line_string(&road.path), json!({ "name": road.name })))
+            .collect();
+        let areas = input.areas.iter()
+            .map(|area| feature(multi_polygon(&area.area_polygons), json!({
+                "type": format!("{:?}", area.area_type),
+            })))
+            .collect();
+        let waterways = input.waterways.iter()
+            .map(|waterway| feature(line_string(&waterway.path), json!({
+                "name": waterway.name,
+                "type": format!("{:?}", waterway.waterway_type),
+            })))
+            .collect();
+        let buildings = input.buildings.iter()
+            .map(|building| feature(multi_polygon(&building.polygons), json!({
+                "height_m": building.height_m,
+                "levels": building.levels,
+            })))
+            .collect();
+        let landmarks = input.landmarks.iter()
+            .map(|landmark| feature(point(landmark.lat, landmark.lon), json!({
+                "name": landmark.name,
+                "category": landmark.category,
+            })))
+            .collect();
+        let tube_rails = input.tube_rails.iter()
+            .map(|rail| feature(
+                line_string(&rail.path),
+                transit_line_properties(&rail.line, input.transit_lines.get(&rail.line)),
+            ))
+            .collect();
+        let councils = input.councils.iter()
+            .map(|council| feature(multi_polygon(&council.boundary), json!({
+                "name": council.name,
+                "label_lat": council.center.lat,
+                "label_lon": council.center.lon,
+            })))
+            .collect();
+        let places = input.places.iter()
+            .map(|place| feature(point(place.center.lat, place.center.lon), json!({
+                "name": place.name,
+                "type": format!("{:?}", place.place_type),
+                "population": place.population,
+            })))
+            .collect();
+
+        let collections: Self::Output = vec![
+            ("stations", stations),
+            ("rails", rails),
+            ("roads", roads),
+            ("areas", areas),
+            ("waterways", waterways),
+            ("buildings", buildings),
+            ("landmarks", landmarks),
+            ("tube_rails", tube_rails),
+            ("councils", councils),
+            ("places", places),
+        ];
+        for (name, features) in &collections {
+            self.stats.count(name, features.len());
+        }
+        Ok(collections)
+    }
+
+    fn load(&mut self, dir: &Path, output: Self::Output) -> Result<()> {
+        let output_dir = Self::output_path(dir);
+        fs::create_dir_all(&output_dir)?;
+        for (name, features) in output {
+            let path = output_dir.join(format!("{}.geojson", name));
+            let writer = BufWriter::new(File::create(&path)?);
+            let collection = json!({ "type": "FeatureCollection", "features": features });
+            serde_json::to_writer(writer, &collection)
+                .map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+        }
+        Ok(())
+    }
+
+    fn stats(&mut self) -> &mut EtlStats {
+        &mut self.stats
+    }
+}
@@ -1,6 +1,6 @@
 use std::collections::HashMap;
-use std::fs::{self, File};
-use std::io::{BufRead, BufReader, Write};
+use std::fs;
+use std::io::{BufRead, BufReader};
 use std::path::{Path, PathBuf};
 use std::str::{self, FromStr};
 
@@ -10,12 +10,14 @@ use quick_xml::reader::Reader;
 use xz::bufread::XzDecoder;
 
 use crate::{errors, UserConfig};
-use crate::data::osm::{OsmMapData, Node, Way, Relation};
+use crate::data::osm::{Bounds, OsmMapData, Node, Way, Relation};
 use crate::errors::{Error, Result};
-use crate::etl::Etl;
+use crate::etl::{artifact, Etl};
+use crate::etl::stats::EtlStats;
 
 pub const ETL_NAME: &str = "parse_osm";
-pub const OUTPUT_FILE_NAME: &str = "osm_elements.rkyv";
+/// Versioned by the layout of `OsmMapData`, bump it when that changes.
+pub const OUTPUT_FILE_NAME: &str = "osm_elements.v3.rkyv";
 
 
 #[derive(Debug, PartialEq)]
@@ -33,10 +35,13 @@ pub struct ParseOsmEtl<'a> {
     nodes: HashMap<u64, Node>,
     ways: HashMap<u64, Way>,
     relations: HashMap<u64, Relation>,
+    bounds: Option<Bounds>,
 
     current_node: Node,
     current_way: Way,
     current_relation: Relation,
+
+    stats: EtlStats,
 }
 
 impl ParseOsmEtl<'_> {
@@ -152,6 +157,14 @@ impl ParseOsmEtl<'_> {
                 self.state = ParserState::Relation;
                 self.current_relation = ParseOsmEtl::parse_relation(e)?;
             },
+            b"bounds" => {
+                self.bounds = Some(Bounds {
+                    min_lon: ParseOsmEtl::parse_attr(e, b"minlon")?,
+                    min_lat: ParseOsmEtl::parse_attr(e, b"minlat")?,
+                    max_lon: ParseOsmEtl::parse_attr(e, b"maxlon")?,
+                    max_lat: ParseOsmEtl::parse_attr(e, b"maxlat")?,
+                });
+            },
             b"nd" => {
                 if self.state != ParserState::Way {
                     return Err(format!("Got <nd> element in state {:?}", self.state).into())
@@ -162,6 +175,7 @@ impl ParseOsmEtl<'_> {
                     self.current_way.nodes.push(node.clone());
                 } else {
                     warn!("Reference to undefined node id {:?} while in state {:?}.", id, self.state);
+                    self.stats.dropped("undefined node in way", 1);
                 }
             },
             b"member" => {
@@ -170,7 +184,24 @@ impl ParseOsmEtl<'_> {
                 }
 
                 let member_type = ParseOsmEtl::get_attr(e, b"type")?;
+                if b"relation" == member_type.as_slice() {
+                    self.current_relation.relations.push(ParseOsmEtl::parse_attr(e, b"ref")?);
+                    return Ok(())
+                }
+                if b"node" == member_type.as_slice() {
+                    let role = ParseOsmEtl::get_attr(e, b"role").unwrap_or_default();
+                    let id = ParseOsmEtl::parse_attr(e, b"ref")?;
+                    if let Some(node) = self.nodes.get(&id) {
+                        self.current_relation.nodes.push(node.clone());
+                        self.current_relation.node_roles.push(role);
+                    } else {
+                        warn!("Reference to undefined node id {:?} while in state {:?}.", id, self.state);
+                        self.stats.dropped("undefined node in relation", 1);
+                    }
+                    return Ok(())
+                }
                 if b"way" != member_type.as_slice() {
+                    self.stats.dropped("relation member of unknown type", 1);
                     return Ok(())
                 }
 
@@ -224,10 +255,13 @@ impl ParseOsmEtl<'_> {
             nodes: HashMap::default(),
             ways: HashMap::default(),
             relations: HashMap::default(),
+            bounds: None,
 
             current_node: Node::default(),
             current_way: Way::default(),
             current_relation: Relation::default(),
+
+            stats: EtlStats::default(),
         }
     }
 }
@@ -274,7 +308,7 @@ impl Etl for ParseOsmEtl<'_> {
             buf.clear();
         };
 
-        Ok(OsmMapData {
+        let output = OsmMapData {
             // Don't store nodes without tags, as we won't want to draw them
             nodes: self.nodes.iter()
                 .filter(|(_id, node)| !node.tags.is_empty())
@@ -282,13 +316,20 @@ impl Etl for ParseOsmEtl<'_> {
                 .collect(),
             ways: self.ways.clone(),
             relations: self.relations.clone(),
-        })
+            bounds: self.bounds.or_else(|| Bounds::of_nodes(self.nodes.values())),
+        };
+        self.stats.count("nodes", output.nodes.len());
+        self.stats.dropped("untagged node", self.nodes.len() - output.nodes.len());
+        self.stats.count("ways", output.ways.len());
+        self.stats.count("relations", output.relations.len());
+        Ok(output)
     }
 
     fn load(&mut self, dir: &Path, output: Self::Output) -> Result<()> {
-        let mut output_file = File::create(Self::output_path(dir))?;
-        let bytes = rkyv::to_bytes::<_, 256>(&output).unwrap();
-        output_file.write_all(&bytes)?;
-        Ok(())
+        artifact::write(&Self::output_path(dir), &output)
+    }
+
+    fn stats(&mut self) -> &mut EtlStats {
+        &mut self.stats
     }
 }
@@ -1,31 +1,170 @@
-use std::{str, collections::HashMap, fs::{self, File}, io::{Read, Write}, path::{Path, PathBuf}};
+use std::{borrow::Cow, str, collections::{HashMap, HashSet}, fs, path::{Path, PathBuf}};
 
 use crate::{
     data::{
-        osm::{Node, OsmId, OsmMapData, Relation, Way},
+        icons::IconManifest,
+        osm::{Node, OsmElementRef, OsmId, OsmMapData, Relation, Way},
         semantic::{
-            Area, AreaType, Council, Landmark, LandmarkType, MapCoords, SemanticMapElements, TransportStation, TransportStationType, TubeLine, TubeRail
+            self, pole_of_inaccessibility, Area, Building, AreaType, Council, Landmark, MapCoords, Place, PlaceType, Road, SegmentGrid, SemanticMapElements, TransitLine, TransitLineId, TransitMode, TransportStation, TransportStationType, TubeRail, Waterway, WaterwayType
         }
     },
     errors::Result,
 };
 use crate::etl::parse_osm;
 
-use super::Etl;
+use super::{artifact, Etl};
+use super::stats::EtlStats;
 use quick_xml::escape::unescape;
 use regex::Regex;
 
+mod coastline;
+mod explain;
+
 pub const ETL_NAME: &str = "semantic_map";
-pub const OUTPUT_FILE_NAME: &str = "semantic_map.rkyv";
+/// Versioned by the layout of `SemanticMapElements`, bump it when that changes.
+pub const OUTPUT_FILE_NAME: &str = "semantic_map.v1.rkyv";
+/// Records what else the output was built from, see `cache_key`.
+const CACHE_KEY_FILE_NAME: &str = "semantic_map.key";
+
+/// Stations without route information are served by lines passing this close.
+const STATION_LINE_DISTANCE_M: f64 = 100.0;
+/// Stations with the same name and network closer than this are the same station.
+const STATION_MERGE_DISTANCE_M: f64 = 500.0;
+/// Merged elements closer than this describe the same part of a station, like a station
+/// node, the area around it and its stop area. Only parts further apart get their own
+/// interchange ring.
+const STATION_PART_DISTANCE_M: f64 = 150.0;
+/// How close the council label position has to be to the true pole of inaccessibility.
+const COUNCIL_LABEL_PRECISION_M: f64 = 10.0;
+/// Councils left off the map.
+const EXCLUDED_COUNCILS: [&str; 3] = [
+    "London Borough of Richmond upon Thames",
+    "Bexley Council",
+    "Barking and Dagenham Council",
+];
+
+/// A tag looked for by a classification rule, as key and value. The value `*` matches any
+/// value.
+pub type TagRule = (&'static str, &'static str);
+
+const STATION_TAGS: [TagRule; 4] = [
+    ("railway", "station"),
+    ("railway", "halt"),
+    ("public_transport", "station"),
+    ("aerialway", "station"),
+];
+
+/// Checked in order, a station gets the type of the first entry it has any tag of. The
+/// network is more reliable than the mode tags, so it goes first.
+const STATION_TYPE_RULES: [(TransportStationType, &[TagRule]); 8] = [
+    (TransportStationType::Underground, &[("network", "London Underground")]),
+    (TransportStationType::Dlr, &[("network", "Docklands Light Railway")]),
+    (TransportStationType::Overground, &[("network", "London Overground")]),
+    (TransportStationType::ElizabethLine, &[("network", "Elizabeth Line"), ("network", "Elizabeth line")]),
+    (TransportStationType::CableCar, &[("aerialway", "station"), ("station", "aerialway")]),
+    (TransportStationType::Tram, &[("station", "tram"), ("station", "light_rail"), ("tram", "yes"), ("light_rail", "yes")]),
+    (TransportStationType::Underground, &[("station", "subway"), ("subway", "yes")]),
+    (TransportStationType::NationalRail, &[("station", "train"), ("train", "yes"), ("railway", "station"), ("railway", "halt")]),
+];
+
+const PLACE_TYPE_RULES: [(PlaceType, &[TagRule]); 5] = [
+    (PlaceType::Town, &[("place", "town")]),
+    (PlaceType::Suburb, &[("place", "suburb")]),
+    (PlaceType::Village, &[("place", "village")]),
+    (PlaceType::Quarter, &[("place", "quarter")]),
+    (PlaceType::Neighbourhood, &[("place", "neighbourhood")]),
+];
+
+/// Checked in order, so e.g. a wood tagged as a park is drawn as wood.
+const AREA_TYPE_RULES: [(AreaType, &[TagRule]); 17] = [
+    (AreaType::Wood, &[("leisure", "wood"), ("landuse", "forest"), ("natural", "wood")]),
+    (AreaType::Park, &[("leisure", "park"), ("landuse", "grass"), ("natural", "heath"), ("leisure", "garden")]),
+    (AreaType::Water, &[
+        ("water", "*"),
+        ("natural", "water"),
+        ("waterway", "riverbank"),
+        ("waterway", "dock"),
+        ("landuse", "reservoir"),
+        ("landuse", "basin"),
+    ]),
+    (AreaType::Cemetery, &[("landuse", "cemetery"), ("amenity", "grave_yard")]),
+    (AreaType::Wetland, &[("natural", "wetland")]),
+    (AreaType::Allotments, &[("landuse", "allotments")]),
+    (AreaType::GolfCourse, &[("leisure", "golf_course")]),
+    (AreaType::SportsPitch, &[("leisure", "pitch"), ("leisure", "track")]),
+    (AreaType::Beach, &[("natural", "beach"), ("natural", "sand")]),
+    (AreaType::Farmland, &[("landuse", "farmland"), ("landuse", "meadow"), ("landuse", "orchard")]),
+    (AreaType::Education, &[
+        ("amenity", "school"),
+        ("amenity", "college"),
+        ("amenity", "university"),
+        ("landuse", "education"),
+    ]),
+    (AreaType::Hospital, &[("amenity", "hospital")]),
+    (AreaType::RailwayLand, &[("landuse", "railway")]),
+    (AreaType::Industrial, &[("landuse", "industrial")]),
+    (AreaType::Commercial, &[("landuse", "commercial")]),
+    (AreaType::Retail, &[("landuse", "retail")]),
+    (AreaType::Residential, &[("landuse", "residential")]),
+];
+
+const WATERWAY_TYPE_RULES: [(WaterwayType, &[TagRule]); 3] = [
+    (WaterwayType::River, &[("waterway", "river")]),
+    (WaterwayType::Canal, &[("waterway", "canal")]),
+    (WaterwayType::Stream, &[("waterway", "stream"), ("waterway", "drain"), ("waterway", "ditch")]),
+];
+
+/// Whether `tags` has the tag of `rule`. Values can be lists separated by `;`, any entry
+/// of which matches.
+pub fn matches_tag(tags: &HashMap<Vec<u8>, Vec<u8>>, (key, value): (&str, &str)) -> bool {
+    match tags.get(key.as_bytes()) {
+        Some(_) if value == "*" => true,
+        Some(tag_value) => tag_value.split(|b| *b == b';').any(|tag| tag == value.as_bytes()),
+        None => false,
+    }
+}
 
-pub struct SemanticMapEtl {
+/// The first entry of `rules` with any tag in `tags`, and that tag.
+fn first_match<T: Copy>(tags: &HashMap<Vec<u8>, Vec<u8>>, rules: &[(T, &[TagRule])]) -> Option<(T, TagRule)> {
+    rules.iter().find_map(|(result, rule_tags)| {
+        rule_tags.iter()
+            .find(|rule| matches_tag(tags, **rule))
+            .map(|rule| (*result, *rule))
+    })
 }
 
-impl SemanticMapEtl {
+/// What a classifier made of an element it applies to, or why it skipped the element.
+/// Shared by the pipeline and `explain`, so that explanations follow the pipeline.
+type Decision<T> = std::result::Result<T, String>;
+
+/// Tags of an element, borrowed or completed from other elements.
+type CompletedTags<'a> = Cow<'a, HashMap<Vec<u8>, Vec<u8>>>;
+
+/// The tag `rule` matched on, with the actual value where the rule matches any value.
+fn matched_tag(tags: &HashMap<Vec<u8>, Vec<u8>>, (key, value): TagRule) -> String {
+    let value = match value {
+        "*" => SemanticMapEtl::get_string(tags, key.as_bytes()).unwrap_or_default(),
+        _ => value.to_string(),
+    };
+    format!("{}={}", key, value)
+}
+
+pub struct SemanticMapEtl<'a> {
+    station_name_regex: Regex,
+    icons: &'a IconManifest,
+    stats: EtlStats,
+}
+
+impl<'a> SemanticMapEtl<'a> {
     fn output_path(dir: &Path) -> PathBuf {
         dir.join(OUTPUT_FILE_NAME)
     }
 
+    /// Inputs of the stage besides the parsed OSM data.
+    fn cache_key(&self) -> String {
+        format!("icon_manifest={:016x}\n", self.icons.digest)
+    }
+
     fn has_key(tags: &HashMap<Vec<u8>, Vec<u8>>, key: &[u8]) -> bool {
         let key_vec = key.to_vec();
         tags.contains_key(&key_vec)
@@ -51,177 +190,335 @@ impl SemanticMapEtl {
         )
     }
 
-    fn get_strings(tags: &HashMap<Vec<u8>, Vec<u8>>, key: &[u8]) -> Vec<String> {
-        Self::get_string(tags, key)
-            .map(|s| s.split(";").map(|s| s.to_string()).collect::<Vec<String>>())
-            .into_iter()
-            .flatten()
+    /// Parses the leading number of a tag like `height=12.5 m`. Values in other units than
+    /// metres are rare and not converted.
+    fn get_number(tags: &HashMap<Vec<u8>, Vec<u8>>, key: &[u8]) -> Option<f32> {
+        let value = Self::get_string(tags, key)?;
+        let number: String = value.trim()
+            .chars()
+            .take_while(|c| c.is_ascii_digit() || *c == '.')
+            .collect();
+        number.parse().ok()
+    }
+
+    /// The building `tags` describe, with the outline from `polygons`. None if the tags
+    /// have no `building` key.
+    fn building_from_tags<F>(tags: &HashMap<Vec<u8>, Vec<u8>>, polygons: F) -> Option<Decision<Building>>
+        where F: FnOnce() -> Decision<Vec<semantic::Path>> {
+        if !Self::has_key(tags, b"building") {
+            return None;
+        }
+        if Self::has_kv_pair(tags, b"building", b"no") {
+            return Some(Err("explicitly not a building".to_string()));
+        }
+        Some(polygons().map(|polygons| Building {
+            polygons,
+            height_m: Self::get_number(tags, b"height"),
+            levels: Self::get_number(tags, b"building:levels").map(|levels| levels as u32),
+        }))
+    }
+
+    fn way_building(way: &Way) -> Option<Decision<Building>> {
+        Self::building_from_tags(&way.tags, || match way.nodes.len() {
+            0..=2 => Err("fewer than 3 nodes".to_string()),
+            _ => Ok(vec![way.into()]),
+        })
+    }
+
+    fn relation_building(relation: &Relation) -> Option<Decision<Building>> {
+        Self::building_from_tags(&relation.tags, || {
+            if !Self::has_kv_pair(&relation.tags, b"type", b"multipolygon") {
+                return Err("only multipolygon relations are buildings".to_string());
+            }
+            let ways = Self::member_ways(relation);
+            if ways.is_empty() {
+                return Err("no member ways".to_string());
+            }
+            Ok(Area::reorder_ways(&ways))
+        })
+    }
+
+    /// Paths of the member ways of `relation` that have nodes.
+    fn member_ways(relation: &Relation) -> Vec<semantic::Path> {
+        relation.ways.iter()
+            .filter(|way| !way.nodes.is_empty())
+            .map(|way| way.into())
             .collect()
     }
 
-    fn landmark_type_from_tags(&mut self, tags: &HashMap<Vec<u8>, Vec<u8>>) -> Option<LandmarkType> {
-        if Self::has_kv_pair(tags, b"lgbtq:men", b"only")
-            || Self::has_kv_pair(tags, b"lgbtq:men", b"primary") {
-                Some(LandmarkType::LgbtqMen)
-        } else if Self::has_kv_pair(tags, b"lgbtq", b"primary")
-            || Self::has_kv_pair(tags, b"gay", b"yes") {
-            Some(LandmarkType::Lgbtq)
-        } else if Self::has_kv_pair(tags, b"bar", b"cocktail")
-            || Self::has_kv_pair(tags, b"cocktails", b"yes")
-            || Self::has_kv_pair(tags, b"drink:cocktail", b"served") {
-            Some(LandmarkType::CocktailBar)
-        } else if Self::has_kv_pair(tags, b"emergency", b"emergency_ward_entrance")
-            || Self::has_kv_pair(tags, b"healthcare", b"emergency_ward") {
-            Some(LandmarkType::Hospital)
-        } else if Self::has_kv_pair(tags, b"natural", b"tree")
-            && Self::has_key(tags, b"name") {
-            Some(LandmarkType::Tree)
-        } else if Self::has_kv_pair(tags, b"leisure", b"fitness_centre") {
-            Some(LandmarkType::Gym)
-        } else if Self::has_kv_pair(tags, b"climbing:toprope", b"yes")
-            || Self::has_kv_pair(tags, b"climbing:sport", b"yes")
-            || Self::has_kv_pair(tags, b"climbing:ice", b"yes") {
-            Some(LandmarkType::ClimbingRope)
-        } else if Self::has_kv_pair(tags, b"climbing:boulder", b"yes")
-            || Self::has_kv_pair(tags, b"climbing", b"bouldering") 
-            || (
-                Self::has_kv_pair(tags, b"leisure", b"sports_centre")
-                && Self::has_kv_pair(tags, b"sport", b"climbing")
-            ) {
-            Some(LandmarkType::ClimbingBoulder)
-        } else if Self::has_kv_pair(tags, b"leisure", b"pitch")
-            && Self::has_kv_pair(tags, b"sport", b"climbing") {
-            Some(LandmarkType::ClimbingOutdoor)
-        } else if Self::has_kv_pair(tags, b"amenity", b"music_venue")
-            || Self::has_kv_pair(tags, b"live_music", b"yes") {
-            Some(LandmarkType::MusicVenue)
-        } else if Self::has_kv_pair(tags, b"amenity", b"place_of_worship") {
-            if Self::has_kv_pair(tags, b"religion", b"aetherius_society") {
-                Some(LandmarkType::TempleAetheriusSociety)
-            } else if Self::has_kv_pair(tags, b"religion", b"buddhist") {
-                Some(LandmarkType::TempleBuddhist)
-            } else if Self::has_kv_pair(tags, b"religion", b"christian") || Self::has_kv_pair(tags, b"religion", b"spiritualist") {
-                Some(LandmarkType::TempleChristian)
-            } else if Self::has_kv_pair(tags, b"religion", b"hindu") {
-                Some(LandmarkType::TempleHindu)
-            } else if Self::has_kv_pair(tags, b"religion", b"humanist") {
-                Some(LandmarkType::TempleHumanist)
-            } else if Self::has_kv_pair(tags, b"religion", b"jain") {
-                Some(LandmarkType::TempleJain)
-            } else if Self::has_kv_pair(tags, b"religion", b"jewish") {
-                Some(LandmarkType::TempleJewish)
-            } else if Self::has_kv_pair(tags, b"religion", b"muslim") {
-                Some(LandmarkType::TempleMuslim)
-            } else if Self::has_kv_pair(tags, b"religion", b"rastafarian") {
-                Some(LandmarkType::TempleRastafarian)
-            } else if Self::has_kv_pair(tags, b"religion", b"rosicrucian") {
-                Some(LandmarkType::TempleRosicucian)
-            } else if Self::has_kv_pair(tags, b"religion", b"scientologist") {
-                Some(LandmarkType::TempleScientologist)
-            } else if Self::has_kv_pair(tags, b"religion", b"self-realization_fellowship") {
-                Some(LandmarkType::TempleSelfRealizationFellowship)
-            } else if Self::has_kv_pair(tags, b"religion", b"sikh") {
-                Some(LandmarkType::TempleSikh)
-            } else {
-                None
+    /// The tag that makes the element a station, if any.
+    fn station_tag(tags: &HashMap<Vec<u8>, Vec<u8>>) -> Option<TagRule> {
+        STATION_TAGS.iter().find(|rule| matches_tag(tags, **rule)).copied()
+    }
+
+    fn is_station(tags: &HashMap<Vec<u8>, Vec<u8>>) -> bool {
+        Self::station_tag(tags).is_some()
+    }
+
+    /// The station type and the tag it was chosen by.
+    fn station_type_from_tags(tags: &HashMap<Vec<u8>, Vec<u8>>) -> Option<(TransportStationType, TagRule)> {
+        first_match(tags, &STATION_TYPE_RULES)
+    }
+
+    /// Some names are like "Edgeware Road (Bakerloo line)", we want to strip the brackets.
+    fn station_base_name<'b>(&self, name: &'b str) -> &'b str {
+        self.station_name_regex.captures(name)
+            .and_then(|captures| captures.name("base_name"))
+            .map_or(name, |base_name| base_name.as_str())
+            .trim()
+    }
+
+    /// Builds a station from the tags of a node, way or relation, placed at `location`,
+    /// and returns it with the tag its type was chosen by.
+    fn station_from_tags(
+        &self,
+        tags: &HashMap<Vec<u8>, Vec<u8>>,
+        osm_element: OsmElementRef,
+        location: Option<MapCoords>,
+    ) -> Decision<(TransportStation, TagRule)> {
+        let name = Self::get_string(tags, b"name").ok_or("no name")?;
+        let (station_type, type_rule) = Self::station_type_from_tags(tags).ok_or("no station type rule matched")?;
+        let location = location.ok_or("no nodes to place it")?;
+        let station = TransportStation {
+            name: self.station_base_name(&name).to_string(),
+            station_type,
+            network: Self::get_string(tags, b"network"),
+            lat: location.lat,
+            lon: location.lon,
+            osm_elements: vec![osm_element],
+            locations: vec![location],
+            lines: Vec::new(),
+        };
+        Ok((station, type_rule))
+    }
+
+    /// The tags a relation describes a station with and why it is considered one, or None
+    /// if it isn't. Relations are stations if tagged as one, or as a
+    /// `public_transport=stop_area` that doesn't contain a separately mapped station. Stop
+    /// areas often only carry a name, so missing tags are taken from their members.
+    fn station_relation_tags(relation: &Relation) -> Option<Decision<(CompletedTags<'_>, String)>> {
+        if !Self::has_kv_pair(&relation.tags, b"public_transport", b"stop_area") {
+            let station_tag = Self::station_tag(&relation.tags)?;
+            return Some(Ok((Cow::Borrowed(&relation.tags), matched_tag(&relation.tags, station_tag))));
+        }
+        let station_member = relation.nodes.iter()
+            .filter(|node| Self::is_station(&node.tags))
+            .map(|node| OsmElementRef::Node(node.id))
+            .chain(relation.ways.iter()
+                .filter(|way| Self::is_station(&way.tags))
+                .map(|way| OsmElementRef::Way(way.id)))
+            .next();
+        if let Some(station_member) = station_member {
+            return Some(Err(format!("stop area with station member {}", station_member)));
+        }
+
+        let mut tags = relation.tags.clone();
+        let member_tags = relation.nodes.iter().map(|node| &node.tags)
+            .chain(relation.ways.iter().map(|way| &way.tags));
+        for member_tags in member_tags {
+            for (key, value) in member_tags {
+                tags.entry(key.clone()).or_insert_with(|| value.clone());
+            }
+        }
+        Some(Ok((Cow::Owned(tags), "public_transport=stop_area, tags completed from members".to_string())))
+    }
+
+    /// Mean position of the nodes of a relation and its member ways.
+    fn relation_centroid(relation: &Relation) -> Option<MapCoords> {
+        Self::centroid(relation.nodes.iter().chain(relation.ways.iter().flat_map(|way| &way.nodes)))
+    }
+
+    /// Mean position of the given nodes, ignoring the repeated first node of closed ways.
+    fn centroid<'b, I: IntoIterator<Item = &'b Node>>(nodes: I) -> Option<MapCoords> {
+        let mut seen = HashSet::new();
+        let (mut lat, mut lon, mut count) = (0.0, 0.0, 0.0);
+        for node in nodes {
+            if seen.insert(node.id) {
+                lat += node.lat;
+                lon += node.lon;
+                count += 1.0;
             }
-        } else {
-            None
         }
+        (count > 0.0).then(|| MapCoords { lat: lat / count, lon: lon / count })
+    }
+
+    /// The place type and the tag it was chosen by.
+    fn place_type_from_tags(tags: &HashMap<Vec<u8>, Vec<u8>>) -> Option<(PlaceType, TagRule)> {
+        first_match(tags, &PLACE_TYPE_RULES)
+    }
+
+    /// The place `tags` describe, at the centroid of `nodes`, and the tag its type was
+    /// chosen by. None if the tags name no place type.
+    fn place_from_tags<'b, I: IntoIterator<Item = &'b Node>>(tags: &HashMap<Vec<u8>, Vec<u8>>, nodes: I) -> Option<(Decision<Place>, TagRule)> {
+        let (place_type, rule) = Self::place_type_from_tags(tags)?;
+        let place = match (Self::get_string(tags, b"name"), Self::centroid(nodes)) {
+            (None, _) => Err("no name".to_string()),
+            (_, None) => Err("no nodes to place it".to_string()),
+            (Some(name), Some(center)) => Ok(Place {
+                name,
+                place_type,
+                population: Self::get_string(tags, b"population")
+                    .and_then(|population| population.replace([',', ' '], "").parse().ok()),
+                center,
+            }),
+        };
+        Some((place, rule))
+    }
+
+    /// Places are often mapped both as a node and as an area. Keep the node, as its
+    /// position was chosen by a mapper, and drop areas with the same name and type.
+    fn deduplicate_places(&mut self, output: &mut SemanticMapElements, area_places: Vec<Place>) {
+        let node_places: HashSet<(String, PlaceType)> = output.places.iter()
+            .map(|place| (place.name.clone(), place.place_type))
+            .collect();
+        output.places.extend(
+            area_places.into_iter()
+                .filter(|place| !node_places.contains(&(place.name.clone(), place.place_type)))
+        );
     }
 
     fn process_nodes(&mut self, output: &mut SemanticMapElements, nodes: &HashMap<OsmId, Node>) {
         for node in nodes.values() {
-            if Self::has_kv_pair(&node.tags, b"railway", b"station")
-                // && Self::has_kv_pair(&node.tags, b"subway", b"yes")
-                && Self::has_key(&node.tags, b"name") {
-
-                let name = Self::get_string(&node.tags, b"name").unwrap();
-
-                // Some names are like "Edgeware Road (Bakerloo line)", we want to strip the
-                // brackets.
-                let re = Regex::new(r"(?<base_name>[^(]*)(\(.*\))?").unwrap();
-                let base_name = re.captures(&name).unwrap().name("base_name").unwrap().as_str();
-                let maybe_station_type = if Self::has_kv_pair(&node.tags, b"network", b"London Underground") {
-                    Some(TransportStationType::Underground)
-                } else if Self::has_kv_pair(&node.tags, b"network", b"Docklands Light Railway"){
-                    Some(TransportStationType::Dlr)
-                } else if Self::has_kv_pair(&node.tags, b"network", b"London Overground") {
-                    Some(TransportStationType::Overground)
-                } else if Self::has_kv_pair(&node.tags, b"network", b"Elizabeth Line") {
-                    Some(TransportStationType::ElizabethLine)
-                } else {
-                    None
-                };
-                if let Some(station_type) = maybe_station_type {
-                    output.underground_stations.push(
-                        TransportStation {
-                            name: base_name.trim().to_string(),
-                            station_type,
-                            lon: node.lon,
-                            lat: node.lat
-                        }
-                    );
+            if Self::is_station(&node.tags) {
+                if let Ok((station, _)) = self.station_from_tags(&node.tags, OsmElementRef::Node(node.id), Some(node.into())) {
+                    output.underground_stations.push(station);
                 }
             }
-            if let Some(landmark_type) = self.landmark_type_from_tags(&node.tags) {
-                output.landmarks.push(
-                    Landmark{
-                        lon: node.lon,
-                        lat: node.lat,
-                        landmark_type,
-                    }
-                );
+            if let Some((Ok(place), _)) = Self::place_from_tags(&node.tags, [node]) {
+                output.places.push(place);
             }
-
-            if node.id == 5417354028 {  // Bobby Fitzpatric
+            if let Some(category) = self.icons.landmark_category(OsmElementRef::Node(node.id), &node.tags) {
                 output.landmarks.push(
                     Landmark{
                         lon: node.lon,
                         lat: node.lat,
-                        landmark_type: LandmarkType::CocktailBar,
-                    }
-                );
-            } else if node.id == 264905226 {  // Meridian Place
-                output.landmarks.push(
-                    Landmark{
-                        lon: node.lon,
-                        lat: node.lat,
-                        landmark_type: LandmarkType::CocktailBar,
+                        category: category.to_string(),
+                        name: Self::get_string(&node.tags, b"name"),
                     }
                 );
             }
         }
     }
 
-    fn area_type_from_tags(&mut self, tags: &HashMap<Vec<u8>, Vec<u8>>) -> Option<AreaType> {
-        if Self::has_kv_pair(tags, b"leisure", b"wood")
-            || Self::has_kv_pair(tags, b"landuse", b"forest")
-            || Self::has_kv_pair(tags, b"natural", b"wood") {
-            Some(AreaType::Wood)
+    /// The area type and the tag it was chosen by.
+    fn area_type_from_tags(tags: &HashMap<Vec<u8>, Vec<u8>>) -> Option<(AreaType, TagRule)> {
+        first_match(tags, &AREA_TYPE_RULES)
+    }
+
+    /// The waterway of `way` and the tag its type was chosen by. None if the way isn't
+    /// tagged as a waterway.
+    fn waterway_from_way(way: &Way) -> Option<(Decision<Waterway>, TagRule)> {
+        let (waterway_type, rule) = first_match(&way.tags, &WATERWAY_TYPE_RULES)?;
+        // Culverted streams run underground.
+        if Self::has_kv_pair(&way.tags, b"tunnel", b"culvert") {
+            return Some((Err("runs underground in a culvert".to_string()), rule));
         }
-        else if Self::has_kv_pair(tags, b"leisure", b"park")
-            || Self::has_kv_pair(tags, b"landuse", b"grass")
-            || Self::has_kv_pair(tags, b"natural", b"heath")
-            || Self::has_kv_pair(tags, b"leisure", b"garden") {
-            Some(AreaType::Park)
+        let waterway = Waterway {
+            name: Self::get_string(&way.tags, b"name"),
+            waterway_type,
+            path: way.into(),
+        };
+        Some((Ok(waterway), rule))
+    }
+
+    /// The plain rail of `way`, None if it isn't tagged `railway=rail`.
+    fn rail_from_way(way: &Way, route_tracks: &HashSet<OsmId>) -> Option<Decision<semantic::Path>> {
+        if !Self::has_kv_pair(&way.tags, b"railway", b"rail") {
+            return None;
         }
-        else if Self::has_key(tags, b"water") 
-            || Self::has_kv_pair(tags, b"natural", b"water")
-        {
-            Some(AreaType::Water)
+        if route_tracks.contains(&way.id) {
+            return Some(Err("drawn as the track of a transit line".to_string()));
         }
-        else {
-            None
+        Some(Ok(way.into()))
+    }
+
+    /// The council a relation with a `council_name` describes, None for other relations.
+    /// Skip reasons double as the statistics' drop reasons.
+    fn council_from_relation(relation: &Relation) -> Option<Decision<Council>> {
+        let name = Self::get_string(&relation.tags, b"council_name")?;
+        if EXCLUDED_COUNCILS.contains(&name.as_str()) {
+            return Some(Err("excluded council".to_string()));
+        }
+        let ways = Self::member_ways(relation);
+        if ways.is_empty() {
+            return Some(Err("council without member ways".to_string()));
+        }
+        let boundary = Area::reorder_ways(&ways);
+        let member_with_role = |role: &[u8]| {
+            relation.nodes.iter()
+                .zip(&relation.node_roles)
+                .find(|(_, node_role)| node_role.as_slice() == role)
+                .map(|(node, _)| MapCoords::from(node))
+        };
+        let center = member_with_role(b"label")
+            .or_else(|| pole_of_inaccessibility(&boundary, COUNCIL_LABEL_PRECISION_M))
+            .or_else(|| member_with_role(b"admin_centre"));
+        let Some(center) = center else {
+            return Some(Err("council without label position".to_string()));
+        };
+        Some(Ok(Council { name, center, boundary }))
+    }
+
+    /// Route relations also list platforms and stop areas, only their tracks are drawn.
+    fn is_track(way: &Way) -> bool {
+        Self::has_key(&way.tags, b"railway") && !Self::has_kv_pair(&way.tags, b"railway", b"platform")
+    }
+
+    /// Ways drawn as the track of a transit line, see `process_transit_routes`.
+    fn transit_route_tracks(relations: &HashMap<OsmId, Relation>) -> HashSet<OsmId> {
+        relations.values()
+            .filter(|route| matches!(Self::transit_route_mode(route), Some(Ok(_))))
+            .flat_map(|route| route.ways.iter().filter(|way| Self::is_track(way)).map(|way| way.id))
+            .collect()
+    }
+
+    /// The mode of a `type=route` relation, None for other relations.
+    fn transit_route_mode(relation: &Relation) -> Option<Decision<TransitMode>> {
+        if !Self::has_kv_pair(&relation.tags, b"type", b"route") {
+            return None;
         }
+        Some(Self::transit_mode_from_tags(&relation.tags, b"route").ok_or_else(|| "not a transit mode".to_string()))
     }
 
-    fn process_ways(&mut self, output: &mut SemanticMapElements, ways: &HashMap<OsmId, Way>) {
+    /// The `type=route_master` relation of each route listed in one.
+    fn route_masters(relations: &HashMap<OsmId, Relation>) -> HashMap<OsmId, &Relation> {
+        let mut master_of_route = HashMap::new();
+        for relation in relations.values() {
+            if Self::has_kv_pair(&relation.tags, b"type", b"route_master") {
+                for route_id in &relation.relations {
+                    master_of_route.insert(*route_id, relation);
+                }
+            }
+        }
+        master_of_route
+    }
+
+    /// Track of transit lines, in `route_tracks`, is left out of the plain rails so that it
+    /// isn't drawn twice.
+    fn process_ways(
+        &mut self,
+        output: &mut SemanticMapElements,
+        ways: &HashMap<OsmId, Way>,
+        route_tracks: &HashSet<OsmId>,
+    ) -> Vec<Place> {
+        let mut area_places = Vec::new();
         for way in ways.values() {
+            if let Some((Ok(place), _)) = Self::place_from_tags(&way.tags, &way.nodes) {
+                area_places.push(place);
+            }
+            if Self::is_station(&way.tags) {
+                let station = self.station_from_tags(&way.tags, OsmElementRef::Way(way.id), Self::centroid(&way.nodes));
+                if let Ok((station, _)) = station {
+                    output.underground_stations.push(station);
+                }
+            }
             if Self::has_key(&way.tags, b"highway") {
-                output.roads.push(way.into());
+                output.roads.push(Road {
+                    name: Self::get_string(&way.tags, b"name"),
+                    path: way.into(),
+                });
             }
-            if let Some(area_type) = self.area_type_from_tags(&way.tags) {
+            if let Some((area_type, _)) = Self::area_type_from_tags(&way.tags) {
                 output.areas.push(
                     Area::new(
                         area_type,
@@ -229,97 +526,51 @@ impl SemanticMapEtl {
                     )
                 );
             }
-            if (
-                Self::has_kv_pair(&way.tags, b"railway", b"subway")
-                || Self::has_kv_pair(&way.tags, b"railway", b"rail")
-                || Self::has_kv_pair(&way.tags, b"railway", b"light_rail")
-            ) && Self::has_key(&way.tags, b"line") {
-                for line_tag in Self::get_strings(&way.tags, b"line") {
-                    let lines = match line_tag.to_lowercase().as_str() {
-                        "bakerloo" => vec![TubeLine::Bakerloo],
-                        "central" | "central line" => vec![TubeLine::Central],
-                        "circle" => vec![TubeLine::Circle],
-                        "deep level district" | "district" | "district, north london" => vec![TubeLine::District],
-                        "district, piccadilly" => vec![TubeLine::District, TubeLine::Piccadilly],
-                        "dlr" => vec![TubeLine::Dlr],
-                        "elizabeth" => vec![TubeLine::Elizabeth],
-                        "hammersmith & city" => vec![TubeLine::HammersmithAndCity],
-                        "jubilee" | "jubilee line" => vec![TubeLine::Jubilee],
-                        "metropolitan" => vec![TubeLine::Metropolitan],
-                        "metropolitan, piccadilly" => vec![TubeLine::Metropolitan, TubeLine::Piccadilly],
-                        "north london line" => vec![TubeLine::Overground],
-                        "northern" | "northern line" => vec![TubeLine::Northern],
-                        "northern city" => vec![TubeLine::Northern, TubeLine::HammersmithAndCity],
-                        "picadilly" | "piccadilly" => vec![TubeLine::Piccadilly],
-                        "victoria" => vec![TubeLine::Victoria],
-                        "waterloo & city" => vec![TubeLine::WaterlooAndCity],
-                        _ => {
-                            vec![]
-                        },
-                    };
-                    for line in lines {
-                        output.tube_rails.push(TubeRail {
-                            line,
-                            path: way.into(),
-                        });
-                    }
-                }
-            } else if Self::has_kv_pair(&way.tags, b"railway", b"rail")
-                && Self::has_kv_pair(&way.tags, b"name", b"Elizabeth Line") {
-                output.tube_rails.push(TubeRail {
-                    line: TubeLine::Elizabeth,
-                    path: way.into(),
-                });
-            // } else if Self::has_kv_pair(&way.tags, b"railway", b"rail")
-            //     && (
-            //         Self::has_kv_pair(&way.tags, b"network", b"London Overground")
-            //         || Self::has_kv_pair(&way.tags, b"line", b"Lea Valley Lines")
-            //         || Self::has_kv_pair(&way.tags, b"line", b"North London Line")
-            //         || Self::has_kv_pair(&way.tags, b"line", b"North London line")
-            //         || Self::has_kv_pair(&way.tags, b"name", b"East London Line")
-            //         || Self::has_kv_pair(&way.tags, b"name", b"East London line")
-            //         || Self::has_kv_pair(&way.tags, b"name", b"Hackney Downs and Cheshunt Line")
-            //         || Self::has_kv_pair(&way.tags, b"name", b"North/West London lines")
-            //         || Self::has_kv_pair(&way.tags, b"name", b"South Bermondsey to Horsham Line")
-            //         || Self::has_kv_pair(&way.tags, b"name", b"South London Line")
-            //         || Self::has_kv_pair(&way.tags, b"name", b"South London line")
-            //         || Self::has_kv_pair(&way.tags, b"name", b"South/West London lines")
-            //         || Self::has_kv_pair(&way.tags, b"name", b"West Anglia Main Line")
-            //     ) {
-            //     output.tube_rails.push(TubeRail {
-            //         line: TubeLine::Overground,
-            //         path: way.into(),
-            //     });
-            } else if Self::has_kv_pair(&way.tags, b"railway", b"rail") {
-                output.rails.push(way.into());
+            if let Some(Ok(rail)) = Self::rail_from_way(way, route_tracks) {
+                output.rails.push(rail);
             }
-
-            if let Some(landmark_type) = self.landmark_type_from_tags(&way.tags) {
-                output.landmarks.push(
-                    Landmark{
-                        lon: way.nodes[0].lon,
-                        lat: way.nodes[0].lat,
-                        landmark_type,
-                    }
-                );
+            if let Some(Ok(building)) = Self::way_building(way) {
+                output.buildings.push(building);
+            }
+            if let Some((Ok(waterway), _)) = Self::waterway_from_way(way) {
+                output.waterways.push(waterway);
             }
 
-            // Handle castle climbing separately
-            if way.id == 963992061 {
+            if let Some(category) = self.icons.landmark_category(OsmElementRef::Way(way.id), &way.tags) {
                 output.landmarks.push(
                     Landmark{
                         lon: way.nodes[0].lon,
                         lat: way.nodes[0].lat,
-                        landmark_type: LandmarkType::ClimbingRope,
+                        category: category.to_string(),
+                        name: Self::get_string(&way.tags, b"name"),
                     }
                 );
             }
         }
+        area_places
     }
 
-    fn process_relations(&mut self, output: &mut SemanticMapElements, relations: &HashMap<OsmId, Relation>) {
+    /// Stations mapped as relations, see `station_relation_tags`.
+    fn process_station_relation(&self, output: &mut SemanticMapElements, relation: &Relation) {
+        let Some(Ok((tags, _))) = Self::station_relation_tags(relation) else {
+            return;
+        };
+        let station = self.station_from_tags(&tags, OsmElementRef::Relation(relation.id), Self::relation_centroid(relation));
+        if let Ok((station, _)) = station {
+            output.underground_stations.push(station);
+        }
+    }
+
+    fn process_relations(&mut self, output: &mut SemanticMapElements, relations: &HashMap<OsmId, Relation>) -> Vec<Place> {
+        let mut area_places = Vec::new();
         for relation in relations.values() {
-            if let Some(area_type) = self.area_type_from_tags(&relation.tags) {
+            self.process_station_relation(output, relation);
+            let member_nodes = relation.nodes.iter()
+                .chain(relation.ways.iter().flat_map(|way| &way.nodes));
+            if let Some((Ok(place), _)) = Self::place_from_tags(&relation.tags, member_nodes) {
+                area_places.push(place);
+            }
+            if let Some((area_type, _)) = Self::area_type_from_tags(&relation.tags) {
                 output.areas.push(
                     Area::new(
                         area_type,
@@ -327,51 +578,254 @@ impl SemanticMapEtl {
                     )
                 );
             }
-            if Self::has_kv_pair(&relation.tags, b"network", b"London Overground") {
-                for way in &relation.ways {
-                    if Self::has_kv_pair(&way.tags, b"railway", b"rail") {
-                        output.tube_rails.push(TubeRail {
-                            line: TubeLine::Overground,
-                            path: way.into(),
-                        });
-                    }
+            if let Some(Ok(building)) = Self::relation_building(relation) {
+                output.buildings.push(building);
+            }
+            match Self::council_from_relation(relation) {
+                Some(Ok(council)) => output.councils.push(council),
+                Some(Err(reason)) => self.stats.dropped(&reason, 1),
+                None => {},
+            }
+        }
+        area_places
+    }
+
+    fn transit_mode_from_tags(tags: &HashMap<Vec<u8>, Vec<u8>>, key: &[u8]) -> Option<TransitMode> {
+        if Self::has_kv_pair(tags, key, b"subway") {
+            Some(TransitMode::Subway)
+        } else if Self::has_kv_pair(tags, key, b"light_rail") {
+            Some(TransitMode::LightRail)
+        } else if Self::has_kv_pair(tags, key, b"train") {
+            Some(TransitMode::Train)
+        } else if Self::has_kv_pair(tags, key, b"tram") {
+            Some(TransitMode::Tram)
+        } else if Self::has_kv_pair(tags, key, b"monorail") {
+            Some(TransitMode::Monorail)
+        } else {
+            None
+        }
+    }
+
+    /// Builds transit lines from `type=route` relations. Routes that are members of a
+    /// `type=route_master` relation (usually one route per direction or branch) are merged
+    /// into a single line, which takes its name, ref and colour from the route master
+    /// where present.
+    ///
+    /// Returns the line each route relation was assigned to.
+    fn process_transit_routes(
+        &mut self,
+        output: &mut SemanticMapElements,
+        relations: &HashMap<OsmId, Relation>,
+    ) -> HashMap<OsmId, TransitLineId> {
+        let master_of_route = Self::route_masters(relations);
+
+        let mut line_of_route = HashMap::new();
+        let mut seen_rails: HashSet<(TransitLineId, OsmId)> = HashSet::new();
+        for route in relations.values() {
+            let mode = match Self::transit_route_mode(route) {
+                None => continue,
+                Some(Err(_)) => {
+                    let value = Self::get_string(&route.tags, b"route").unwrap_or_default();
+                    self.stats.unknown_value("route", &value);
+                    continue;
+                },
+                Some(Ok(mode)) => mode,
+            };
+            let master = master_of_route.get(&route.id);
+            let line_id = master.map_or(route.id, |master| master.id);
+            line_of_route.insert(route.id, line_id);
+            let get_tag = |key: &[u8]| {
+                master.and_then(|master| Self::get_string(&master.tags, key))
+                    .or_else(|| Self::get_string(&route.tags, key))
+            };
+
+            output.transit_lines.entry(line_id).or_insert_with(|| TransitLine {
+                id: line_id,
+                name: get_tag(b"name")
+                    .or_else(|| get_tag(b"ref"))
+                    .unwrap_or_else(|| line_id.to_string()),
+                reference: get_tag(b"ref"),
+                colour: get_tag(b"colour"),
+                network: get_tag(b"network"),
+                mode,
+            });
+
+            for way in &route.ways {
+                if !Self::is_track(way) {
+                    continue;
+                }
+                if seen_rails.insert((line_id, way.id)) {
+                    output.tube_rails.push(TubeRail {
+                        line: line_id,
+                        path: way.into(),
+                    });
                 }
             }
-            if Self::has_key(&relation.tags, b"council_name") {
-                let council_name = Self::get_string(&relation.tags, b"council_name").unwrap();
-                if council_name == "London Borough of Richmond upon Thames"
-                    || council_name == "Bexley Council"
-                    || council_name == "Barking and Dagenham Council" {
-                        continue;
+        }
+        line_of_route
+    }
+
+    /// Links stations to the lines serving them. Route relations list their stops, which
+    /// are either the station node itself or a stop position grouped with the station in a
+    /// `public_transport=stop_area` relation. Stations that are neither listed in a route
+    /// nor grouped in a stop area fall back to the lines running within
+    /// `STATION_LINE_DISTANCE_M` of them. Stations that are get only the lines of their
+    /// routes, as lines merely passing nearby would make them interchanges.
+    fn associate_station_lines(
+        &mut self,
+        output: &mut SemanticMapElements,
+        relations: &HashMap<OsmId, Relation>,
+        line_of_route: &HashMap<OsmId, TransitLineId>,
+    ) {
+        let mut station_of_element: HashMap<OsmElementRef, usize> = HashMap::new();
+        for (idx, station) in output.underground_stations.iter().enumerate() {
+            for osm_element in &station.osm_elements {
+                station_of_element.insert(*osm_element, idx);
+            }
+        }
+        // Stations listed in a route or grouped in a stop area, whose mapped lines are used.
+        let mut mapped_stations: HashSet<usize> = HashSet::new();
+        let member_refs = |relation: &Relation| -> Vec<OsmElementRef> {
+            relation.nodes.iter().map(|node| OsmElementRef::Node(node.id))
+                .chain(relation.ways.iter().map(|way| OsmElementRef::Way(way.id)))
+                .chain(std::iter::once(OsmElementRef::Relation(relation.id)))
+                .collect()
+        };
+
+        for relation in relations.values() {
+            if !Self::has_kv_pair(&relation.tags, b"public_transport", b"stop_area") {
+                continue;
+            }
+            let members = member_refs(relation);
+            let station_idx = members.iter()
+                .find_map(|member| station_of_element.get(member).copied());
+            if let Some(station_idx) = station_idx {
+                mapped_stations.insert(station_idx);
+                for member in members {
+                    station_of_element.entry(member).or_insert(station_idx);
                 }
-                let mut avg_lat = 0.0;
-                let mut avg_lon = 0.0;
-                let mut count = 0.0;
-                for way in &relation.ways {
-                    for node in &way.nodes {
-                        avg_lat += node.lat;
-                        avg_lon += node.lon;
-                        count += 1.0;
+            }
+        }
+
+        for route in relations.values() {
+            let Some(line_id) = line_of_route.get(&route.id) else {
+                continue;
+            };
+            for member in member_refs(route) {
+                if let Some(station_idx) = station_of_element.get(&member) {
+                    mapped_stations.insert(*station_idx);
+                    let lines = &mut output.underground_stations[*station_idx].lines;
+                    if !lines.contains(line_id) {
+                        lines.push(*line_id);
                     }
                 }
-                avg_lat /= count;
-                avg_lon /= count;
-                output.councils.push(
-                    Council {
-                        name: council_name,
-                        center: MapCoords { lat: avg_lat, lon: avg_lon },
+            }
+        }
+
+        let rail_paths: Vec<&[MapCoords]> = output.tube_rails.iter().map(|rail| rail.path.as_slice()).collect();
+        let rail_grid = SegmentGrid::new(&rail_paths, STATION_LINE_DISTANCE_M);
+        for (idx, station) in output.underground_stations.iter_mut().enumerate() {
+            if mapped_stations.contains(&idx) {
+                continue;
+            }
+            let location = MapCoords { lat: station.lat, lon: station.lon };
+            for rail_idx in rail_grid.paths_near(&location, STATION_LINE_DISTANCE_M) {
+                let line = output.tube_rails[rail_idx].line;
+                if !station.lines.contains(&line) {
+                    station.lines.push(line);
+                }
+            }
+        }
+
+        for station in &mut output.underground_stations {
+            station.lines.sort_unstable();
+            Self::dedup_lines(&mut station.lines, &output.transit_lines);
+        }
+    }
+
+    /// Keeps one of the lines with the same ref, or name if they have none, and network.
+    /// Routes without a route master are lines of their own, so a line mapped as one route
+    /// per direction would otherwise count twice.
+    fn dedup_lines(lines: &mut Vec<TransitLineId>, transit_lines: &HashMap<TransitLineId, TransitLine>) {
+        let mut seen = HashSet::new();
+        lines.retain(|line_id| match transit_lines.get(line_id) {
+            Some(line) => seen.insert((line.reference.as_ref().unwrap_or(&line.name), &line.network)),
+            None => true,
+        });
+    }
+
+    /// Adds the locations of a merged station that are further than
+    /// `STATION_PART_DISTANCE_M` from all known ones.
+    fn add_station_parts(locations: &mut Vec<MapCoords>, new_locations: Vec<MapCoords>) {
+        for location in new_locations {
+            let is_known = locations.iter()
+                .any(|known| known.distance_m(&location) < STATION_PART_DISTANCE_M);
+            if !is_known {
+                locations.push(location);
+            }
+        }
+    }
+
+    /// Combines stations with the same name and network that are close together, e.g. the
+    /// separate nodes mapped for each line at a large interchange.
+    fn merge_stations(&mut self, output: &mut SemanticMapElements) {
+        let station_count = output.underground_stations.len();
+        let mut merged: Vec<TransportStation> = Vec::new();
+        for station in output.underground_stations.drain(..) {
+            let location = MapCoords { lat: station.lat, lon: station.lon };
+            let existing = merged.iter_mut().find(|other| {
+                other.name == station.name
+                    && other.network == station.network
+                    && location.distance_m(&MapCoords { lat: other.lat, lon: other.lon }) < STATION_MERGE_DISTANCE_M
+            });
+            match existing {
+                Some(other) => {
+                    other.osm_elements.extend(station.osm_elements);
+                    Self::add_station_parts(&mut other.locations, station.locations);
+                    for line in station.lines {
+                        if !other.lines.contains(&line) {
+                            other.lines.push(line);
+                        }
                     }
-                );
+                    other.lines.sort_unstable();
+                    Self::dedup_lines(&mut other.lines, &output.transit_lines);
+                    let count = other.locations.len() as f64;
+                    other.lat = other.locations.iter().map(|coords| coords.lat).sum::<f64>() / count;
+                    other.lon = other.locations.iter().map(|coords| coords.lon).sum::<f64>() / count;
+                },
+                None => merged.push(station),
             }
         }
+        self.stats.dropped("station merged into another", station_count - merged.len());
+        output.underground_stations = merged;
     }
 
-    pub fn new() -> SemanticMapEtl {
-        SemanticMapEtl {}
+    fn count_output(&mut self, output: &SemanticMapElements) {
+        let stats = &mut self.stats;
+        stats.count_by("stations", &output.underground_stations, |station| format!("{:?}", station.station_type));
+        stats.count("rails", output.rails.len());
+        stats.count("roads", output.roads.len());
+        stats.count_by("areas", &output.areas, |area| format!("{:?}", area.area_type));
+        stats.count_by("waterways", &output.waterways, |waterway| format!("{:?}", waterway.waterway_type));
+        stats.count("buildings", output.buildings.len());
+        stats.count_by("landmarks", &output.landmarks, |landmark| landmark.category.clone());
+        stats.count("tube_rails", output.tube_rails.len());
+        let lines: Vec<&TransitLine> = output.transit_lines.values().collect();
+        stats.count_by("transit_lines", &lines, |line| format!("{:?}", line.mode));
+        stats.count("councils", output.councils.len());
+        stats.count_by("places", &output.places, |place| format!("{:?}", place.place_type));
+    }
+
+    pub fn new(icons: &'a IconManifest) -> SemanticMapEtl<'a> {
+        SemanticMapEtl {
+            station_name_regex: Regex::new(r"(?<base_name>[^(]*)(\(.*\))?").unwrap(),
+            icons,
+            stats: EtlStats::default(),
+        }
     }
 }
 
-impl Etl for SemanticMapEtl {
+impl Etl for SemanticMapEtl<'_> {
     type Input = OsmMapData;
     type Output = SemanticMapElements;
 
@@ -379,44 +833,47 @@ impl Etl for SemanticMapEtl {
         ETL_NAME
     }
 
+    /// Cached if the output was built with the current icon manifest, whose landmark
+    /// categories it depends on.
     fn is_cached(&self, dir: &std::path::Path) -> Result<bool> {
-        Ok(Self::output_path(dir).exists())
+        let key = fs::read_to_string(dir.join(CACHE_KEY_FILE_NAME)).unwrap_or_default();
+        Ok(Self::output_path(dir).exists() && key == self.cache_key())
     }
 
     fn clean(&self, dir: &std::path::Path) -> Result<()> {
         fs::remove_file(Self::output_path(dir))?;
+        fs::remove_file(dir.join(CACHE_KEY_FILE_NAME))?;
         Ok(())
     }
 
     fn extract(&mut self, dir: &std::path::Path) -> Result<Self::Input> {
-        let input_file_path = dir.join(parse_osm::OUTPUT_FILE_NAME);
-        let mut input_file = File::open(input_file_path)?;
-
-        let mut buf_vec: Vec<u8> = Vec::new();
-        input_file.read_to_end(&mut buf_vec).expect("Could not read note cache.");
-
-        let input: OsmMapData = unsafe {
-            rkyv::from_bytes_unchecked(&buf_vec).expect("Could not deserialize node cache.")
-        };
-
-        Ok(input)
+        artifact::read(&dir.join(parse_osm::OUTPUT_FILE_NAME))
     }
 
     fn transform(&mut self, input: Self::Input) -> Result<Self::Output> {
         let mut output = SemanticMapElements::default();
 
         self.process_nodes(&mut output, &input.nodes);
-        self.process_ways(&mut output, &input.ways);
-        self.process_relations(&mut output, &input.relations);
+        let route_tracks = Self::transit_route_tracks(&input.relations);
+        let mut area_places = self.process_ways(&mut output, &input.ways, &route_tracks);
+        self.process_coastlines(&mut output, &input.ways, input.bounds.as_ref());
+        area_places.extend(self.process_relations(&mut output, &input.relations));
+        self.deduplicate_places(&mut output, area_places);
+        let line_of_route = self.process_transit_routes(&mut output, &input.relations);
+        self.associate_station_lines(&mut output, &input.relations, &line_of_route);
+        self.merge_stations(&mut output);
+        self.count_output(&output);
 
         Ok(output)
     }
 
     fn load(&mut self, dir: &std::path::Path, output: Self::Output) -> Result<()> {
-        let mut output_file = File::create(Self::output_path(dir))?;
-        let bytes = rkyv::to_bytes::<_, 256>(&output).unwrap();
-        output_file.write_all(&bytes)?;
+        artifact::write(&Self::output_path(dir), &output)?;
+        fs::write(dir.join(CACHE_KEY_FILE_NAME), self.cache_key())?;
         Ok(())
     }
 
+    fn stats(&mut self) -> &mut EtlStats {
+        &mut self.stats
+    }
 }
@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+
+use log::warn;
+
+use crate::data::osm::{Bounds, OsmId, Way};
+use crate::data::semantic::{ring_contains, Area, AreaType, MapCoords, Path, SemanticMapElements};
+
+use super::SemanticMapEtl;
+
+/// Follows ways head to tail from `first`, until no unused way continues the chain.
+fn follow_chain(first: usize, ways: &[Path], way_by_start: &HashMap<&MapCoords, usize>, used: &mut [bool]) -> Path {
+    let mut chain = ways[first].clone();
+    used[first] = true;
+    while let Some(&next) = way_by_start.get(&chain[chain.len() - 1]) {
+        if used[next] {
+            break;
+        }
+        used[next] = true;
+        chain.extend(ways[next][1..].iter().cloned());
+    }
+    chain
+}
+
+/// Joins coastline ways head to tail, in the direction they are mapped. Returns the closed
+/// rings and the chains that stay open, like those cut off by the edge of the extract.
+fn chain_coastlines(ways: &[Path]) -> (Vec<Path>, Vec<Path>) {
+    let way_by_start: HashMap<&MapCoords, usize> = ways.iter().enumerate()
+        .map(|(idx, way)| (&way[0], idx))
+        .collect();
+    let mut has_predecessor = vec![false; ways.len()];
+    for way in ways {
+        if let Some(next) = way_by_start.get(&way[way.len() - 1]) {
+            has_predecessor[*next] = true;
+        }
+    }
+
+    let mut used = vec![false; ways.len()];
+    let mut chains = Vec::new();
+    // Open chains first, so that the ways left afterwards all lie on rings.
+    for (idx, has_predecessor) in has_predecessor.into_iter().enumerate() {
+        if !has_predecessor {
+            chains.push(follow_chain(idx, ways, &way_by_start, &mut used));
+        }
+    }
+    for idx in 0..ways.len() {
+        if !used[idx] {
+            chains.push(follow_chain(idx, ways, &way_by_start, &mut used));
+        }
+    }
+    chains.into_iter().partition(|chain| chain.len() > 2 && chain.first() == chain.last())
+}
+
+/// Shoelace formula, positive for counter-clockwise rings.
+fn signed_area(ring: &[MapCoords]) -> f64 {
+    ring.windows(2)
+        .map(|pair| pair[0].lon * pair[1].lat - pair[1].lon * pair[0].lat)
+        .sum()
+}
+
+/// The part of the segment from `a` to `b` within `bounds`, by Liang-Barsky clipping.
+fn clip_segment(a: &MapCoords, b: &MapCoords, bounds: &Bounds) -> Option<(MapCoords, MapCoords)> {
+    let (d_lon, d_lat) = (b.lon - a.lon, b.lat - a.lat);
+    let (mut t_min, mut t_max) = (0.0_f64, 1.0_f64);
+    let checks = [
+        (-d_lon, a.lon - bounds.min_lon),
+        (d_lon, bounds.max_lon - a.lon),
+        (-d_lat, a.lat - bounds.min_lat),
+        (d_lat, bounds.max_lat - a.lat),
+    ];
+    for (p, q) in checks {
+        if p == 0.0 {
+            if q < 0.0 {
+                return None;
+            }
+        } else if p < 0.0 {
+            t_min = t_min.max(q / p);
+        } else {
+            t_max = t_max.min(q / p);
+        }
+    }
+    if t_min > t_max {
+        return None;
+    }
+    let at = |t: f64| MapCoords { lat: a.lat + t * d_lat, lon: a.lon + t * d_lon };
+    Some((at(t_min), at(t_max)))
+}
+
+/// Pieces of `chain` within `bounds`.
+fn clip_chain(chain: &[MapCoords], bounds: &Bounds) -> Vec<Path> {
+    let mut pieces = Vec::new();
+    let mut piece: Path = Vec::new();
+    for segment in chain.windows(2) {
+        let Some((start, end)) = clip_segment(&segment[0], &segment[1], bounds) else {
+            continue;
+        };
+        if piece.last() != Some(&start) {
+            if piece.len() > 1 {
+                pieces.push(std::mem::take(&mut piece));
+            }
+            piece = vec![start];
+        }
+        piece.push(end);
+    }
+    if piece.len() > 1 {
+        pieces.push(piece);
+    }
+    pieces
+}
+
+/// The point on the edge of `bounds` closest to `coords`, and its position along the edge,
+/// running clockwise from 0 at the top left corner through 1, 2 and 3 at the other corners.
+fn snap_to_edge(coords: &MapCoords, bounds: &Bounds) -> (MapCoords, f64) {
+    let lon = coords.lon.clamp(bounds.min_lon, bounds.max_lon);
+    let lat = coords.lat.clamp(bounds.min_lat, bounds.max_lat);
+    let (width, height) = (bounds.max_lon - bounds.min_lon, bounds.max_lat - bounds.min_lat);
+    let edges = [
+        (bounds.max_lat - lat, MapCoords { lat: bounds.max_lat, lon }, (lon - bounds.min_lon) / width),
+        (bounds.max_lon - lon, MapCoords { lat, lon: bounds.max_lon }, 1.0 + (bounds.max_lat - lat) / height),
+        (lat - bounds.min_lat, MapCoords { lat: bounds.min_lat, lon }, 2.0 + (bounds.max_lon - lon) / width),
+        (lon - bounds.min_lon, MapCoords { lat, lon: bounds.min_lon }, 3.0 + (lat - bounds.min_lat) / height),
+    ];
+    let (_, snapped, position) = edges.into_iter()
+        .min_by(|a, b| a.0.total_cmp(&b.0))
+        .expect("Bounds have four edges");
+    (snapped, position % 4.0)
+}
+
+fn corner(bounds: &Bounds, idx: usize) -> MapCoords {
+    match idx % 4 {
+        0 => MapCoords { lat: bounds.max_lat, lon: bounds.min_lon },
+        1 => MapCoords { lat: bounds.max_lat, lon: bounds.max_lon },
+        2 => MapCoords { lat: bounds.min_lat, lon: bounds.max_lon },
+        _ => MapCoords { lat: bounds.min_lat, lon: bounds.min_lon },
+    }
+}
+
+/// Distance clockwise along the edge of the bounds, in the positions of `snap_to_edge`.
+fn clockwise_distance(from: f64, to: f64) -> f64 {
+    (to - from).rem_euclid(4.0)
+}
+
+/// A coastline piece running from edge to edge of the bounds.
+struct EdgePiece {
+    path: Path,
+    start: f64,
+    end: f64,
+}
+
+/// Closes coastline pieces into water rings along the edge of `bounds`. Water lies to the
+/// right of the coastline, so from the end of a piece the ring follows the edge clockwise
+/// to the start of the next piece.
+fn close_along_edge(pieces: Vec<Path>, bounds: &Bounds) -> Vec<Path> {
+    let pieces: Vec<EdgePiece> = pieces.into_iter()
+        .map(|mut path| {
+            // Pieces ending inside the bounds, where the extract cut the coastline short,
+            // are extended straight to the nearest edge.
+            let (start_coords, start) = snap_to_edge(&path[0], bounds);
+            let (end_coords, end) = snap_to_edge(&path[path.len() - 1], bounds);
+            if path[0] != start_coords {
+                path.insert(0, start_coords);
+            }
+            if path[path.len() - 1] != end_coords {
+                path.push(end_coords);
+            }
+            EdgePiece { path, start, end }
+        })
+        .collect();
+
+    let mut used = vec![false; pieces.len()];
+    let mut rings = Vec::new();
+    for first in 0..pieces.len() {
+        if used[first] {
+            continue;
+        }
+        let mut ring: Path = Vec::new();
+        let mut idx = first;
+        loop {
+            used[idx] = true;
+            ring.extend(pieces[idx].path.iter().cloned());
+            let end = pieces[idx].end;
+            let next = (0..pieces.len())
+                .filter(|other| !used[*other] || *other == first)
+                .min_by(|a, b| {
+                    clockwise_distance(end, pieces[*a].start).total_cmp(&clockwise_distance(end, pieces[*b].start))
+                })
+                .expect("The first piece is always a candidate");
+            let distance = clockwise_distance(end, pieces[next].start);
+            let first_corner = end.floor() as usize + 1;
+            for corner_idx in first_corner..first_corner + 4 {
+                if corner_idx as f64 - end < distance {
+                    ring.push(corner(bounds, corner_idx));
+                }
+            }
+            if next == first {
+                ring.push(ring[0].clone());
+                break;
+            }
+            idx = next;
+        }
+        rings.push(ring);
+    }
+    rings
+}
+
+impl SemanticMapEtl<'_> {
+    /// Builds water areas from `natural=coastline` ways. OSM coastlines have the land on
+    /// their left, so closed rings running clockwise enclose water and the others are
+    /// islands. Coastlines cut off by the edge of the extract are clipped to its bounds and
+    /// closed along them. Islands become holes of the water around them.
+    pub(super) fn process_coastlines(
+        &mut self,
+        output: &mut SemanticMapElements,
+        ways: &HashMap<OsmId, Way>,
+        bounds: Option<&Bounds>,
+    ) {
+        let mut coastlines: Vec<(OsmId, Path)> = ways.values()
+            .filter(|way| Self::has_kv_pair(&way.tags, b"natural", b"coastline") && way.nodes.len() > 1)
+            .map(|way| (way.id, way.into()))
+            .collect();
+        if coastlines.is_empty() {
+            return;
+        }
+        // Chaining depends on the order of the ways, keep it stable between runs.
+        coastlines.sort_unstable_by_key(|(id, _)| *id);
+        let coastlines: Vec<Path> = coastlines.into_iter().map(|(_, path)| path).collect();
+        let (rings, open_chains) = chain_coastlines(&coastlines);
+
+        let (mut water, islands): (Vec<Path>, Vec<Path>) = rings.into_iter()
+            .partition(|ring| signed_area(ring) < 0.0);
+        match bounds {
+            Some(bounds) => {
+                let pieces: Vec<Path> = open_chains.iter()
+                    .flat_map(|chain| clip_chain(chain, bounds))
+                    .collect();
+                water.extend(close_along_edge(pieces, bounds));
+            },
+            None => {
+                warn!("Skipped {} open coastline segments, the extract has no bounds.", open_chains.len());
+                self.stats.dropped("open coastline without bounds", open_chains.len());
+            },
+        }
+
+        let mut areas: Vec<Area> = water.into_iter()
+            .map(|ring| Area { area_type: AreaType::Water, area_polygons: vec![ring] })
+            .collect();
+        let mut dry_islands = 0;
+        for island in islands {
+            let surrounding = areas.iter_mut()
+                .find(|area| ring_contains(&area.area_polygons[0], &island[0]));
+            match surrounding {
+                Some(area) => area.area_polygons.push(island),
+                None => dry_islands += 1,
+            }
+        }
+        self.stats.dropped("coastline island outside coastline water", dry_islands);
+        output.areas.extend(areas);
+    }
+}
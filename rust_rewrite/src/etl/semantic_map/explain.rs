@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+
+use crate::data::osm::{Node, OsmElementRef, OsmMapData, Relation, Way};
+use crate::data::semantic::MapCoords;
+
+use super::{matched_tag, Decision, SemanticMapEtl};
+
+type Tags = HashMap<Vec<u8>, Vec<u8>>;
+
+/// All tags of a landmark category entry, in a stable order.
+fn landmark_tags(required: &HashMap<String, String>) -> String {
+    let mut tags: Vec<String> = required.iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect();
+    tags.sort();
+    tags.join(", ")
+}
+
+/// `kind: what (reason)` for elements a classifier took, `kind: skipped, why (reason)` for
+/// those it skipped.
+fn decision_line<T>(kind: &str, decision: &Decision<T>, what: impl FnOnce(&T) -> String, reason: &str) -> String {
+    match decision {
+        Ok(value) => match what(value) {
+            what if what.is_empty() => format!("{}: ({})", kind, reason),
+            what => format!("{}: {} ({})", kind, what, reason),
+        },
+        Err(skipped) => format!("{}: skipped, {} ({})", kind, skipped, reason),
+    }
+}
+
+/// Explanations call the classifiers of `process_nodes`, `process_ways` and
+/// `process_relations` in the same order, and only word their decisions.
+impl SemanticMapEtl<'_> {
+    /// How the semantic map classifies `element`, as lines like `area: Park (leisure=park)`.
+    /// Classifiers that matched the element but then skipped it are listed with the reason.
+    /// None if the element isn't in `data`.
+    pub fn explain(&self, data: &OsmMapData, element: OsmElementRef) -> Option<Vec<String>> {
+        let mut lines = Vec::new();
+        match element {
+            OsmElementRef::Node(id) => self.explain_node(data.nodes.get(&id)?, &mut lines),
+            OsmElementRef::Way(id) => self.explain_way(data, data.ways.get(&id)?, &mut lines),
+            OsmElementRef::Relation(id) => self.explain_relation(data, data.relations.get(&id)?, &mut lines),
+        }
+        if lines.is_empty() {
+            lines.push("unused: no rule matched its tags".to_string());
+        }
+        Some(lines)
+    }
+
+    /// Explains the station built from `tags`, for an element considered one because of
+    /// `reason`.
+    fn explain_station(
+        &self,
+        tags: &Tags,
+        element: OsmElementRef,
+        location: Option<MapCoords>,
+        reason: &str,
+        lines: &mut Vec<String>,
+    ) {
+        let line = match self.station_from_tags(tags, element, location) {
+            Ok((station, type_rule)) => format!(
+                "station: {:?} {} ({}, type from {})",
+                station.station_type, station.name, reason, matched_tag(tags, type_rule),
+            ),
+            Err(skipped) => format!("station: skipped, {} ({})", skipped, reason),
+        };
+        lines.push(line);
+    }
+
+    fn explain_place<'b, I: IntoIterator<Item = &'b Node>>(tags: &Tags, nodes: I, is_area: bool, lines: &mut Vec<String>) {
+        let Some((place, rule)) = Self::place_from_tags(tags, nodes) else {
+            return;
+        };
+        let mut reason = matched_tag(tags, rule);
+        if is_area && place.is_ok() {
+            reason += ", dropped if a node place has the same name and type";
+        }
+        lines.push(decision_line("place", &place, |place| format!("{:?} {}", place.place_type, place.name), &reason));
+    }
+
+    fn explain_landmark(&self, element: OsmElementRef, tags: &Tags, lines: &mut Vec<String>) {
+        if let Some(category) = self.icons.listed_landmark(element) {
+            lines.push(format!("landmark: {} ({} listed in the icon manifest)", category, element));
+        } else if let Some((category, required)) = self.icons.landmark_match(tags) {
+            lines.push(format!("landmark: {} ({} in the icon manifest)", category, landmark_tags(required)));
+        }
+    }
+
+    fn explain_area(tags: &Tags, lines: &mut Vec<String>) {
+        if let Some((area_type, rule)) = Self::area_type_from_tags(tags) {
+            lines.push(format!("area: {:?} ({})", area_type, matched_tag(tags, rule)));
+        }
+    }
+
+    fn explain_building<T>(tags: &Tags, building: Option<Decision<T>>, lines: &mut Vec<String>) {
+        if let Some(building) = building {
+            lines.push(decision_line("building", &building, |_| String::new(), &matched_tag(tags, ("building", "*"))));
+        }
+    }
+
+    fn explain_node(&self, node: &Node, lines: &mut Vec<String>) {
+        let element = OsmElementRef::Node(node.id);
+        if let Some(station_tag) = Self::station_tag(&node.tags) {
+            self.explain_station(&node.tags, element, Some(node.into()), &matched_tag(&node.tags, station_tag), lines);
+        }
+        Self::explain_place(&node.tags, [node], false, lines);
+        self.explain_landmark(element, &node.tags, lines);
+    }
+
+    fn explain_way(&self, data: &OsmMapData, way: &Way, lines: &mut Vec<String>) {
+        let element = OsmElementRef::Way(way.id);
+        Self::explain_place(&way.tags, &way.nodes, true, lines);
+        if let Some(station_tag) = Self::station_tag(&way.tags) {
+            self.explain_station(&way.tags, element, Self::centroid(&way.nodes), &matched_tag(&way.tags, station_tag), lines);
+        }
+        if Self::has_key(&way.tags, b"highway") {
+            lines.push(format!("road: ({})", matched_tag(&way.tags, ("highway", "*"))));
+        }
+        Self::explain_area(&way.tags, lines);
+        if let Some(rail) = Self::rail_from_way(way, &Self::transit_route_tracks(&data.relations)) {
+            lines.push(decision_line("rail", &rail, |_| String::new(), "railway=rail"));
+        }
+        Self::explain_building(&way.tags, Self::way_building(way), lines);
+        if let Some((waterway, rule)) = Self::waterway_from_way(way) {
+            let reason = matched_tag(&way.tags, rule);
+            lines.push(decision_line("waterway", &waterway, |waterway| format!("{:?}", waterway.waterway_type), &reason));
+        }
+        self.explain_landmark(element, &way.tags, lines);
+        if Self::has_kv_pair(&way.tags, b"natural", b"coastline") {
+            lines.push("coastline: joined with the other coastline ways into water areas and their islands, closed along the extract bounds (natural=coastline)".to_string());
+        }
+
+        let is_track = Self::is_track(way);
+        let mut routes: Vec<&Relation> = data.relations.values()
+            .filter(|relation| relation.ways.iter().any(|member| member.id == way.id))
+            .filter(|relation| matches!(Self::transit_route_mode(relation), Some(Ok(_))))
+            .collect();
+        routes.sort_by_key(|route| route.id);
+        for route in routes {
+            let route_ref = OsmElementRef::Relation(route.id);
+            if is_track {
+                lines.push(format!("tube rail: track of {} ({})", route_ref, matched_tag(&way.tags, ("railway", "*"))));
+            } else {
+                lines.push(format!("tube rail: skipped, member of {} but not a track", route_ref));
+            }
+        }
+    }
+
+    fn explain_relation(&self, data: &OsmMapData, relation: &Relation, lines: &mut Vec<String>) {
+        let element = OsmElementRef::Relation(relation.id);
+        match Self::station_relation_tags(relation) {
+            Some(Ok((tags, reason))) => {
+                self.explain_station(&tags, element, Self::relation_centroid(relation), &reason, lines);
+            },
+            Some(Err(skipped)) => lines.push(format!("station: skipped, {} (public_transport=stop_area)", skipped)),
+            None => {},
+        }
+        let member_nodes = relation.nodes.iter()
+            .chain(relation.ways.iter().flat_map(|way| &way.nodes));
+        Self::explain_place(&relation.tags, member_nodes, true, lines);
+        Self::explain_area(&relation.tags, lines);
+        Self::explain_building(&relation.tags, Self::relation_building(relation), lines);
+        if let Some(council) = Self::council_from_relation(relation) {
+            lines.push(decision_line("council", &council, |council| council.name.clone(), "council_name"));
+        }
+
+        let route = matched_tag(&relation.tags, ("route", "*"));
+        match Self::transit_route_mode(relation) {
+            Some(Ok(mode)) => match Self::route_masters(&data.relations).get(&relation.id) {
+                None => lines.push(format!("transit line: {:?}, its own line ({})", mode, route)),
+                Some(master) => lines.push(format!(
+                    "transit line: {:?}, part of the line of {} ({}, member of a route_master)",
+                    mode, OsmElementRef::Relation(master.id), route,
+                )),
+            },
+            Some(Err(skipped)) => lines.push(format!("transit line: skipped, {} ({})", skipped, route)),
+            None => {},
+        }
+        if Self::has_kv_pair(&relation.tags, b"type", b"route_master") {
+            lines.push(format!(
+                "transit line: route master, names the line of its {} member routes (type=route_master)",
+                relation.relations.len(),
+            ));
+        }
+    }
+}
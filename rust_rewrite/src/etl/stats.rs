@@ -0,0 +1,154 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::Path;
+use std::time::Instant;
+
+use log::info;
+use serde::Serialize;
+
+use crate::errors::Result;
+
+/// Statistics of one run of a stage, filled in by the stage as it goes and written next
+/// to its artifacts as `<etl_name>.stats.json`. Runs that fail write the statistics they
+/// got to, with the phase that failed.
+#[derive(Serialize, Default)]
+pub struct EtlStats {
+    pub etl_name: String,
+    pub phases: Vec<PhaseStats>,
+    /// E.g. `transform`, unset if the run succeeded.
+    pub failed_phase: Option<&'static str>,
+    pub error: Option<String>,
+    /// Elements by type, e.g. `ways` or `areas/Park`.
+    pub counts: BTreeMap<String, u64>,
+    /// Values of tags no rule knows, counted by key and value.
+    pub unknown_values: BTreeMap<String, BTreeMap<String, u64>>,
+    /// Elements and references left out, counted by reason.
+    pub dropped: BTreeMap<String, u64>,
+}
+
+#[derive(Serialize)]
+pub struct PhaseStats {
+    pub phase: &'static str,
+    pub wall_time_s: f64,
+    /// Peak resident memory during the phase, or since the start of the process where the
+    /// peak can't be reset. Like the byte counts, only available on Linux.
+    pub peak_memory_bytes: Option<u64>,
+    /// All reads and writes of the process, including the log.
+    pub bytes_read: Option<u64>,
+    pub bytes_written: Option<u64>,
+}
+
+/// A field of a `/proc/self` file like `VmHWM:    1796 kB`, as a number.
+fn proc_value(contents: &str, field: &str) -> Option<u64> {
+    contents.lines()
+        .find_map(|line| line.strip_prefix(field)?.strip_prefix(':'))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+fn peak_memory_bytes() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    proc_value(&status, "VmHWM").map(|kb| kb * 1024)
+}
+
+/// Characters read and written by the process so far. Reading them counts as reading the
+/// length of the file, which is returned too.
+fn io_bytes() -> Option<(u64, u64, u64)> {
+    let io = fs::read_to_string("/proc/self/io").ok()?;
+    Some((proc_value(&io, "rchar")?, proc_value(&io, "wchar")?, io.len() as u64))
+}
+
+impl PhaseStats {
+    /// Runs `phase` and measures it.
+    pub fn measure<T, F: FnOnce() -> T>(name: &'static str, phase: F) -> (T, PhaseStats) {
+        // Resets the peak resident set size, see proc(5).
+        let _ = fs::write("/proc/self/clear_refs", "5");
+        let before = io_bytes();
+        let start = Instant::now();
+
+        let result = phase();
+
+        let wall_time_s = start.elapsed().as_secs_f64();
+        let io = io_bytes().zip(before)
+            .map(|((read, written, _), (read_before, written_before, overhead))| {
+                (read.saturating_sub(read_before + overhead), written.saturating_sub(written_before))
+            });
+        let stats = PhaseStats {
+            phase: name,
+            wall_time_s,
+            peak_memory_bytes: peak_memory_bytes(),
+            bytes_read: io.map(|(read, _)| read),
+            bytes_written: io.map(|(_, written)| written),
+        };
+        (result, stats)
+    }
+}
+
+/// E.g. `nodes=12, ways=3`.
+fn summary(counts: &BTreeMap<String, u64>) -> String {
+    counts.iter()
+        .map(|(name, count)| format!("{}={}", name, count))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl EtlStats {
+    pub fn count(&mut self, name: &str, count: usize) {
+        self.counts.insert(name.to_string(), count as u64);
+    }
+
+    /// Counts `elements` as `name`, and by type as `name/<key>`.
+    pub fn count_by<T, F: Fn(&T) -> String>(&mut self, name: &str, elements: &[T], key: F) {
+        self.count(name, elements.len());
+        for element in elements {
+            *self.counts.entry(format!("{}/{}", name, key(element))).or_default() += 1;
+        }
+    }
+
+    pub fn unknown_value(&mut self, key: &str, value: &str) {
+        *self.unknown_values.entry(key.to_string()).or_default()
+            .entry(value.to_string()).or_default() += 1;
+    }
+
+    pub fn dropped(&mut self, reason: &str, count: usize) {
+        if count > 0 {
+            *self.dropped.entry(reason.to_string()).or_default() += count as u64;
+        }
+    }
+
+    pub fn write(&self, dir: &Path) -> Result<()> {
+        let path = dir.join(format!("{}.stats.json", self.etl_name));
+        let writer = BufWriter::new(File::create(&path)?);
+        serde_json::to_writer_pretty(writer, self)
+            .map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+        Ok(())
+    }
+
+    /// Logs a line per phase and one with the counts.
+    pub fn log(&self) {
+        for phase in &self.phases {
+            info!(
+                etl_name = self.etl_name,
+                phase = phase.phase,
+                wall_time_s = phase.wall_time_s,
+                peak_memory_mb = phase.peak_memory_bytes.map(|bytes| bytes / (1024 * 1024)),
+                bytes_read = phase.bytes_read,
+                bytes_written = phase.bytes_written;
+                "Phase statistics"
+            );
+        }
+        let unknown_values: BTreeMap<String, u64> = self.unknown_values.iter()
+            .map(|(key, values)| (key.clone(), values.values().sum()))
+            .collect();
+        info!(
+            etl_name = self.etl_name,
+            counts = summary(&self.counts),
+            unknown_values = summary(&unknown_values),
+            dropped = summary(&self.dropped);
+            "Stage statistics"
+        );
+    }
+}
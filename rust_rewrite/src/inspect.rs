@@ -0,0 +1,285 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use quick_xml::escape::unescape;
+
+use crate::data::icons::IconManifest;
+use crate::data::osm::{Node, OsmElementRef, OsmMapData};
+use crate::data::semantic::SemanticMapElements;
+use crate::errors::Result;
+use crate::etl::{artifact, parse_osm};
+use crate::etl::semantic_map::{self, matches_tag, SemanticMapEtl};
+
+const USAGE: &str = "Usage: inspect <query>, where query is one of
+  element <type/id>                                 tags, geometry and parents of an element
+  find <key[=value]>...                             elements with all the given tags
+  bbox <min_lon,min_lat,max_lon,max_lat> [key[=value]]...
+                                                    elements with a node in the box
+  counts                                            elements per semantic category
+  explain <type/id>                                 how the semantic map classifies an element
+Elements are given as in OSM URLs, e.g. way/123. A value of * matches any value.";
+
+/// Answers questions about the intermediate artifacts of an earlier run, for finding out
+/// why something is or isn't on the map without rendering it.
+pub fn run(args: &[String], output_dir: &Path, icons: &IconManifest) -> Result<()> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["element", element] => print_element(&load_osm_data(output_dir)?, element.parse()?)?,
+        ["find", filters @ ..] if !filters.is_empty() => {
+            let filters = parse_filters(filters);
+            let data = load_osm_data(output_dir)?;
+            print_elements(&data, |_, tags| filters.iter().all(|filter| matches_tag(tags, *filter)));
+        },
+        ["bbox", bbox, filters @ ..] => {
+            let bbox = parse_bbox(bbox)?;
+            let filters = parse_filters(filters);
+            let data = load_osm_data(output_dir)?;
+            print_elements(&data, |nodes, tags| {
+                filters.iter().all(|filter| matches_tag(tags, *filter))
+                    && nodes.iter().any(|node| bbox.contains(node))
+            });
+        },
+        ["counts"] => print_counts(&load_osm_data(output_dir)?, &load_semantic_map(output_dir)?),
+        ["explain", element] => {
+            let element: OsmElementRef = element.parse()?;
+            let data = load_osm_data(output_dir)?;
+            let lines = SemanticMapEtl::new(icons).explain(&data, element)
+                .ok_or_else(|| not_found(&data, element))?;
+            println!("{}", element);
+            for line in lines {
+                println!("  {}", line);
+            }
+            print_semantic_stations(&load_semantic_map(output_dir)?, element);
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            return Err("Unknown inspect query".into());
+        },
+    }
+    Ok(())
+}
+
+fn artifact_path(dir: &Path, file_name: &str) -> Result<PathBuf> {
+    let path = dir.join(file_name);
+    if !path.exists() {
+        return Err(format!("{} not found, run render or export first", path.display()).into());
+    }
+    Ok(path)
+}
+
+fn load_osm_data(dir: &Path) -> Result<OsmMapData> {
+    artifact::read(&artifact_path(dir, parse_osm::OUTPUT_FILE_NAME)?)
+}
+
+fn load_semantic_map(dir: &Path) -> Result<SemanticMapElements> {
+    artifact::read(&artifact_path(dir, semantic_map::OUTPUT_FILE_NAME)?)
+}
+
+/// `key=value` or just `key`, which matches any value.
+fn parse_filters<'s>(args: &[&'s str]) -> Vec<(&'s str, &'s str)> {
+    args.iter()
+        .map(|arg| arg.split_once('=').unwrap_or((arg, "*")))
+        .collect()
+}
+
+struct BoundingBox {
+    min_lon: f64,
+    min_lat: f64,
+    max_lon: f64,
+    max_lat: f64,
+}
+
+impl BoundingBox {
+    fn contains(&self, node: &Node) -> bool {
+        (self.min_lon..=self.max_lon).contains(&node.lon) && (self.min_lat..=self.max_lat).contains(&node.lat)
+    }
+}
+
+fn parse_bbox(arg: &str) -> Result<BoundingBox> {
+    let values = arg.split(',')
+        .map(|value| value.trim().parse())
+        .collect::<std::result::Result<Vec<f64>, _>>()?;
+    match values.as_slice() {
+        [min_lon, min_lat, max_lon, max_lat] => Ok(BoundingBox {
+            min_lon: min_lon.min(*max_lon),
+            min_lat: min_lat.min(*max_lat),
+            max_lon: min_lon.max(*max_lon),
+            max_lat: min_lat.max(*max_lat),
+        }),
+        _ => Err(format!("Expected min_lon,min_lat,max_lon,max_lat, got {}", arg).into()),
+    }
+}
+
+/// Tags are kept XML escaped as in the .osm file.
+fn tag_string(bytes: &[u8]) -> String {
+    let text = String::from_utf8_lossy(bytes);
+    unescape(&text).map_or_else(|_| text.to_string(), |text| text.into_owned())
+}
+
+/// All nodes of an element, including those of member ways.
+fn element_nodes(data: &OsmMapData, element: OsmElementRef) -> Vec<&Node> {
+    match element {
+        OsmElementRef::Node(id) => data.nodes.get(&id).into_iter().collect(),
+        OsmElementRef::Way(id) => data.ways.get(&id).map(|way| way.nodes.iter().collect()).unwrap_or_default(),
+        OsmElementRef::Relation(id) => data.relations.get(&id)
+            .map(|relation| relation.nodes.iter()
+                .chain(relation.ways.iter().flat_map(|way| &way.nodes))
+                .collect())
+            .unwrap_or_default(),
+    }
+}
+
+/// Untagged nodes are only kept as part of ways and relations.
+fn not_found(data: &OsmMapData, element: OsmElementRef) -> String {
+    let OsmElementRef::Node(id) = element else {
+        return format!("{} is not in {}", element, parse_osm::OUTPUT_FILE_NAME);
+    };
+    let way = data.ways.values().find(|way| way.nodes.iter().any(|node| node.id == id));
+    match way {
+        Some(way) => format!("{} is an untagged node of way/{}, which isn't classified on its own", element, way.id),
+        None => format!("{} is not in {}", element, parse_osm::OUTPUT_FILE_NAME),
+    }
+}
+
+/// Prints the elements `filter` accepts, given their nodes and tags, sorted by type and id.
+fn print_elements<F>(data: &OsmMapData, filter: F)
+where
+    F: Fn(&[&Node], &std::collections::HashMap<Vec<u8>, Vec<u8>>) -> bool,
+{
+    let tags_of = data.nodes.values().map(|node| (OsmElementRef::Node(node.id), &node.tags))
+        .chain(data.ways.values().map(|way| (OsmElementRef::Way(way.id), &way.tags)))
+        .chain(data.relations.values().map(|relation| (OsmElementRef::Relation(relation.id), &relation.tags)));
+    let mut matches: Vec<(OsmElementRef, Option<String>)> = tags_of
+        .filter(|(element, tags)| filter(&element_nodes(data, *element), tags))
+        .map(|(element, tags)| (element, tags.get(b"name".as_slice()).map(|name| tag_string(name))))
+        .collect();
+    matches.sort();
+    for (element, name) in &matches {
+        match name {
+            Some(name) => println!("{}  {}", element, name),
+            None => println!("{}", element),
+        }
+    }
+    println!("{} elements", matches.len());
+}
+
+fn print_element(data: &OsmMapData, element: OsmElementRef) -> Result<()> {
+    let tags = match element {
+        OsmElementRef::Node(id) => data.nodes.get(&id).map(|node| &node.tags),
+        OsmElementRef::Way(id) => data.ways.get(&id).map(|way| &way.tags),
+        OsmElementRef::Relation(id) => data.relations.get(&id).map(|relation| &relation.tags),
+    };
+    let Some(tags) = tags else {
+        return Err(not_found(data, element).into());
+    };
+
+    println!("{}", element);
+    let mut tags: Vec<(String, String)> = tags.iter()
+        .map(|(key, value)| (tag_string(key), tag_string(value)))
+        .collect();
+    tags.sort();
+    for (key, value) in tags {
+        println!("  {}={}", key, value);
+    }
+
+    let nodes = element_nodes(data, element);
+    match element {
+        OsmElementRef::Node(_) => println!("position: {}, {}", nodes[0].lat, nodes[0].lon),
+        OsmElementRef::Way(_) => println!("nodes: {}", nodes.len()),
+        OsmElementRef::Relation(id) => {
+            let relation = &data.relations[&id];
+            println!(
+                "members: {} nodes, {} ways, {} relations",
+                relation.nodes.len(), relation.ways.len(), relation.relations.len(),
+            );
+        },
+    }
+    if nodes.len() > 1 {
+        let min_lat = nodes.iter().map(|node| node.lat).fold(f64::INFINITY, f64::min);
+        let max_lat = nodes.iter().map(|node| node.lat).fold(f64::NEG_INFINITY, f64::max);
+        let min_lon = nodes.iter().map(|node| node.lon).fold(f64::INFINITY, f64::min);
+        let max_lon = nodes.iter().map(|node| node.lon).fold(f64::NEG_INFINITY, f64::max);
+        println!("bbox: {},{},{},{}", min_lon, min_lat, max_lon, max_lat);
+    }
+
+    let mut parents: Vec<OsmElementRef> = data.relations.values()
+        .filter(|relation| match element {
+            OsmElementRef::Node(id) => relation.nodes.iter().any(|node| node.id == id),
+            OsmElementRef::Way(id) => relation.ways.iter().any(|way| way.id == id),
+            OsmElementRef::Relation(id) => relation.relations.contains(&id),
+        })
+        .map(|relation| OsmElementRef::Relation(relation.id))
+        .collect();
+    if let OsmElementRef::Node(id) = element {
+        parents.extend(data.ways.values()
+            .filter(|way| way.nodes.iter().any(|node| node.id == id))
+            .map(|way| OsmElementRef::Way(way.id)));
+    }
+    parents.sort();
+    if !parents.is_empty() {
+        let parents: Vec<String> = parents.iter().map(OsmElementRef::to_string).collect();
+        println!("member of: {}", parents.join(", "));
+    }
+    Ok(())
+}
+
+/// Prints the number of elements and a breakdown by `key`, largest first.
+fn print_count<T, F: Fn(&T) -> String>(name: &str, elements: &[T], key: F) {
+    println!("  {:<20}{:>9}", name, elements.len());
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    for element in elements {
+        *counts.entry(key(element)).or_default() += 1;
+    }
+    let mut counts: Vec<(String, usize)> = counts.into_iter().collect();
+    counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    for (key, count) in counts {
+        println!("    {:<18}{:>9}", key, count);
+    }
+}
+
+fn print_counts(data: &OsmMapData, elements: &SemanticMapElements) {
+    println!("{}", parse_osm::OUTPUT_FILE_NAME);
+    println!("  {:<20}{:>9}", "nodes", data.nodes.len());
+    println!("  {:<20}{:>9}", "ways", data.ways.len());
+    println!("  {:<20}{:>9}", "relations", data.relations.len());
+
+    println!("{}", semantic_map::OUTPUT_FILE_NAME);
+    print_count("stations", &elements.underground_stations, |station| format!("{:?}", station.station_type));
+    print_count("places", &elements.places, |place| format!("{:?}", place.place_type));
+    print_count("areas", &elements.areas, |area| format!("{:?}", area.area_type));
+    print_count("waterways", &elements.waterways, |waterway| format!("{:?}", waterway.waterway_type));
+    print_count("landmarks", &elements.landmarks, |landmark| landmark.category.clone());
+    print_count("tube rails", &elements.tube_rails, |rail| {
+        elements.transit_lines.get(&rail.line).map_or_else(|| rail.line.to_string(), |line| line.name.clone())
+    });
+    let lines: Vec<_> = elements.transit_lines.values().collect();
+    print_count("transit lines", &lines, |line| format!("{:?}", line.mode));
+    println!("  {:<20}{:>9}", "roads", elements.roads.len());
+    println!("  {:<20}{:>9}", "rails", elements.rails.len());
+    println!("  {:<20}{:>9}", "buildings", elements.buildings.len());
+    println!("  {:<20}{:>9}", "councils", elements.councils.len());
+}
+
+/// Stations are merged and dropped after classification, so tell what became of them.
+fn print_semantic_stations(elements: &SemanticMapElements, element: OsmElementRef) {
+    for station in &elements.underground_stations {
+        if !station.osm_elements.contains(&element) {
+            continue;
+        }
+        let lines: Vec<&str> = station.lines.iter()
+            .filter_map(|id| elements.transit_lines.get(id))
+            .map(|line| line.name.as_str())
+            .collect();
+        let merged: Vec<String> = station.osm_elements.iter()
+            .filter(|other| **other != element)
+            .map(OsmElementRef::to_string)
+            .collect();
+        println!("in semantic map: station {} ({:?})", station.name, station.station_type);
+        if !merged.is_empty() {
+            println!("  merged with {}", merged.join(", "));
+        }
+        if !lines.is_empty() {
+            println!("  served by {}", lines.join(", "));
+        }
+    }
+}
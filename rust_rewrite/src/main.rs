@@ -1,15 +1,19 @@
 mod etl;
 mod data;
 mod errors;
+mod inspect;
 
 mod study;
 
+use std::env;
 use std::fs::{create_dir_all, File};
 use std::io;
 use std::path::{Path, PathBuf};
 use std::str;
 
-use etl::draw_map::{Theme, DrawMapEtl};
+use data::icons::{IconManifest, ICON_MANIFEST_PATH};
+use etl::draw_map::{Generalization, IconRegistry, OverlayLayer, Overlays, Textures, Theme, DrawMapEtl};
+use etl::export_geojson::ExportGeoJsonEtl;
 use etl::semantic_map::SemanticMapEtl;
 use serde::Deserialize;
 use structured_logger::json::new_writer;
@@ -30,6 +34,11 @@ pub struct UserConfig<'a> {
     pub width_px: u64,
     pub height_px: u64,
     pub theme: Theme<'a>,
+    #[serde(default)]
+    pub generalization: Generalization,
+    /// User supplied layers, like routes or points of interest, drawn on top of the map.
+    #[serde(default)]
+    pub overlays: Vec<OverlayLayer<'a>>,
 }
 
 
@@ -54,15 +63,52 @@ fn setup_logging() {
         .init();
 }
 
+/// What to do, given as the first command line argument.
+enum Command {
+    /// Run the stages, ending with `FinalStage`.
+    Run(FinalStage),
+    /// Query the artifacts of an earlier run, with the remaining arguments.
+    Inspect(Vec<String>),
+}
+
+/// What to do with the semantic map.
+enum FinalStage {
+    /// Draw the map, the default.
+    Render,
+    /// Write the semantic map as GeoJSON, for inspecting it in GIS tools.
+    Export,
+}
+
+fn parse_command() -> Result<Command> {
+    match env::args().nth(1).as_deref() {
+        None | Some("render") => Ok(Command::Run(FinalStage::Render)),
+        Some("export") => Ok(Command::Run(FinalStage::Export)),
+        Some("inspect") => Ok(Command::Inspect(env::args().skip(2).collect())),
+        Some(other) => Err(format!("Unknown command {}, expected render, export or inspect", other).into()),
+    }
+}
+
 fn main() -> Result<()> {
     //crate::study::big_image();
     //return Ok(());
 
     setup_logging();
 
+    let command = parse_command()?;
+
     let user_config = load_user_config("../config/london_full.json");
     // let user_config = load_user_config("../config/london_center.json");
     let output_dir = create_output_dir(&user_config)?;
+    // Load icons, overlays and textures before the slow stages run, so that broken files
+    // fail fast.
+    let icon_manifest = IconManifest::load(ICON_MANIFEST_PATH)?;
+    let final_stage = match command {
+        Command::Run(final_stage) => final_stage,
+        Command::Inspect(args) => return inspect::run(&args, &output_dir, &icon_manifest),
+    };
+    let icons = IconRegistry::load(&icon_manifest, &user_config.theme)?;
+    let overlays = Overlays::load(&user_config.overlays, &icons)?;
+    let textures = Textures::load(&user_config.theme)?;
 
     // Limit ETL Scope so that memory can be freed as early as possible
     {
@@ -70,12 +116,18 @@ fn main() -> Result<()> {
         parse_osm_etl.process(&output_dir)?;
     }
     {
-        let mut semantic_map_etl = SemanticMapEtl::new();
+        let mut semantic_map_etl = SemanticMapEtl::new(&icon_manifest);
         semantic_map_etl.process(&output_dir)?;
     }
-    {
-        let mut draw_map_etl = DrawMapEtl::new(&user_config);
-        draw_map_etl.process(&output_dir)?;
+    match final_stage {
+        FinalStage::Render => {
+            let mut draw_map_etl = DrawMapEtl::new(&user_config, icons, overlays, textures)?;
+            draw_map_etl.process(&output_dir)?;
+        },
+        FinalStage::Export => {
+            let mut export_geojson_etl = ExportGeoJsonEtl::new();
+            export_geojson_etl.process(&output_dir)?;
+        },
     }
 
     Ok(())